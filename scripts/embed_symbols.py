@@ -0,0 +1,113 @@
+#!/usr/bin/env python3
+# Fills the `.ksyms` section of a linked kernel ELF with the kernel's own
+# symbol table, so that `symbols::resolve` can symbolize backtraces.
+# See `src/symbols.rs` for the layout of the table.
+#
+# Usage: embed_symbols.py <kernel elf>
+
+import re
+import struct
+import subprocess
+import sys
+
+SECTION = b".ksyms"
+MAGIC = b"KSYM"
+HEADER = struct.Struct("<4sII")
+ENTRY = struct.Struct("<QIII")
+# Longer names are truncated to keep the table small
+MAX_NAME_LEN = 120
+# Legacy Rust mangling leaves a hash suffix after demangling
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def find_section(elf):
+    # ELF64 header: section header offset, entry size, count and the
+    # index of the section containing the section names
+    (shoff,) = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+
+    def header(index):
+        # sh_name, sh_type, sh_flags, sh_addr, sh_offset, sh_size
+        return struct.unpack_from("<IIQQQQ", elf, shoff + index * shentsize)
+
+    names_offset = header(shstrndx)[4]
+    for index in range(shnum):
+        name, _, _, _, offset, size = header(index)
+        start = names_offset + name
+        if elf[start : elf.index(b"\0", start)] == SECTION:
+            return offset, size
+    sys.exit("embed_symbols: no {} section found".format(SECTION.decode()))
+
+
+def read_symbols(path):
+    output = subprocess.run(
+        ["nm", "--defined-only", "--print-size", "--demangle", path],
+        check=True,
+        stdout=subprocess.PIPE,
+        universal_newlines=True,
+    ).stdout
+
+    symbols = {}
+    for line in output.splitlines():
+        parts = line.split(" ", 3)
+        if len(parts) == 4:
+            address, size, kind, name = parts
+        else:
+            # Symbols without a size
+            address, kind, name = line.split(" ", 2)
+            size = "0"
+        # Only code is interesting for backtraces
+        if kind not in ("T", "t", "W", "w"):
+            continue
+        name = HASH_SUFFIX.sub("", name)[:MAX_NAME_LEN]
+        symbols.setdefault(int(address, 16), (int(size, 16), name))
+    return sorted((address, size, name) for address, (size, name) in symbols.items())
+
+
+def build_table(symbols, capacity):
+    # If not everything fits, prefer the kernel's own functions
+    ranked = sorted(symbols, key=lambda symbol: not symbol[2].startswith("rust_os::"))
+    kept = []
+    used = HEADER.size
+    for symbol in ranked:
+        cost = ENTRY.size + len(symbol[2].encode())
+        if used + cost <= capacity:
+            kept.append(symbol)
+            used += cost
+    kept.sort()
+
+    entries = bytearray()
+    strings = bytearray()
+    for address, size, name in kept:
+        encoded = name.encode()
+        entries += ENTRY.pack(address, size, len(strings), len(encoded))
+        strings += encoded
+
+    strings_offset = HEADER.size + len(entries)
+    return HEADER.pack(MAGIC, len(kept), strings_offset) + entries + strings, len(kept)
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: embed_symbols.py <kernel elf>")
+    path = sys.argv[1]
+
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+
+    offset, capacity = find_section(elf)
+    symbols = read_symbols(path)
+    table, count = build_table(symbols, capacity)
+    if count < len(symbols):
+        print(
+            "embed_symbols: table full, kept {} of {} symbols".format(count, len(symbols)),
+            file=sys.stderr,
+        )
+
+    elf[offset : offset + capacity] = table.ljust(capacity, b"\0")
+    with open(path, "wb") as f:
+        f.write(elf)
+
+
+if __name__ == "__main__":
+    main()
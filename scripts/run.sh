@@ -0,0 +1,6 @@
+#!/bin/sh
+# Cargo runner: embeds the kernel symbol table into the linked kernel,
+# then hands it to `bootimage runner` as before.
+set -e
+python3 "$(dirname "$0")/embed_symbols.py" "$1"
+exec bootimage runner "$@"
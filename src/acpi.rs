@@ -0,0 +1,246 @@
+// Parsing of the ACPI tables describing the machine
+//
+// The tables live in physical memory, which we access through the
+// mapping of the complete physical memory set up by the bootloader.
+// Everything starts at the RSDP, which points to the RSDT or XSDT
+// listing all other tables. Tables with a wrong checksum are ignored.
+//
+// The tables we understand are parsed into typed structures:
+// - the MADT ("APIC") lists the processors, I/O APICs and how the legacy
+//   interrupts are routed to them,
+// - the FADT ("FACP") holds the power management ports and the DSDT,
+//   which we only scan for the sleep type of the soft-off state,
+// - the HPET table describes the high precision event timer,
+// - the MCFG lists the memory mapped PCI configuration space.
+
+use crate::{memory, warn};
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::ptr::read_unaligned;
+use x86_64::PhysAddr;
+
+mod dsdt;
+mod fadt;
+mod hpet;
+mod madt;
+mod mcfg;
+
+pub use dsdt::{s5_sleep_type, SleepType};
+pub use fadt::{fadt, Fadt};
+pub use hpet::{hpet, Hpet};
+pub use madt::{
+    madt, InterruptOverride, IoApic, LocalApic, LocalApicNmi, Madt, Polarity, TriggerMode,
+};
+pub use mcfg::{mcfg, PciConfigRegion};
+
+// Root System Description Pointer, the entry point to all other tables
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The remaining fields only exist from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// Size of the revision 1 RSDP, which the first checksum covers
+const RSDP_V1_SIZE: usize = 20;
+
+// Header shared by all system description tables
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+// Address of a register in one of several address spaces, as used by
+// the FADT and the HPET table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+// The generic address structure as found in the tables
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct RawGenericAddress {
+    space: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+impl RawGenericAddress {
+    // Unused registers have a zero address
+    fn parse(self) -> Option<GenericAddress> {
+        let space = match self.space {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        match self.address {
+            0 => None,
+            address => Some(GenericAddress {
+                space,
+                bit_width: self.bit_width,
+                bit_offset: self.bit_offset,
+                access_size: self.access_size,
+                address,
+            }),
+        }
+    }
+}
+
+// Reads a `T` from physical memory
+fn read_phys<T>(addr: PhysAddr) -> T {
+    let offset = memory::physical_memory_offset().expect("memory not initialised");
+    let virt = offset + addr.as_u64();
+    unsafe { read_unaligned(virt.as_ptr::<T>()) }
+}
+
+// Reads a `T` from physical memory, of which only the first `length`
+// bytes exist. The rest is zeroed. Older tables are shorter than newer
+// ones, but otherwise laid out the same.
+fn read_phys_prefix<T: Copy>(addr: PhysAddr, length: usize) -> T {
+    let mut value = MaybeUninit::<T>::zeroed();
+    let length = length.min(size_of::<T>());
+    let bytes = phys_bytes(addr, length);
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), value.as_mut_ptr() as *mut u8, length);
+        // Only used for tables of plain integers, for which zero is valid
+        value.assume_init()
+    }
+}
+
+fn phys_bytes(addr: PhysAddr, length: usize) -> &'static [u8] {
+    let offset = memory::physical_memory_offset().expect("memory not initialised");
+    let virt = offset + addr.as_u64();
+    unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), length) }
+}
+
+// All bytes covered by a checksum add up to zero
+fn checksum_valid(addr: PhysAddr, length: usize) -> bool {
+    phys_bytes(addr, length)
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+        == 0
+}
+
+impl Rsdp {
+    fn is_valid(&self, addr: PhysAddr) -> bool {
+        if &self.signature != b"RSD PTR " || !checksum_valid(addr, RSDP_V1_SIZE) {
+            return false;
+        }
+        // The extended checksum covers the whole structure
+        self.revision < 2 || checksum_valid(addr, self.length as usize)
+    }
+}
+
+// The RSDP is either in the first KiB of the extended BIOS data area or
+// in the BIOS area below 1 MiB, always aligned to 16 bytes
+fn find_rsdp() -> Option<Rsdp> {
+    // The real mode segment of the EBDA is stored at 0x40e
+    let ebda = u64::from(read_phys::<u16>(PhysAddr::new(0x40e))) << 4;
+    let areas = [ebda..ebda + 1024, 0xe0000..0x100000];
+
+    areas
+        .iter()
+        .cloned()
+        .flat_map(|area| area.step_by(16))
+        .map(PhysAddr::new)
+        .map(|addr| (addr, read_phys::<Rsdp>(addr)))
+        .find(|(addr, rsdp)| rsdp.is_valid(*addr))
+        .map(|(_, rsdp)| rsdp)
+}
+
+// Physical addresses of all tables listed by the RSDT or XSDT
+fn table_addresses() -> Vec<PhysAddr> {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return Vec::new(),
+    };
+
+    // Revision 2 added the XSDT, which holds 64 bit pointers
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+    let header: SdtHeader = read_phys(root);
+    if !checksum_valid(root, header.length as usize) {
+        warn!("ignoring ACPI root table with a wrong checksum");
+        return Vec::new();
+    }
+    let entries = (header.length as usize).saturating_sub(size_of::<SdtHeader>()) / entry_size;
+
+    (0..entries)
+        .map(|i| {
+            let entry = root + size_of::<SdtHeader>() + i * entry_size;
+            match entry_size {
+                8 => PhysAddr::new(read_phys::<u64>(entry)),
+                _ => PhysAddr::new(u64::from(read_phys::<u32>(entry))),
+            }
+        })
+        .collect()
+}
+
+// Returns the physical address and header of the first valid table with
+// the given signature
+fn find_table(signature: &[u8; 4]) -> Option<(PhysAddr, SdtHeader)> {
+    table_addresses()
+        .into_iter()
+        .map(|table| (table, read_phys::<SdtHeader>(table)))
+        .filter(|(_, header)| &header.signature == signature)
+        .find(|(table, header)| {
+            let valid = checksum_valid(*table, header.length as usize);
+            if !valid {
+                warn!(
+                    "ignoring ACPI table {} with a wrong checksum",
+                    core::str::from_utf8(signature).unwrap_or("????")
+                );
+            }
+            valid
+        })
+}
+
+// Signatures of all tables the firmware provides, for diagnostics
+pub fn table_signatures() -> Vec<[u8; 4]> {
+    table_addresses()
+        .into_iter()
+        .map(|table| read_phys::<SdtHeader>(table).signature)
+        .collect()
+}
+
+// Lists the usable processors according to the MADT, or returns `None`
+// if there is none
+pub fn local_apics() -> Option<Vec<LocalApic>> {
+    madt().map(|madt| madt.processors)
+}
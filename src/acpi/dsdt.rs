@@ -0,0 +1,102 @@
+// The Differentiated System Description Table ("DSDT")
+//
+// The DSDT holds AML bytecode, which we do not interpret. The only thing
+// we need from it is the `_S5_` package saying what to write to the PM1
+// control registers to power off, which can be found by scanning for it.
+
+use super::{checksum_valid, fadt, phys_bytes, read_phys, SdtHeader};
+use crate::warn;
+use core::mem::size_of;
+
+const NAME_OP: u8 = 0x08;
+const ROOT_CHAR: u8 = b'\\';
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+
+// Values for the SLP_TYP fields of the PM1a and PM1b control registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+// Finds the sleep type of the soft-off state S5 in the DSDT, or returns
+// `None` if there is no DSDT or it has no `_S5_` object
+pub fn s5_sleep_type() -> Option<SleepType> {
+    let table = fadt()?.dsdt;
+    if table.as_u64() == 0 {
+        return None;
+    }
+    let header: SdtHeader = read_phys(table);
+    if &header.signature != b"DSDT" || !checksum_valid(table, header.length as usize) {
+        warn!("ignoring invalid DSDT");
+        return None;
+    }
+
+    let aml = &phys_bytes(table, header.length as usize)[size_of::<SdtHeader>()..];
+    parse_s5(aml)
+}
+
+// Looks for `Name(_S5_, Package() { a, b, ... })`, optionally with the
+// name given relative to the root
+fn parse_s5(aml: &[u8]) -> Option<SleepType> {
+    let index = aml.windows(4).position(|window| window == b"_S5_")?;
+    let is_name = match index {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => {
+            aml[index - 1] == NAME_OP || (aml[index - 2] == NAME_OP && aml[index - 1] == ROOT_CHAR)
+        }
+    };
+    let mut position = index + 4;
+    if !is_name || *aml.get(position)? != PACKAGE_OP {
+        return None;
+    }
+
+    // The package length takes between one and four bytes, the number
+    // of additional ones being in the top bits of the first
+    let length_bytes = usize::from(*aml.get(position + 1)? >> 6) + 1;
+    // Skip the package opcode, its length and the number of elements
+    position += 1 + length_bytes + 1;
+
+    let a = parse_integer(aml, &mut position)?;
+    let b = parse_integer(aml, &mut position)?;
+    Some(SleepType { a, b })
+}
+
+// Parses a small integer constant, as found in sleep packages
+fn parse_integer(aml: &[u8], position: &mut usize) -> Option<u8> {
+    let (value, length) = match *aml.get(*position)? {
+        ZERO_OP => (0, 1),
+        ONE_OP => (1, 1),
+        BYTE_PREFIX => (*aml.get(*position + 1)?, 2),
+        WORD_PREFIX => (*aml.get(*position + 1)?, 3),
+        _ => return None,
+    };
+    *position += length;
+    Some(value)
+}
+
+#[test_case]
+fn test_parse_s5() {
+    // As compiled by iasl from `Name (_S5, Package (0x04) { 0x05, Zero, Zero, Zero })`
+    let aml = [
+        &[0x10, 0x08, NAME_OP][..],
+        b"_S5_",
+        &[PACKAGE_OP, 0x06, 0x04],
+        &[BYTE_PREFIX, 0x05, ZERO_OP, ZERO_OP, ZERO_OP],
+    ]
+    .concat();
+    assert_eq!(parse_s5(&aml), Some(SleepType { a: 5, b: 0 }));
+
+    let rooted = [
+        NAME_OP, ROOT_CHAR, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x04, 0x02, ONE_OP, ONE_OP,
+    ];
+    assert_eq!(parse_s5(&rooted), Some(SleepType { a: 1, b: 1 }));
+
+    // A method named _S5_ is not a sleep package
+    assert_eq!(parse_s5(&[0x14, 0x06, b'_', b'S', b'5', b'_', 0x00]), None);
+}
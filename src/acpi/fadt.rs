@@ -0,0 +1,150 @@
+// The Fixed ACPI Description Table ("FACP")
+//
+// The table has grown with every ACPI revision, newer fields being
+// appended. Fields that the firmware's revision does not have read as
+// zero. The 64 bit "X_" variants of addresses replace the 32 bit ones
+// when set.
+
+use super::{
+    find_table, read_phys_prefix, AddressSpace, GenericAddress, RawGenericAddress, SdtHeader,
+};
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+const FLAG_PM_TIMER_32_BIT: u32 = 1 << 8;
+const FLAG_RESET_REGISTER: u32 = 1 << 10;
+const FLAG_HARDWARE_REDUCED: u32 = 1 << 20;
+
+// Boot architecture flag saying that there is a keyboard controller
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+// Everything after the header, up to the last field we care about
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct RawFadt {
+    firmware_control: u32,
+    dsdt: u32,
+    reserved: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_request: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_length: u8,
+    gpe1_length: u8,
+    gpe1_base: u8,
+    cstate_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    century: u8,
+    boot_architecture: u16,
+    reserved2: u8,
+    flags: u32,
+    reset_register: RawGenericAddress,
+    reset_value: u8,
+    arm_boot_architecture: u16,
+    minor_version: u8,
+    x_firmware_control: u64,
+    x_dsdt: u64,
+    x_pm1a_event_block: RawGenericAddress,
+    x_pm1b_event_block: RawGenericAddress,
+    x_pm1a_control_block: RawGenericAddress,
+    x_pm1b_control_block: RawGenericAddress,
+    x_pm2_control_block: RawGenericAddress,
+    x_pm_timer_block: RawGenericAddress,
+}
+
+// The power management registers are I/O ports, `None` if absent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub revision: u8,
+    pub sci_interrupt: u16,
+    // Writing `acpi_enable` to this port hands the power management
+    // registers from the firmware to us, `None` if they are ours already
+    pub smi_command_port: Option<u16>,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: Option<u16>,
+    pub pm1b_event_block: Option<u16>,
+    pub pm1_event_length: u8,
+    pub pm1a_control_block: Option<u16>,
+    pub pm1b_control_block: Option<u16>,
+    pub pm1_control_length: u8,
+    pub pm_timer_block: Option<u16>,
+    pub pm_timer_is_32_bit: bool,
+    // CMOS register holding the century, 0 if there is none
+    pub century_register: u8,
+    pub has_8042: bool,
+    pub hardware_reduced: bool,
+    pub dsdt: PhysAddr,
+    // Writing `reset_value` to it resets the machine
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+// An I/O port given by the 32 bit field or its extended replacement
+fn port(legacy: u32, extended: RawGenericAddress) -> Option<u16> {
+    match extended.parse() {
+        Some(address) if address.space == AddressSpace::SystemIo => Some(address.address as u16),
+        _ if legacy != 0 => Some(legacy as u16),
+        _ => None,
+    }
+}
+
+// Parses the FADT, or returns `None` if there is none
+pub fn fadt() -> Option<Fadt> {
+    let (table, header) = find_table(b"FACP")?;
+    let length = (header.length as usize).saturating_sub(size_of::<SdtHeader>());
+    let raw: RawFadt = read_phys_prefix(table + size_of::<SdtHeader>(), length);
+
+    let flags = raw.flags;
+    let dsdt = match raw.x_dsdt {
+        0 => u64::from(raw.dsdt),
+        x_dsdt => x_dsdt,
+    };
+    Some(Fadt {
+        revision: header.revision,
+        sci_interrupt: raw.sci_interrupt,
+        smi_command_port: Some(raw.smi_command as u16).filter(|&port| port != 0),
+        acpi_enable: raw.acpi_enable,
+        acpi_disable: raw.acpi_disable,
+        pm1a_event_block: port(raw.pm1a_event_block, raw.x_pm1a_event_block),
+        pm1b_event_block: port(raw.pm1b_event_block, raw.x_pm1b_event_block),
+        pm1_event_length: raw.pm1_event_length,
+        pm1a_control_block: port(raw.pm1a_control_block, raw.x_pm1a_control_block),
+        pm1b_control_block: port(raw.pm1b_control_block, raw.x_pm1b_control_block),
+        pm1_control_length: raw.pm1_control_length,
+        pm_timer_block: port(raw.pm_timer_block, raw.x_pm_timer_block),
+        pm_timer_is_32_bit: flags & FLAG_PM_TIMER_32_BIT != 0,
+        century_register: raw.century,
+        has_8042: raw.boot_architecture & BOOT_ARCH_8042 != 0,
+        hardware_reduced: flags & FLAG_HARDWARE_REDUCED != 0,
+        dsdt: PhysAddr::new(dsdt),
+        reset_register: if flags & FLAG_RESET_REGISTER != 0 {
+            raw.reset_register.parse()
+        } else {
+            None
+        },
+        reset_value: raw.reset_value,
+    })
+}
@@ -0,0 +1,54 @@
+// The High Precision Event Timer Description Table ("HPET")
+
+use super::{find_table, read_phys, AddressSpace, RawGenericAddress, SdtHeader};
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct RawHpet {
+    event_timer_block_id: u32,
+    base_address: RawGenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    pub counter_is_64_bit: bool,
+    // Whether the timer can replace the PIT and RTC interrupts
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    // Start of the memory mapped registers
+    pub address: PhysAddr,
+    pub number: u8,
+    // Smallest period in periodic mode, in main counter ticks
+    pub minimum_tick: u16,
+}
+
+// Parses the HPET table, or returns `None` if there is none or the timer
+// is not memory mapped
+pub fn hpet() -> Option<Hpet> {
+    let (table, _) = find_table(b"HPET")?;
+    let raw: RawHpet = read_phys(table + size_of::<SdtHeader>());
+    let base = raw.base_address.parse()?;
+    if base.space != AddressSpace::SystemMemory {
+        return None;
+    }
+
+    let id = raw.event_timer_block_id;
+    Some(Hpet {
+        hardware_revision: id as u8,
+        // The ID holds the number of the last comparator
+        comparators: ((id >> 8) & 0x1f) as u8 + 1,
+        counter_is_64_bit: id & (1 << 13) != 0,
+        legacy_replacement: id & (1 << 15) != 0,
+        pci_vendor_id: (id >> 16) as u16,
+        address: PhysAddr::new(base.address),
+        number: raw.hpet_number,
+        minimum_tick: raw.minimum_tick,
+    })
+}
@@ -0,0 +1,199 @@
+// The Multiple APIC Description Table ("APIC")
+//
+// After the address of the local APICs and some flags, the table holds a
+// list of variable sized entries, each starting with its type and length.
+
+use super::{find_table, read_phys, SdtHeader};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+
+// Local APIC flags saying whether the processor is enabled or can at
+// least be enabled
+const FLAG_ENABLED: u32 = 1 << 0;
+const FLAG_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// Table flag saying that the machine also has the two legacy PICs
+const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+
+// Processor ID of local APIC NMI entries that apply to all processors
+const ALL_PROCESSORS: u8 = 0xff;
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub has_legacy_pics: bool,
+    // Only the processors that are enabled or can be enabled
+    pub processors: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+// A processor listed in the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    // First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+// An ISA interrupt that is not wired to the global system interrupt of
+// the same number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+// A local APIC input connected to the non-maskable interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    // `None` if it applies to all processors
+    pub processor_id: Option<u8>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    BusDefault,
+    Edge,
+    Level,
+}
+
+// Splits the MPS INTI flags shared by overrides and NMI entries
+fn parse_inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    };
+    (polarity, trigger)
+}
+
+// Parses the MADT, or returns `None` if there is none
+pub fn madt() -> Option<Madt> {
+    let (table, header): (PhysAddr, SdtHeader) = find_table(b"APIC")?;
+    let header_end = table + size_of::<SdtHeader>();
+    let local_apic_address: u32 = read_phys(header_end);
+    let flags: u32 = read_phys(header_end + 4u64);
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(local_apic_address)),
+        has_legacy_pics: flags & FLAG_PCAT_COMPAT != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+        nmis: Vec::new(),
+    };
+
+    let mut entry = header_end + 8u64;
+    let end = table + u64::from(header.length);
+    while entry + 2u64 <= end {
+        let entry_type: u8 = read_phys(entry);
+        let length: u8 = read_phys(entry + 1u64);
+        if length < 2 || entry + u64::from(length) > end {
+            break; // malformed, we would never get further
+        }
+
+        match entry_type {
+            ENTRY_LOCAL_APIC => {
+                let flags: u32 = read_phys(entry + 4u64);
+                if flags & (FLAG_ENABLED | FLAG_ONLINE_CAPABLE) != 0 {
+                    madt.processors.push(LocalApic {
+                        processor_id: read_phys(entry + 2u64),
+                        apic_id: read_phys(entry + 3u64),
+                    });
+                }
+            }
+            ENTRY_IO_APIC => madt.io_apics.push(IoApic {
+                id: read_phys(entry + 2u64),
+                address: PhysAddr::new(u64::from(read_phys::<u32>(entry + 4u64))),
+                gsi_base: read_phys(entry + 8u64),
+            }),
+            ENTRY_INTERRUPT_OVERRIDE => {
+                let (polarity, trigger) = parse_inti_flags(read_phys(entry + 8u64));
+                madt.overrides.push(InterruptOverride {
+                    bus: read_phys(entry + 2u64),
+                    source: read_phys(entry + 3u64),
+                    gsi: read_phys(entry + 4u64),
+                    polarity,
+                    trigger,
+                });
+            }
+            ENTRY_LOCAL_APIC_NMI => {
+                let processor_id: u8 = read_phys(entry + 2u64);
+                let (polarity, trigger) = parse_inti_flags(read_phys(entry + 3u64));
+                madt.nmis.push(LocalApicNmi {
+                    processor_id: Some(processor_id).filter(|&id| id != ALL_PROCESSORS),
+                    lint: read_phys(entry + 5u64),
+                    polarity,
+                    trigger,
+                });
+            }
+            // Replaces the 32 bit address from the header
+            ENTRY_LOCAL_APIC_ADDRESS => {
+                madt.local_apic_address = PhysAddr::new(read_phys(entry + 4u64));
+            }
+            _ => {}
+        }
+        entry += u64::from(length);
+    }
+    Some(madt)
+}
+
+impl Madt {
+    // Global system interrupt that the given ISA IRQ arrives at
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> u32 {
+        self.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .map_or(u32::from(irq), |o| o.gsi)
+    }
+}
+
+#[test_case]
+fn test_parse_inti_flags() {
+    assert_eq!(
+        parse_inti_flags(0),
+        (Polarity::BusDefault, TriggerMode::BusDefault)
+    );
+    assert_eq!(
+        parse_inti_flags(0b1101),
+        (Polarity::ActiveHigh, TriggerMode::Level)
+    );
+    assert_eq!(
+        parse_inti_flags(0b0111),
+        (Polarity::ActiveLow, TriggerMode::Edge)
+    );
+}
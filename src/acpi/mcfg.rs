@@ -0,0 +1,79 @@
+// The PCI Express memory mapped configuration table ("MCFG")
+//
+// The table lists the regions through which the configuration space of
+// the PCI buses can be accessed with plain memory accesses.
+
+use super::{find_table, read_phys, SdtHeader};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct RawEntry {
+    base_address: u64,
+    segment: u16,
+    bus_start: u8,
+    bus_end: u8,
+    reserved: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciConfigRegion {
+    // Where the configuration space of bus 0 would be, even if the
+    // region starts at a later bus
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub bus_start: u8,
+    pub bus_end: u8,
+}
+
+impl PciConfigRegion {
+    // Address of the 4 KiB configuration space of the given function,
+    // or `None` if its bus is not in this region
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if bus < self.bus_start || bus > self.bus_end || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset =
+            (u64::from(bus) << 20) | (u64::from(device) << 15) | (u64::from(function) << 12);
+        Some(self.base + offset)
+    }
+}
+
+// Lists the configuration space regions, or returns `None` if there is
+// no MCFG, as on machines without PCI Express
+pub fn mcfg() -> Option<Vec<PciConfigRegion>> {
+    let (table, header) = find_table(b"MCFG")?;
+    // The entries follow 8 reserved bytes
+    let start = size_of::<SdtHeader>() + 8;
+    let entries = (header.length as usize).saturating_sub(start) / size_of::<RawEntry>();
+
+    let regions = (0..entries)
+        .map(|i| read_phys::<RawEntry>(table + start + i * size_of::<RawEntry>()))
+        .map(|entry| PciConfigRegion {
+            base: PhysAddr::new(entry.base_address),
+            segment: entry.segment,
+            bus_start: entry.bus_start,
+            bus_end: entry.bus_end,
+        })
+        .collect();
+    Some(regions)
+}
+
+#[test_case]
+fn test_config_address() {
+    let region = PciConfigRegion {
+        base: PhysAddr::new(0xb000_0000),
+        segment: 0,
+        bus_start: 0,
+        bus_end: 0xff,
+    };
+    assert_eq!(
+        region.config_address(1, 2, 3),
+        Some(PhysAddr::new(
+            0xb000_0000 + (1 << 20) + (2 << 15) + (3 << 12)
+        ))
+    );
+    assert_eq!(region.config_address(0, 32, 0), None);
+}
@@ -1,3 +1,4 @@
+use crate::lock::{SpinLock, SpinLockGuard};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
 use fixed_size_block::FixedSizeBlockAllocator;
@@ -15,7 +16,7 @@ pub mod linked_list;
 pub struct Dummy;
 
 pub const HEAP_START: usize = 0x4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1MiB, enough for a few thread stacks
 
 unsafe impl GlobalAlloc for Dummy {
     unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
@@ -58,18 +59,21 @@ pub fn init_heap(
     Ok(())
 }
 
+// Allocations can happen in interrupt handlers, so the lock
+// keeps interrupts disabled while it is held
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: SpinLock<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: SpinLock::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, A> {
         self.inner.lock()
     }
 }
@@ -0,0 +1,154 @@
+// The Local APIC of each CPU
+//
+// We keep using the legacy PICs for device interrupts. The Local APIC
+// is needed to start the other CPUs, to send interrupts between CPUs
+// and as a timer on the CPUs that do not receive the PIT's interrupts.
+//
+// Every CPU sees its own Local APIC at the same physical address, so a
+// single mapping serves all of them.
+
+use crate::interrupts::InterruptIndex;
+use crate::{memory, time};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+
+// Register offsets
+const ID: usize = 0x20;
+const EOI: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// Divides the bus clock by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// Virtual address of the registers, zero until `init` mapped them
+static BASE: AtomicU64 = AtomicU64::new(0);
+// Timer counts per PIT tick, measured by `init`
+static TIMER_COUNTS_PER_TICK: AtomicU32 = AtomicU32::new(0);
+
+fn read(register: usize) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::read_volatile((base as usize + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::write_volatile((base as usize + register) as *mut u32, value) }
+}
+
+// Maps the registers, enables the boot CPU's Local APIC and measures the
+// frequency of its timer. Needs `memory::install` to have been called
+// and interrupts to be enabled.
+pub fn init() {
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & 0xf_ffff_f000;
+    let virt = memory::map_mmio(PhysAddr::new(base), 4096).expect("failed to map the Local APIC");
+    BASE.store(virt.as_u64(), Ordering::Relaxed);
+
+    enable();
+    calibrate_timer();
+}
+
+// Whether `init` was called already
+pub fn is_initialised() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+// Enables the calling CPU's Local APIC
+pub fn enable() {
+    write(
+        SPURIOUS,
+        SPURIOUS_ENABLE | u32::from(InterruptIndex::ApicSpurious.as_u8()),
+    );
+}
+
+// ID of the calling CPU's Local APIC
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+// Signals the end of an interrupt delivered by the Local APIC
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+// Counts down from the largest value for a few PIT ticks
+fn calibrate_timer() {
+    const TICKS: u64 = 5;
+
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(LVT_TIMER, LVT_MASKED);
+
+    // Start right after a tick, so that we measure full ticks
+    let start = time::ticks() + 1;
+    while time::ticks() < start {
+        x86_64::instructions::hlt();
+    }
+    write(TIMER_INITIAL_COUNT, u32::MAX);
+    while time::ticks() < start + TICKS {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = u32::MAX - read(TIMER_CURRENT_COUNT);
+    write(TIMER_INITIAL_COUNT, 0);
+
+    TIMER_COUNTS_PER_TICK.store(elapsed / TICKS as u32, Ordering::Relaxed);
+}
+
+// Makes the calling CPU's timer raise `InterruptIndex::ApicTimer` at
+// about the same rate as the PIT
+pub fn start_timer() {
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(
+        LVT_TIMER,
+        LVT_TIMER_PERIODIC | u32::from(InterruptIndex::ApicTimer.as_u8()),
+    );
+    write(
+        TIMER_INITIAL_COUNT,
+        TIMER_COUNTS_PER_TICK.load(Ordering::Relaxed).max(1),
+    );
+}
+
+// Sends an inter-processor interrupt with the given command
+fn send(apic_id: u8, command: u32) {
+    // An interrupt handler sending an IPI in between
+    // would overwrite the destination
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write(ICR_HIGH, u32::from(apic_id) << 24);
+        // Writing the low half sends it
+        write(ICR_LOW, command);
+        while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+// Raises the interrupt `vector` on the CPU with the given APIC ID
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send(apic_id, ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+// Resets the CPU with the given APIC ID, after which
+// it waits for a startup IPI
+pub fn send_init(apic_id: u8) {
+    send(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+// Starts the CPU with the given APIC ID in real mode at the
+// beginning of the 4 KiB page with the given number
+pub fn send_startup(apic_id: u8, page: u8) {
+    send(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
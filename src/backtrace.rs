@@ -0,0 +1,166 @@
+use crate::{memory, serial, symbols, vga_buffer};
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+// Stop walking after this many frames in case the chain of
+// frame pointers is corrupted and loops around
+const MAX_FRAMES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    // Address of the instruction that will be executed
+    // once the frame's function continues
+    pub return_address: u64,
+    pub frame_pointer: u64,
+}
+
+// Returns the frame pointer (RBP) of the calling function
+//
+// The kernel is compiled with `-C force-frame-pointers=yes` (see
+// `.cargo/config.toml`), so every function saves the caller's RBP at
+// [RBP] and the return address right above it, at [RBP + 8].
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+// Whether a saved frame pointer looks like something we can dereference
+fn is_valid_frame_pointer(rbp: u64) -> bool {
+    if rbp == 0 || rbp % 8 != 0 {
+        return false;
+    }
+
+    // Without the page tables being accessible we cannot tell whether
+    // the address is mapped, so we rely on the other checks only
+    if memory::physical_memory_offset().is_none() {
+        return true;
+    }
+
+    // Both the saved RBP and the return address have to be readable
+    match (VirtAddr::try_new(rbp), VirtAddr::try_new(rbp + 8)) {
+        (Ok(start), Ok(end)) => {
+            memory::translate_addr(start).is_some() && memory::translate_addr(end).is_some()
+        }
+        _ => false,
+    }
+}
+
+// Walks the chain of frame pointers starting at `rbp`, calling `f` for
+// every frame until it returns `false` or the chain ends
+pub fn walk(mut rbp: u64, mut f: impl FnMut(Frame) -> bool) {
+    for _ in 0..MAX_FRAMES {
+        if !is_valid_frame_pointer(rbp) {
+            return;
+        }
+
+        let (saved_rbp, return_address) = unsafe {
+            let ptr = rbp as *const u64;
+            (ptr.read(), ptr.add(1).read())
+        };
+
+        if return_address == 0
+            || !f(Frame {
+                return_address,
+                frame_pointer: rbp,
+            })
+        {
+            return;
+        }
+
+        // Stacks grow downwards, so the callers' frames always
+        // have to be at higher addresses
+        if saved_rbp <= rbp {
+            return;
+        }
+        rbp = saved_rbp;
+    }
+}
+
+// Walks the stack of the calling function
+#[inline(always)]
+pub fn trace(f: impl FnMut(Frame) -> bool) {
+    walk(frame_pointer(), f)
+}
+
+// Prints to both the serial interface and the VGA buffer, since the
+// screen might not be visible (e.g. during tests) and serial output
+// might not be connected
+fn emit(args: fmt::Arguments) {
+    serial::_print(args);
+    vga_buffer::_print(args);
+}
+
+fn print_address(index: usize, addr: u64) {
+    match symbols::resolve(addr) {
+        Some(symbol) => emit(format_args!(
+            "  {:>2}: {:#018x} - {}+{:#x}\n",
+            index, addr, symbol.name, symbol.offset
+        )),
+        None => emit(format_args!("  {:>2}: {:#018x} - <unknown>\n", index, addr)),
+    }
+}
+
+fn print_header() {
+    emit(format_args!("Backtrace:\n"));
+    if !symbols::available() {
+        emit(format_args!("  (no symbol table embedded)\n"));
+    }
+}
+
+fn print_frames(mut index: usize, rbp: u64) {
+    walk(rbp, |frame| {
+        // The return address points to the instruction after the call,
+        // subtracting one gives an address inside the call instruction
+        // so that calls at the very end of a function resolve correctly.
+        print_address(index, frame.return_address - 1);
+        index += 1;
+        true
+    });
+}
+
+// Prints a symbolized backtrace starting at `rip` and continuing with
+// the frames chained from `rbp`. Exception handlers use this to start
+// the backtrace at the instruction that caused the exception.
+pub fn print_from(rip: u64, rbp: u64) {
+    print_header();
+    print_address(0, rip);
+    print_frames(1, rbp);
+}
+
+// Prints a symbolized backtrace of the calling function
+#[inline(always)]
+pub fn print_backtrace() {
+    print_header();
+    print_frames(0, frame_pointer());
+}
+
+// Prints a backtrace for an exception. This has to be called directly
+// from the exception handler: the handler's own frame is skipped and the
+// backtrace starts at the instruction that caused the exception.
+#[inline(always)]
+pub fn print_exception_backtrace(stack_frame: &InterruptStackFrame) {
+    let handler_rbp = frame_pointer();
+    // The handler's prologue saved the interrupted code's RBP
+    let interrupted_rbp = if is_valid_frame_pointer(handler_rbp) {
+        unsafe { (handler_rbp as *const u64).read() }
+    } else {
+        0
+    };
+    print_from(stack_frame.instruction_pointer.as_u64(), interrupted_rbp);
+}
+
+// Ensure that walking the current stack finds at least the caller
+#[test_case]
+fn test_trace_finds_frames() {
+    let mut frames = 0;
+    trace(|_| {
+        frames += 1;
+        true
+    });
+    assert!(frames > 0);
+}
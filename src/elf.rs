@@ -0,0 +1,423 @@
+// Loading ELF64 executables to run in user mode
+//
+// `load` checks the headers, maps every PT_LOAD segment into a fresh
+// address space with the permissions the segment asks for, copies the
+// file contents there and zeroes the rest of the segment (.bss). It then
+// sets up the stack the way the System V ABI describes it for the start
+// of a process:
+//
+//   argc, argv[0..argc], NULL, envp[..], NULL, auxv pairs, AT_NULL
+//
+// with the strings they point to above, at the top of the stack. The
+// stack pointer is aligned to 16 bytes and points to argc.
+//
+// The image can come from anywhere, e.g. from the programs embedded in
+// the kernel, see `embedded`.
+
+use crate::memory::address_space::{USER_END, USER_START};
+use crate::memory::AddressSpace;
+use crate::usermode::{self, Exit};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+pub mod embedded;
+
+// The user stack sits at the very top of the user range, with an unmapped
+// guard page below
+pub const STACK_SIZE: u64 = 64 * 1024;
+const STACK_TOP: u64 = USER_END;
+const STACK_BOTTOM: u64 = STACK_TOP - STACK_SIZE;
+pub const GUARD_PAGE: u64 = STACK_BOTTOM - 4096;
+
+// Arguments and environment may take up to this part of the stack
+const MAX_ARGUMENTS_SIZE: u64 = STACK_SIZE / 4;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_VERSION_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// Auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // The image ends before a header or segment does
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    // Not a static executable, e.g. a shared object
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeader,
+    // A segment does not fit into the user range below the stack
+    SegmentOutOfRange,
+    NoLoadableSegments,
+    // The entry point is not in an executable segment
+    BadEntry,
+    ArgumentsTooLarge,
+    OutOfMemory,
+}
+
+struct Header {
+    entry: u64,
+    program_header_offset: u64,
+    program_header_count: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = image.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = image.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    let mut value = [0; 4];
+    value.copy_from_slice(bytes);
+    Ok(u32::from_le_bytes(value))
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = image.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    let mut value = [0; 8];
+    value.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+fn parse_header(image: &[u8]) -> Result<Header, ElfError> {
+    if image.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if image[0..4] != *b"\x7fELF" {
+        return Err(ElfError::BadMagic);
+    }
+    if image[4] != ELF_CLASS_64 {
+        return Err(ElfError::NotElf64);
+    }
+    if image[5] != ELF_DATA_LITTLE_ENDIAN {
+        return Err(ElfError::NotLittleEndian);
+    }
+    if image[6] != ELF_VERSION_CURRENT || read_u32(image, 20)? != 1 {
+        return Err(ElfError::BadVersion);
+    }
+    if read_u16(image, 16)? != ET_EXEC {
+        return Err(ElfError::NotExecutable);
+    }
+    if read_u16(image, 18)? != EM_X86_64 {
+        return Err(ElfError::WrongMachine);
+    }
+
+    let program_header_count = read_u16(image, 56)?;
+    if program_header_count > 0 && usize::from(read_u16(image, 54)?) != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::BadProgramHeader);
+    }
+    Ok(Header {
+        entry: read_u64(image, 24)?,
+        program_header_offset: read_u64(image, 32)?,
+        program_header_count,
+    })
+}
+
+fn parse_program_headers(image: &[u8], header: &Header) -> Result<Vec<ProgramHeader>, ElfError> {
+    let start = usize::try_from(header.program_header_offset).map_err(|_| ElfError::Truncated)?;
+    (0..usize::from(header.program_header_count))
+        .map(|i| {
+            let offset = start
+                .checked_add(i * PROGRAM_HEADER_SIZE)
+                .ok_or(ElfError::Truncated)?;
+            let end = offset
+                .checked_add(PROGRAM_HEADER_SIZE)
+                .ok_or(ElfError::Truncated)?;
+            let entry = image.get(offset..end).ok_or(ElfError::Truncated)?;
+            Ok(ProgramHeader {
+                kind: read_u32(entry, 0)?,
+                flags: read_u32(entry, 4)?,
+                offset: read_u64(entry, 8)?,
+                address: read_u64(entry, 16)?,
+                file_size: read_u64(entry, 32)?,
+                memory_size: read_u64(entry, 40)?,
+            })
+        })
+        .collect()
+}
+
+impl ProgramHeader {
+    fn check(&self, image: &[u8]) -> Result<(), ElfError> {
+        if self.file_size > self.memory_size {
+            return Err(ElfError::BadProgramHeader);
+        }
+        match self.offset.checked_add(self.file_size) {
+            Some(end) if end <= image.len() as u64 => {}
+            _ => return Err(ElfError::Truncated),
+        }
+        match self.address.checked_add(self.memory_size) {
+            Some(end) if self.address >= USER_START && end <= GUARD_PAGE => Ok(()),
+            _ => Err(ElfError::SegmentOutOfRange),
+        }
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        (self.address..self.address + self.memory_size).contains(&address)
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::containing_address(VirtAddr::new(self.address));
+        let last = Page::containing_address(VirtAddr::new(self.address + self.memory_size - 1));
+        Page::range_inclusive(first, last)
+    }
+}
+
+// A program loaded into its address space, ready to run
+pub struct Program {
+    address_space: AddressSpace,
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+    // First page above all segments, where the heap can start
+    program_break: VirtAddr,
+}
+
+impl Program {
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        self.entry
+    }
+
+    pub fn stack_pointer(&self) -> VirtAddr {
+        self.stack_pointer
+    }
+
+    pub fn program_break(&self) -> VirtAddr {
+        self.program_break
+    }
+
+    // Runs the program on the current thread until it exits
+    pub fn run(self) -> Exit {
+        usermode::enter_address_space(
+            self.address_space.page_table(),
+            self.entry,
+            self.stack_pointer,
+        )
+    }
+
+    // The address space, entry point and initial stack pointer, e.g. for
+    // a process to keep the address space while the program runs
+    pub fn into_parts(self) -> (AddressSpace, VirtAddr, VirtAddr) {
+        (self.address_space, self.entry, self.stack_pointer)
+    }
+}
+
+// Loads the executable in `image` into a new address space, with a stack
+// holding the given arguments and environment variables
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let header = parse_header(image)?;
+    let segments: Vec<ProgramHeader> = parse_program_headers(image, &header)?
+        .into_iter()
+        .filter(|segment| segment.kind == PT_LOAD && segment.memory_size > 0)
+        .collect();
+    if segments.is_empty() {
+        return Err(ElfError::NoLoadableSegments);
+    }
+    for segment in &segments {
+        segment.check(image)?;
+    }
+    let executable = |segment: &&ProgramHeader| segment.flags & PF_X != 0;
+    if !segments
+        .iter()
+        .filter(executable)
+        .any(|s| s.contains(header.entry))
+    {
+        return Err(ElfError::BadEntry);
+    }
+
+    // Segments may share a page, which then gets the permissions of both
+    let mut pages = BTreeMap::new();
+    for segment in &segments {
+        for page in segment.pages() {
+            let (writable, executable) = pages.entry(page).or_insert((false, false));
+            *writable |= segment.flags & PF_W != 0;
+            *executable |= segment.flags & PF_X != 0;
+        }
+    }
+
+    let mut address_space = AddressSpace::new().ok_or(ElfError::OutOfMemory)?;
+    for (&page, &(writable, executable)) in &pages {
+        let mut flags = PageTableFlags::empty();
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        address_space
+            .map(page, flags)
+            .map_err(|_| ElfError::OutOfMemory)?;
+    }
+    for segment in &segments {
+        let start = segment.offset as usize;
+        let data = &image[start..start + segment.file_size as usize];
+        let address = VirtAddr::new(segment.address);
+        // Checked to be mapped above, and fresh frames are zeroed already,
+        // but .bss may share a page with data of another segment
+        address_space.write(address, data).unwrap();
+        let bss = (segment.memory_size - segment.file_size) as usize;
+        address_space
+            .zero(address + segment.file_size, bss)
+            .unwrap();
+    }
+
+    let mut auxv = Vec::new();
+    // The program headers are only visible to the program if a segment
+    // loads that part of the file
+    let table_size = u64::from(header.program_header_count) * PROGRAM_HEADER_SIZE as u64;
+    let table = segments.iter().find(|segment| {
+        segment.offset <= header.program_header_offset
+            && header.program_header_offset + table_size <= segment.offset + segment.file_size
+    });
+    if let Some(segment) = table {
+        auxv.push((
+            AT_PHDR,
+            segment.address + header.program_header_offset - segment.offset,
+        ));
+    }
+    auxv.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxv.push((AT_PHNUM, u64::from(header.program_header_count)));
+    auxv.push((AT_PAGESZ, 4096));
+    auxv.push((AT_ENTRY, header.entry));
+
+    let stack_pointer = set_up_stack(&mut address_space, argv, envp, &auxv)?;
+    let end = segments
+        .iter()
+        .map(|segment| segment.address + segment.memory_size)
+        .max()
+        .unwrap();
+    Ok(Program {
+        address_space,
+        entry: VirtAddr::new(header.entry),
+        stack_pointer,
+        program_break: VirtAddr::new(end).align_up(4096u64),
+    })
+}
+
+// Maps the stack and writes the arguments, environment and auxiliary
+// vector to its top. Returns the initial stack pointer.
+fn set_up_stack(
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    let first = Page::containing_address(VirtAddr::new(STACK_BOTTOM));
+    let last = Page::containing_address(VirtAddr::new(STACK_TOP - 1));
+    for page in Page::range_inclusive(first, last) {
+        address_space
+            .map(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+            .map_err(|_| ElfError::OutOfMemory)?;
+    }
+
+    // The strings go right at the top, each terminated by a zero byte
+    let strings_size: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 1);
+    let total = strings_size + words as u64 * 8 + 16;
+    if total > MAX_ARGUMENTS_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    let mut strings = Vec::with_capacity(strings_size as usize);
+    let strings_start = STACK_TOP - strings_size;
+    let mut pointers = Vec::with_capacity(argv.len() + envp.len());
+    for string in argv.iter().chain(envp) {
+        pointers.push(strings_start + strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+    let (arg_pointers, env_pointers) = pointers.split_at(argv.len());
+
+    let mut table = Vec::with_capacity(words);
+    table.push(argv.len() as u64);
+    table.extend_from_slice(arg_pointers);
+    table.push(0);
+    table.extend_from_slice(env_pointers);
+    table.push(0);
+    for &(key, value) in auxv {
+        table.push(key);
+        table.push(value);
+    }
+    table.push(AT_NULL);
+    table.push(0);
+
+    let stack_pointer = (strings_start - table.len() as u64 * 8) & !15;
+    let table_bytes: Vec<u8> = table.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space
+        .write(VirtAddr::new(strings_start), &strings)
+        .unwrap();
+    address_space
+        .write(VirtAddr::new(stack_pointer), &table_bytes)
+        .unwrap();
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+#[test_case]
+fn test_header_checks() {
+    let mut image = [0u8; HEADER_SIZE];
+    assert_eq!(parse_header(&image[..10]).err(), Some(ElfError::Truncated));
+    assert_eq!(parse_header(&image).err(), Some(ElfError::BadMagic));
+
+    image[0..4].copy_from_slice(b"\x7fELF");
+    image[4] = 1;
+    assert_eq!(parse_header(&image).err(), Some(ElfError::NotElf64));
+    image[4] = ELF_CLASS_64;
+    image[5] = 2;
+    assert_eq!(parse_header(&image).err(), Some(ElfError::NotLittleEndian));
+    image[5] = ELF_DATA_LITTLE_ENDIAN;
+    image[6] = ELF_VERSION_CURRENT;
+    image[20] = 1;
+    // A shared object
+    image[16] = 3;
+    assert_eq!(parse_header(&image).err(), Some(ElfError::NotExecutable));
+    image[16] = ET_EXEC as u8;
+    image[18] = 0x28;
+    assert_eq!(parse_header(&image).err(), Some(ElfError::WrongMachine));
+    image[18] = EM_X86_64 as u8;
+    assert!(parse_header(&image).is_ok());
+
+    // A program header table that would end beyond the address space
+    image[32..40].copy_from_slice(&(u64::MAX - 2).to_le_bytes());
+    image[54] = PROGRAM_HEADER_SIZE as u8;
+    image[56] = 1;
+    let header = parse_header(&image).unwrap();
+    assert_eq!(
+        parse_program_headers(&image, &header).err(),
+        Some(ElfError::Truncated)
+    );
+}
@@ -0,0 +1,22 @@
+// User programs built into the kernel image
+//
+// Their sources are in user/, run user/build.sh after changing them.
+
+static PROGRAMS: &[(&str, &[u8])] = &[
+    ("block", include_bytes!("../../user/block.elf")),
+    ("hello", include_bytes!("../../user/hello.elf")),
+    ("spin", include_bytes!("../../user/spin.elf")),
+    ("syscalls", include_bytes!("../../user/syscalls.elf")),
+];
+
+// The ELF image of the embedded program `name`
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS
+        .iter()
+        .find(|(program, _)| *program == name)
+        .map(|(_, image)| *image)
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    PROGRAMS.iter().map(|(name, _)| *name)
+}
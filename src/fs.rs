@@ -0,0 +1,180 @@
+// A flat in-memory filesystem
+//
+// Files are byte vectors on the heap, looked up by their full path, and
+// are gone after a reboot. There are no directories, `/a/b` is simply the
+// name of a file. The embedded user programs show up read-only under
+// `/bin`, e.g. `/bin/hello`.
+//
+// `open` hands out files for the file tables of processes. Every opened
+// file has its own offset, shared by all descriptors referring to it.
+
+use crate::elf::embedded;
+use crate::process::file::File;
+use crate::syscall::Errno;
+use crate::thread::sync::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub const MAX_PATH: usize = 256;
+
+// Flags of `open`, with Linux's values
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+const O_ACCESS_MODE: u64 = 3;
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+
+type Contents = Arc<Mutex<Vec<u8>>>;
+
+static FILES: Mutex<BTreeMap<String, Contents>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone)]
+enum Node {
+    Embedded(&'static [u8]),
+    Memory(Contents),
+}
+
+fn new_contents() -> Contents {
+    Arc::new(Mutex::new(Vec::new()))
+}
+
+fn find(path: &str) -> Option<Node> {
+    if let Some(name) = path.strip_prefix("/bin/") {
+        if let Some(image) = embedded::find(name) {
+            return Some(Node::Embedded(image));
+        }
+    }
+    FILES.lock().get(path).cloned().map(Node::Memory)
+}
+
+fn check_path(path: &str) -> Result<(), Errno> {
+    if path.is_empty() {
+        Err(Errno::ENOENT)
+    } else if path.len() > MAX_PATH {
+        Err(Errno::ENAMETOOLONG)
+    } else {
+        Ok(())
+    }
+}
+
+// Opens the file at `path`, creating it with `O_CREAT`
+pub fn open(path: &str, flags: u64) -> Result<Arc<dyn File>, Errno> {
+    check_path(path)?;
+    let (readable, writable) = match flags & O_ACCESS_MODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let node = match find(path) {
+        Some(node) => node,
+        None if flags & O_CREAT != 0 => {
+            let mut files = FILES.lock();
+            let contents = files.entry(String::from(path)).or_insert_with(new_contents);
+            Node::Memory(contents.clone())
+        }
+        None => return Err(Errno::ENOENT),
+    };
+    match &node {
+        Node::Embedded(_) if writable || flags & O_TRUNC != 0 => return Err(Errno::EACCES),
+        Node::Memory(contents) if flags & O_TRUNC != 0 && writable => contents.lock().clear(),
+        _ => {}
+    }
+
+    Ok(Arc::new(OpenFile {
+        node,
+        readable,
+        writable,
+        append: flags & O_APPEND != 0,
+        offset: Mutex::new(0),
+    }))
+}
+
+// Replaces the contents of the file at `path`, creating it if needed
+pub fn write_file(path: &str, bytes: &[u8]) -> Result<(), Errno> {
+    check_path(path)?;
+    if find(path).map_or(false, |node| matches!(node, Node::Embedded(_))) {
+        return Err(Errno::EACCES);
+    }
+    let contents = FILES
+        .lock()
+        .entry(String::from(path))
+        .or_insert_with(new_contents)
+        .clone();
+    *contents.lock() = bytes.to_vec();
+    Ok(())
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, Errno> {
+    match find(path).ok_or(Errno::ENOENT)? {
+        Node::Embedded(image) => Ok(image.to_vec()),
+        Node::Memory(contents) => Ok(contents.lock().clone()),
+    }
+}
+
+pub fn remove_file(path: &str) -> Result<(), Errno> {
+    FILES.lock().remove(path).map(drop).ok_or(Errno::ENOENT)
+}
+
+// Paths of all files, the embedded programs included
+pub fn list() -> Vec<String> {
+    let mut paths: Vec<String> = embedded::names()
+        .map(|name| alloc::format!("/bin/{}", name))
+        .collect();
+    paths.extend(FILES.lock().keys().cloned());
+    paths
+}
+
+struct OpenFile {
+    node: Node,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    offset: Mutex<usize>,
+}
+
+impl File for OpenFile {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        if !self.readable {
+            return Err(Errno::EBADF);
+        }
+        let mut offset = self.offset.lock();
+        let guard;
+        let contents: &[u8] = match &self.node {
+            Node::Embedded(image) => image,
+            Node::Memory(contents) => {
+                guard = contents.lock();
+                &guard
+            }
+        };
+        let available = contents.get(*offset..).unwrap_or(&[]);
+        let count = buffer.len().min(available.len());
+        buffer[..count].copy_from_slice(&available[..count]);
+        *offset += count;
+        Ok(count)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, Errno> {
+        let contents = match &self.node {
+            Node::Memory(contents) if self.writable => contents,
+            _ => return Err(Errno::EBADF),
+        };
+        let mut offset = self.offset.lock();
+        let mut contents = contents.lock();
+        if self.append {
+            *offset = contents.len();
+        }
+        let end = *offset + buffer.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[*offset..end].copy_from_slice(buffer);
+        *offset = end;
+        Ok(buffer.len())
+    }
+}
@@ -0,0 +1,442 @@
+// An in-kernel GDB stub speaking the remote serial protocol on the
+// second serial port (COM2).
+//
+// Once `init` is called, breakpoint (`int3`) and debug (single step)
+// exceptions stop the whole kernel and hand control to GDB until it
+// continues or steps. Run QEMU with COM2 redirected to a socket, e.g.
+// `cargo run --features gdb -- -serial stdio -serial tcp::1234,server`,
+// then connect with `target remote localhost:1234` from GDB.
+
+use crate::interrupts::trap::TrapFrame;
+use crate::memory;
+use crate::serial::SERIAL2;
+use core::sync::atomic::{AtomicBool, Ordering};
+use packet::{decode_hex_bytes, parse_hex, Receiver, Reply, MAX_PACKET_SIZE};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::VirtAddr;
+
+mod packet;
+
+// Bit of RFLAGS that raises a debug exception after every instruction
+const TRAP_FLAG: u64 = 1 << 8;
+// Opcode of `int3`
+const INT3: u8 = 0xcc;
+const MAX_BREAKPOINTS: usize = 32;
+// Signal number reported to GDB for every stop (SIGTRAP)
+const STOP_REPLY: &[u8] = b"S05";
+// Reported for memory we refuse to access (EFAULT)
+const MEMORY_ERROR: &[u8] = b"E0e";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref STUB: Mutex<Stub> = Mutex::new(Stub::new());
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    // The byte that `int3` replaced
+    original: u8,
+}
+
+struct Stub {
+    receiver: Receiver,
+    reply: Reply,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    // Whether GDB resumed the kernel and now waits for a stop reply
+    resumed: bool,
+}
+
+// What the kernel should do after GDB's command was handled
+enum Action {
+    Stay,
+    Resume,
+}
+
+// Starts routing breakpoint and debug exceptions to the stub
+pub fn init() {
+    // Force initialisation of the serial port outside of exception context
+    lazy_static::initialize(&SERIAL2);
+    lazy_static::initialize(&STUB);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+// Stops the kernel and waits for GDB
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
+
+// Called by `interrupts::trap` for breakpoint and debug exceptions.
+// Returns `false` if the stub is not enabled and the exception should
+// be handled as usual.
+pub(crate) fn handle_trap(frame: &mut TrapFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+
+    // The trap flag is only ever set by us to execute a single step
+    frame.rflags &= !TRAP_FLAG;
+
+    // Exceptions are delivered through interrupt gates, so nothing
+    // can interrupt us while holding these locks
+    let mut stub = STUB.lock();
+    let mut port = SERIAL2.lock();
+    stub.run(&mut port, frame);
+    true
+}
+
+impl Stub {
+    fn new() -> Self {
+        Stub {
+            receiver: Receiver::new(),
+            reply: Reply::new(),
+            breakpoints: [None; MAX_BREAKPOINTS],
+            resumed: false,
+        }
+    }
+
+    // Talks to GDB until it lets the kernel continue
+    fn run(&mut self, port: &mut SerialPort, frame: &mut TrapFrame) {
+        if self.resumed {
+            self.reply.clear();
+            self.reply.push(STOP_REPLY);
+            self.reply.send(port);
+            self.resumed = false;
+        }
+
+        loop {
+            // Split the borrow so the command can be parsed straight
+            // from the receive buffer while building the reply
+            let Stub {
+                receiver,
+                reply,
+                breakpoints,
+                resumed,
+            } = self;
+            let command = receiver.receive(port);
+            reply.clear();
+
+            let action = handle_command(command, reply, breakpoints, frame);
+            match action {
+                Action::Stay => reply.send(port),
+                Action::Resume => {
+                    // `D` expects a reply, `c`, `s` and `k` do not
+                    if command.first() == Some(&b'D') {
+                        reply.send(port);
+                        *resumed = false;
+                    } else {
+                        *resumed = command.first() != Some(&b'k');
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn handle_command(
+    command: &[u8],
+    reply: &mut Reply,
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    frame: &mut TrapFrame,
+) -> Action {
+    let (&kind, args) = match command.split_first() {
+        Some(split) => split,
+        None => return Action::Stay,
+    };
+
+    match kind {
+        b'?' => reply.push(STOP_REPLY),
+        b'g' => {
+            for register in 0..REGISTER_COUNT {
+                let (value, size) = read_register(frame, register);
+                reply.push_hex_le(value, size);
+            }
+        }
+        b'G' => {
+            let mut offset = 0;
+            for register in 0..REGISTER_COUNT {
+                let size = read_register(frame, register).1;
+                match args.get(offset..offset + 2 * size).and_then(parse_le) {
+                    Some(value) => write_register(frame, register, value),
+                    None => break,
+                }
+                offset += 2 * size;
+            }
+            reply.push(b"OK");
+        }
+        b'p' => match parse_hex(args) {
+            Some(register) if (register as usize) < REGISTER_COUNT => {
+                let (value, size) = read_register(frame, register as usize);
+                reply.push_hex_le(value, size);
+            }
+            _ => reply.push(b"E00"),
+        },
+        b'P' => {
+            let mut parts = args.splitn(2, |&b| b == b'=');
+            let register = parts.next().and_then(parse_hex);
+            let value = parts.next().and_then(parse_le);
+            match (register, value) {
+                (Some(register), Some(value)) if (register as usize) < REGISTER_COUNT => {
+                    write_register(frame, register as usize, value);
+                    reply.push(b"OK");
+                }
+                _ => reply.push(b"E00"),
+            }
+        }
+        b'm' => read_memory(args, reply),
+        b'M' => write_memory(args, reply),
+        b'Z' | b'z' => update_breakpoint(kind == b'Z', args, reply, breakpoints),
+        b'c' | b's' => {
+            if let Some(address) = parse_hex(args) {
+                frame.rip = address;
+            }
+            if kind == b's' {
+                frame.rflags |= TRAP_FLAG;
+            }
+            return Action::Resume;
+        }
+        b'D' | b'k' => {
+            // Leave the kernel the way we found it
+            for breakpoint in breakpoints.iter_mut() {
+                if let Some(Breakpoint { address, original }) = breakpoint.take() {
+                    unsafe { write_byte(address, original) };
+                }
+            }
+            reply.push(b"OK");
+            return Action::Resume;
+        }
+        b'H' => reply.push(b"OK"),
+        b'q' if args.starts_with(b"Supported") => {
+            reply.push(b"PacketSize=");
+            reply.push_hex_number(MAX_PACKET_SIZE as u64);
+        }
+        b'q' if args == b"Attached" => reply.push(b"1"),
+        // An empty reply tells GDB that a command is not supported
+        _ => {}
+    }
+
+    Action::Stay
+}
+
+// Registers in the order of GDB's default amd64 description: 16 general
+// purpose registers, RIP, EFLAGS and the six segment registers. We do not
+// save DS, ES, FS and GS; they are reported as zero and writes are ignored.
+const REGISTER_COUNT: usize = 24;
+
+// Returns the value and size in bytes of the given register
+fn read_register(frame: &TrapFrame, register: usize) -> (u64, usize) {
+    match register {
+        0 => (frame.rax, 8),
+        1 => (frame.rbx, 8),
+        2 => (frame.rcx, 8),
+        3 => (frame.rdx, 8),
+        4 => (frame.rsi, 8),
+        5 => (frame.rdi, 8),
+        6 => (frame.rbp, 8),
+        7 => (frame.rsp, 8),
+        8 => (frame.r8, 8),
+        9 => (frame.r9, 8),
+        10 => (frame.r10, 8),
+        11 => (frame.r11, 8),
+        12 => (frame.r12, 8),
+        13 => (frame.r13, 8),
+        14 => (frame.r14, 8),
+        15 => (frame.r15, 8),
+        16 => (frame.rip, 8),
+        17 => (frame.rflags, 4),
+        18 => (frame.cs, 4),
+        19 => (frame.ss, 4),
+        _ => (0, 4),
+    }
+}
+
+fn write_register(frame: &mut TrapFrame, register: usize, value: u64) {
+    let target = match register {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        // Changing the segments would most likely crash the kernel
+        _ => return,
+    };
+    *target = value;
+}
+
+// Parses a little endian value of up to 8 bytes
+fn parse_le(digits: &[u8]) -> Option<u64> {
+    let mut bytes = [0; 8];
+    let len = decode_hex_bytes(digits, &mut bytes)?;
+    if len == 0 {
+        return None;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+// Parses the `addr,length` arguments of memory commands
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    let address = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)?;
+    Some((address, len))
+}
+
+// Whether every byte in the range is mapped
+fn is_accessible(address: u64, len: u64) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = match address.checked_add(len - 1) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut page = address & !0xfff;
+    loop {
+        match VirtAddr::try_new(page) {
+            Ok(addr) if memory::translate_addr(addr).is_some() => {}
+            _ => return false,
+        }
+        if page >= end & !0xfff {
+            return true;
+        }
+        page += 0x1000;
+    }
+}
+
+fn read_memory(args: &[u8], reply: &mut Reply) {
+    let (address, len) = match parse_range(args) {
+        Some(range) => range,
+        None => return reply.push(b"E00"),
+    };
+    // Every byte takes two hex digits
+    let len = len.min((reply.remaining() / 2) as u64);
+
+    if !is_accessible(address, len) {
+        return reply.push(MEMORY_ERROR);
+    }
+    for offset in 0..len {
+        let byte = unsafe { ((address + offset) as *const u8).read_volatile() };
+        reply.push_hex_byte(byte);
+    }
+}
+
+fn write_memory(args: &[u8], reply: &mut Reply) {
+    let mut parts = args.splitn(2, |&b| b == b':');
+    let range = parts.next().and_then(parse_range);
+    let data = parts.next();
+
+    let (address, len, data) = match (range, data) {
+        (Some((address, len)), Some(data)) if data.len() as u64 == 2 * len => (address, len, data),
+        _ => return reply.push(b"E00"),
+    };
+
+    if !is_accessible(address, len) {
+        return reply.push(MEMORY_ERROR);
+    }
+    for (offset, pair) in data.chunks(2).enumerate() {
+        let mut byte = [0];
+        if decode_hex_bytes(pair, &mut byte).is_none() {
+            return reply.push(b"E00");
+        }
+        unsafe { write_byte(address + offset as u64, byte[0]) };
+    }
+    reply.push(b"OK");
+}
+
+// Writes a byte even if the page is mapped read-only, which is needed to
+// place breakpoints into the kernel's code
+//
+// Unsafe because the caller has to ensure that the address is mapped.
+unsafe fn write_byte(address: u64, value: u8) {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    let flags = Cr0::read();
+    Cr0::write(flags - Cr0Flags::WRITE_PROTECT);
+    (address as *mut u8).write_volatile(value);
+    Cr0::write(flags);
+}
+
+// Handles `Z0,addr,kind` and `z0,addr,kind`. Only software breakpoints
+// (type 0) are supported, GDB falls back to them for everything else.
+fn update_breakpoint(
+    insert: bool,
+    args: &[u8],
+    reply: &mut Reply,
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+) {
+    let address = match args.strip_prefix(b"0,").and_then(parse_range) {
+        Some((address, _kind)) => address,
+        // Empty reply: breakpoint type not supported
+        None => return,
+    };
+
+    let existing = breakpoints
+        .iter()
+        .position(|breakpoint| matches!(breakpoint, Some(b) if b.address == address));
+
+    if insert {
+        if existing.is_some() {
+            return reply.push(b"OK");
+        }
+        if !is_accessible(address, 1) {
+            return reply.push(MEMORY_ERROR);
+        }
+        match breakpoints
+            .iter_mut()
+            .find(|breakpoint| breakpoint.is_none())
+        {
+            Some(slot) => {
+                let original = unsafe { (address as *const u8).read_volatile() };
+                unsafe { write_byte(address, INT3) };
+                *slot = Some(Breakpoint { address, original });
+                reply.push(b"OK");
+            }
+            // Out of slots
+            None => reply.push(b"E1c"),
+        }
+    } else {
+        if let Some(Breakpoint { address, original }) = existing.and_then(|i| breakpoints[i].take())
+        {
+            unsafe { write_byte(address, original) };
+        }
+        reply.push(b"OK");
+    }
+}
+
+#[test_case]
+fn test_parse_le() {
+    assert_eq!(parse_le(b"efbe0000"), Some(0xbeef));
+    assert_eq!(parse_le(b""), None);
+    assert_eq!(parse_le(b"efb"), None);
+    // More than 8 bytes
+    assert_eq!(parse_le(b"000000000000000000"), None);
+}
+
+#[test_case]
+fn test_parse_range() {
+    assert_eq!(parse_range(b"ffff8000,10"), Some((0xffff_8000, 0x10)));
+    assert_eq!(parse_range(b"ffff8000"), None);
+    assert_eq!(parse_range(b"ffff8000,"), None);
+    assert_eq!(parse_range(b"zz,10"), None);
+}
@@ -0,0 +1,296 @@
+// Framing of the GDB remote serial protocol. Every packet has the form
+// `$<data>#<checksum>`, where the checksum is the sum of all data bytes
+// modulo 256 as two hex digits. The receiver acknowledges each packet
+// with `+`, or asks for a retransmission with `-`. In the data we send,
+// bytes that have a meaning in the framing are escaped as `}` followed by
+// the byte XORed with 0x20.
+
+use uart_16550::SerialPort;
+
+// Largest packet we accept or send, advertised to GDB in `qSupported`
+pub const MAX_PACKET_SIZE: usize = 4096;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+const ESCAPE: u8 = b'}';
+
+// Where packets are received from and sent to, the serial port outside
+// of tests
+pub trait Link {
+    fn receive(&mut self) -> u8;
+    fn send(&mut self, byte: u8);
+}
+
+impl Link for SerialPort {
+    fn receive(&mut self) -> u8 {
+        SerialPort::receive(self)
+    }
+
+    fn send(&mut self, byte: u8) {
+        SerialPort::send(self, byte)
+    }
+}
+
+// `*` would start a run-length encoding
+fn needs_escape(byte: u8) -> bool {
+    matches!(byte, b'$' | b'#' | ESCAPE | b'*')
+}
+
+pub fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Parses a big endian hex number as used for addresses and lengths
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | u64::from(hex_value(digit)?))
+    })
+}
+
+// Decodes pairs of hex digits into `out`, returning the number of bytes
+pub fn decode_hex_bytes(digits: &[u8], out: &mut [u8]) -> Option<usize> {
+    if digits.len() % 2 != 0 || digits.len() / 2 > out.len() {
+        return None;
+    }
+    for (byte, pair) in out.iter_mut().zip(digits.chunks(2)) {
+        *byte = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(digits.len() / 2)
+}
+
+// Receives packets into a fixed buffer, since we cannot rely on the
+// heap while the rest of the kernel is stopped
+pub struct Receiver {
+    buffer: [u8; MAX_PACKET_SIZE],
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Receiver {
+            buffer: [0; MAX_PACKET_SIZE],
+        }
+    }
+
+    // Blocks until a packet with a valid checksum arrives and returns its data
+    pub fn receive<'a>(&'a mut self, port: &mut impl Link) -> &'a [u8] {
+        loop {
+            // Skip acknowledgements and anything else between packets
+            while port.receive() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum: u8 = 0;
+            let mut overflow = false;
+            loop {
+                let byte = port.receive();
+                if byte == b'#' {
+                    break;
+                }
+                if len < self.buffer.len() {
+                    self.buffer[len] = byte;
+                    len += 1;
+                } else {
+                    overflow = true;
+                }
+                checksum = checksum.wrapping_add(byte);
+            }
+
+            let expected = hex_value(port.receive())
+                .and_then(|high| Some(high << 4 | hex_value(port.receive())?));
+
+            if !overflow && expected == Some(checksum) {
+                port.send(b'+');
+                return &self.buffer[..len];
+            }
+            port.send(b'-');
+        }
+    }
+}
+
+// Builds the data of a reply packet
+pub struct Reply {
+    buffer: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    pub const fn new() -> Self {
+        Reply {
+            buffer: [0; MAX_PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // Bytes that can still be added to the reply
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.len
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        let len = data.len().min(self.remaining());
+        self.buffer[self.len..self.len + len].copy_from_slice(&data[..len]);
+        self.len += len;
+    }
+
+    pub fn push_hex_byte(&mut self, byte: u8) {
+        self.push(&[
+            HEX_DIGITS[usize::from(byte >> 4)],
+            HEX_DIGITS[usize::from(byte & 0xf)],
+        ]);
+    }
+
+    // Numbers such as sizes are sent big endian, without leading zeros
+    pub fn push_hex_number(&mut self, value: u64) {
+        let digits = (64 - value.leading_zeros() as usize + 3) / 4;
+        for digit in (0..digits.max(1)).rev() {
+            self.push(&[HEX_DIGITS[((value >> (4 * digit)) & 0xf) as usize]]);
+        }
+    }
+
+    // Registers are sent in target (little endian) byte order
+    pub fn push_hex_le(&mut self, value: u64, size: usize) {
+        for byte in value.to_le_bytes().iter().take(size) {
+            self.push_hex_byte(*byte);
+        }
+    }
+
+    // Sends the reply, retransmitting until GDB acknowledges it
+    pub fn send(&self, port: &mut impl Link) {
+        loop {
+            port.send(b'$');
+            // Of the bytes as sent, i.e. after escaping
+            let mut checksum: u8 = 0;
+            for &byte in &self.buffer[..self.len] {
+                let byte = if needs_escape(byte) {
+                    port.send(ESCAPE);
+                    checksum = checksum.wrapping_add(ESCAPE);
+                    byte ^ 0x20
+                } else {
+                    byte
+                };
+                port.send(byte);
+                checksum = checksum.wrapping_add(byte);
+            }
+            port.send(b'#');
+            port.send(HEX_DIGITS[usize::from(checksum >> 4)]);
+            port.send(HEX_DIGITS[usize::from(checksum & 0xf)]);
+
+            match port.receive() {
+                b'-' => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+// Plays back `input` as if it came from GDB and records what we send
+#[cfg(test)]
+struct TestLink<I> {
+    input: I,
+    sent: [u8; 64],
+    sent_len: usize,
+}
+
+#[cfg(test)]
+impl<I: Iterator<Item = u8>> TestLink<I> {
+    fn new(input: I) -> Self {
+        TestLink {
+            input,
+            sent: [0; 64],
+            sent_len: 0,
+        }
+    }
+
+    fn sent(&self) -> &[u8] {
+        &self.sent[..self.sent_len]
+    }
+}
+
+#[cfg(test)]
+impl<I: Iterator<Item = u8>> Link for TestLink<I> {
+    fn receive(&mut self) -> u8 {
+        self.input.next().expect("no more input")
+    }
+
+    fn send(&mut self, byte: u8) {
+        self.sent[self.sent_len] = byte;
+        self.sent_len += 1;
+    }
+}
+
+#[test_case]
+fn test_parse_hex() {
+    assert_eq!(parse_hex(b"ffff80001000"), Some(0xffff_8000_1000));
+    assert_eq!(parse_hex(b"0"), Some(0));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"12x4"), None);
+    // More than 64 bits
+    assert_eq!(parse_hex(b"10000000000000000"), None);
+}
+
+#[test_case]
+fn test_decode_hex_bytes() {
+    let mut out = [0; 2];
+    assert_eq!(decode_hex_bytes(b"01fF", &mut out), Some(2));
+    assert_eq!(out, [0x01, 0xff]);
+    assert_eq!(decode_hex_bytes(b"012", &mut out), None);
+    assert_eq!(decode_hex_bytes(b"0g", &mut out), None);
+    // Does not fit
+    assert_eq!(decode_hex_bytes(b"010203", &mut out), None);
+}
+
+#[test_case]
+fn test_receive_valid_packet() {
+    let mut receiver = Receiver::new();
+    // An acknowledgement is skipped
+    let mut link = TestLink::new(b"+$OK#9a".iter().copied());
+    assert_eq!(receiver.receive(&mut link), b"OK");
+    assert_eq!(link.sent(), b"+");
+}
+
+#[test_case]
+fn test_receive_bad_checksum() {
+    let mut receiver = Receiver::new();
+    let mut link = TestLink::new(b"$OK#00$OK#9x$OK#9A".iter().copied());
+    assert_eq!(receiver.receive(&mut link), b"OK");
+    assert_eq!(link.sent(), b"--+");
+}
+
+#[test_case]
+fn test_receive_overlong_packet() {
+    let mut receiver = Receiver::new();
+    let overlong = (0..=MAX_PACKET_SIZE).map(|_| 0);
+    // The data sums up to zero
+    let input = b"$".iter().copied().chain(overlong);
+    let mut link = TestLink::new(input.chain(b"#00$OK#9a".iter().copied()));
+    assert_eq!(receiver.receive(&mut link), b"OK");
+    assert_eq!(link.sent(), b"-+");
+}
+
+#[test_case]
+fn test_send_retransmits() {
+    let mut reply = Reply::new();
+    reply.push(b"OK");
+    let mut link = TestLink::new(b"-+".iter().copied());
+    reply.send(&mut link);
+    assert_eq!(link.sent(), b"$OK#9a$OK#9a");
+}
+
+#[test_case]
+fn test_send_escapes() {
+    let mut reply = Reply::new();
+    reply.push(b"a#b");
+    let mut link = TestLink::new(b"+".iter().copied());
+    reply.send(&mut link);
+    assert_eq!(link.sent(), b"$a}\x03b#43");
+}
@@ -1,59 +1,102 @@
-use lazy_static::lazy_static;
+use crate::per_cpu;
+use core::cell::UnsafeCell;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+// `syscall` and `sysret` expect the segments in this order: kernel
+// code and data, then user data and code
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+// Only modified by its own CPU. Apart from the kernel stack, which the
+// CPU only reads when entering the kernel from user mode, that happens
+// before the TSS is loaded.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// Being in a static makes sure that this will not
+// be allocated on a read-only page
+struct Stack(UnsafeCell<[u8; DOUBLE_FAULT_STACK_SIZE]>);
+
+// Loading a TSS marks its descriptor as busy, so every CPU needs a TSS
+// of its own, and with it its own stack for double faults
+per_cpu! {
+    static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            // `mut` is important so that this will not be allocated
-            // on a read-only page
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+per_cpu! {
+    static DOUBLE_FAULT_STACK: Stack = Stack(UnsafeCell::new([0; DOUBLE_FAULT_STACK_SIZE]));
+}
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            // We write the top address of the stack since stacks on x86
-            // grow downwards
-            stack_end
-        };
-        tss
-    };
+per_cpu! {
+    static GDT: spin::Once<(GlobalDescriptorTable, Selectors)> = spin::Once::new();
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+// Loads the calling CPU's GDT and TSS, which needs per-CPU data
+pub fn init() {
+    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::tables::load_tss;
+
+    let tss = TSS.get().0.get();
+    let gdt = GDT.get().call_once(|| {
+        let stack_start = VirtAddr::from_ptr(DOUBLE_FAULT_STACK.get().0.get());
+        // We write the top address of the stack since stacks on x86
+        // grow downwards
+        let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
+        unsafe { (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end };
+
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss }));
         (
             gdt,
             Selectors {
-                code_selector,
-                tss_selector,
+                kernel_code,
+                kernel_data,
+                user_data,
+                user_code,
+                tss,
             },
         )
-    };
-}
-
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
-    use x86_64::instructions::tables::load_tss;
+    });
 
-    GDT.0.load();
+    gdt.0.load();
 
     unsafe {
         // Reload code segment register
-        set_cs(GDT.1.code_selector);
+        set_cs(gdt.1.kernel_code);
         // Load the TSS
-        load_tss(GDT.1.tss_selector);
+        load_tss(gdt.1.tss);
     }
 }
+
+// The calling CPU's segment selectors, once `init` ran on it
+pub fn selectors() -> Selectors {
+    GDT.get().r#try().expect("GDT not loaded").1
+}
+
+// Sets the stack the CPU switches to when an interrupt arrives in
+// user mode
+pub fn set_kernel_stack(top: VirtAddr) {
+    let tss = TSS.get().0.get();
+    unsafe { (*tss).privilege_stack_table[0] = top };
+}
+
+// The stack set with `set_kernel_stack`
+pub fn kernel_stack() -> VirtAddr {
+    let tss = TSS.get().0.get();
+    unsafe { (*tss).privilege_stack_table[0] }
+}
@@ -1,10 +1,17 @@
 use crate::gdt;
 use crate::hlt_loop;
-use crate::{print, println};
+use crate::percpu::KernelGs;
+use crate::println;
+use crate::usermode::{self, Exit};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
+use stats::SpuriousSource;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+pub mod stats;
+pub mod trap;
 
 // Interrupt vector offsets for PICs
 // The first 32 slots are already taken by exception handlers
@@ -14,15 +21,35 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+// Command ports of the two PICs, used to query their
+// in-service registers
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // The lowest priority line of each PIC (IRQ7 and IRQ15) is
+    // also where it delivers spurious interrupts
+    PicSpuriousPrimary = PIC_1_OFFSET + 7,
+    PicSpuriousSecondary = PIC_2_OFFSET + 7,
+    // Timer of the Local APIC, which drives the CPUs that do
+    // not receive the PIT's interrupts
+    ApicTimer = 0xf0,
+    // Sent by other CPUs to get a halted CPU to look for work
+    Wakeup,
+    // Sent by other CPUs that want this CPU to run a function,
+    // see `smp::call_on`
+    Call,
+    // Vector we will program into the Local APIC's spurious
+    // interrupt vector register
+    ApicSpurious = 0xff,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -34,18 +61,45 @@ impl InterruptIndex {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
+            // The debugger needs the full register state for these two,
+            // so they go through the assembly stubs in `trap`
+            idt.breakpoint.set_handler_addr(trap::breakpoint_entry());
+            idt.debug.set_handler_addr(trap::debug_entry());
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt.page_fault.set_handler_fn(page_fault_handler);
-
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        // Exceptions the kernel does not cause, but user code can
+        idt.device_not_available
+            .set_handler_fn(device_not_available_handler);
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present
+            .set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault
+            .set_handler_fn(stack_segment_fault_handler);
+        idt.x87_floating_point
+            .set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.simd_floating_point
+            .set_handler_fn(simd_floating_point_handler);
 
         // Hardware interrupts
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::PicSpuriousPrimary.as_usize()]
+            .set_handler_fn(pic_spurious_primary_handler);
+        idt[InterruptIndex::PicSpuriousSecondary.as_usize()]
+            .set_handler_fn(pic_spurious_secondary_handler);
+        idt[InterruptIndex::ApicTimer.as_usize()].set_handler_fn(apic_timer_handler);
+        idt[InterruptIndex::Wakeup.as_usize()].set_handler_fn(wakeup_handler);
+        idt[InterruptIndex::Call.as_usize()].set_handler_fn(call_handler);
+        idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(apic_spurious_handler);
         idt
     };
 }
@@ -55,11 +109,6 @@ pub fn init_idt() {
     IDT.load();
 }
 
-// Breakpoint handler is invoked when the `int3` instruction is executed
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
 // Double fault handler is invoked when an exception occurs and
 // fails to invoke the corresponding handler.
 //
@@ -70,6 +119,8 @@ extern "x86-interrupt" fn double_fault_handler(
     // The error code is always 0 for double faults
     _error_code: u64,
 ) -> ! {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(8);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -79,6 +130,10 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(14);
+    kill_user_code(&stack_frame, 14);
+
     // CPU automatically sets the CR2 register to the address
     // accessed that caused the page fault
 
@@ -86,12 +141,136 @@ extern "x86-interrupt" fn page_fault_handler(
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
+    crate::backtrace::print_exception_backtrace(&stack_frame);
 
     hlt_loop();
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(0);
+    kill_user_code(&stack_frame, 0);
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(6);
+    kill_user_code(&stack_frame, 6);
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(13);
+    kill_user_code(&stack_frame, 13);
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(7);
+    kill_user_code(&stack_frame, 7);
+    panic!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(10);
+    kill_user_code(&stack_frame, 10);
+    panic!(
+        "EXCEPTION: INVALID TSS ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(11);
+    kill_user_code(&stack_frame, 11);
+    panic!(
+        "EXCEPTION: SEGMENT NOT PRESENT ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(12);
+    kill_user_code(&stack_frame, 12);
+    panic!(
+        "EXCEPTION: STACK SEGMENT FAULT ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(16);
+    kill_user_code(&stack_frame, 16);
+    panic!("EXCEPTION: X87 FLOATING POINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(17);
+    kill_user_code(&stack_frame, 17);
+    panic!(
+        "EXCEPTION: ALIGNMENT CHECK ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(19);
+    kill_user_code(&stack_frame, 19);
+    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
+}
+
+// Exceptions caused by user code only end that code, which continues
+// the kernel where it entered user mode. Returns if the exception
+// happened in the kernel.
+fn kill_user_code(stack_frame: &InterruptStackFrame, vector: u8) {
+    kill_user_code_at(
+        stack_frame.code_segment,
+        stack_frame.instruction_pointer,
+        vector,
+    );
+}
+
+// Like `kill_user_code`, given the code segment and instruction pointer
+// of the interrupted code
+fn kill_user_code_at(code_segment: u64, instruction_pointer: VirtAddr, vector: u8) {
+    if code_segment & 0b11 != 0 {
+        crate::warn!(
+            "user code killed by exception {} at {:?}",
+            vector,
+            instruction_pointer
+        );
+        usermode::exit_current(Exit::Faulted(vector));
+    }
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::Timer.as_u8());
+    crate::time::tick();
 
     // Inform the PIC that we are done processing this interrupt
     // i.e. we are ready to handle the next one
@@ -99,11 +278,23 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+
+    // Might switch to another thread, which is why
+    // the PIC has to be notified before
+    crate::thread::on_timer_tick();
+
+    // Killed processes stop running user code here at the latest
+    if stack_frame.code_segment & 0b11 != 0 {
+        crate::process::exit_if_killed();
+    }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
     use x86_64::instructions::port::Port;
 
+    stats::record(InterruptIndex::Keyboard.as_u8());
+
     // Data port of the PS/2 controller which we
     // use to query the keyboard controller
     let mut port = Port::new(0x60);
@@ -118,9 +309,101 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+// Reads the in-service register of the PIC with the given command port,
+// i.e. the set of IRQs that the PIC is currently waiting on an EOI for.
+// Takes the locked PICs, as EOIs are written to the same port.
+fn pic_in_service(_pics: &mut ChainedPics, command_port: u16) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    // OCW3 telling the PIC to return the ISR on the next read
+    const READ_ISR: u8 = 0x0b;
+
+    let mut port = Port::new(command_port);
+    unsafe {
+        port.write(READ_ISR);
+        port.read()
+    }
+}
+
+// IRQ7 is delivered both for real interrupts on that line and when
+// an interrupt is retracted before the CPU acknowledges it. A spurious
+// IRQ7 must not be acknowledged since the PIC is not expecting an EOI.
+extern "x86-interrupt" fn pic_spurious_primary_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::PicSpuriousPrimary.as_u8());
+
+    let mut pics = PICS.lock();
+    if pic_in_service(&mut pics, PIC_1_COMMAND) & (1 << 7) == 0 {
+        stats::record_spurious(SpuriousSource::PicPrimary);
+        return;
+    }
+
+    // Nothing is attached to IRQ7, but a real interrupt
+    // still has to be acknowledged
+    unsafe {
+        pics.notify_end_of_interrupt(InterruptIndex::PicSpuriousPrimary.as_u8());
+    }
+}
+
+// Same as above for IRQ15. The secondary PIC does not expect an EOI,
+// but the primary PIC saw a legitimate interrupt on its cascade line
+// (IRQ2) and still has to be acknowledged.
+extern "x86-interrupt" fn pic_spurious_secondary_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::PicSpuriousSecondary.as_u8());
+
+    let mut pics = PICS.lock();
+    let eoi_vector = if pic_in_service(&mut pics, PIC_2_COMMAND) & (1 << 7) == 0 {
+        stats::record_spurious(SpuriousSource::PicSecondary);
+        // Any vector of the primary PIC only acknowledges the primary PIC
+        PIC_1_OFFSET + 2
+    } else {
+        InterruptIndex::PicSpuriousSecondary.as_u8()
+    };
+
+    unsafe {
+        pics.notify_end_of_interrupt(eoi_vector);
+    }
+}
+
+// Only wakes up the CPU, e.g. for its executor to look for new tasks
+extern "x86-interrupt" fn apic_timer_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::ApicTimer.as_u8());
+    crate::apic::end_of_interrupt();
+}
+
+// Getting the CPU out of `hlt` is all it takes
+extern "x86-interrupt" fn wakeup_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::Wakeup.as_u8());
+    crate::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn call_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::Call.as_u8());
+    crate::smp::handle_requests();
+    crate::apic::end_of_interrupt();
+}
+
+// The Local APIC never expects an EOI for its spurious vector
+extern "x86-interrupt" fn apic_spurious_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::ApicSpurious.as_u8());
+    stats::record_spurious(SpuriousSource::Apic);
+}
+
 // If execution continues, we verify that the breakpoint
 // handler is working correctly.
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = stats::count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(stats::count(3), before + 1);
+}
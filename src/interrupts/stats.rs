@@ -0,0 +1,105 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// One counter for every entry of the IDT
+const VECTOR_COUNT: usize = 256;
+
+// Interior-mutable consts may not be used to initialise statics by
+// reference, but they can be used as array repeat expressions.
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+// Number of times each vector was delivered, including
+// deliveries that turned out to be spurious
+static VECTOR_COUNTS: [AtomicU64; VECTOR_COUNT] = [ZERO; VECTOR_COUNT];
+
+static SPURIOUS_PIC_PRIMARY: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_PIC_SECONDARY: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_APIC: AtomicU64 = AtomicU64::new(0);
+
+// Where a spurious interrupt came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpuriousSource {
+    // IRQ7 without the corresponding bit set in the primary PIC's ISR
+    PicPrimary,
+    // IRQ15 without the corresponding bit set in the secondary PIC's ISR
+    PicSecondary,
+    // The Local APIC's spurious interrupt vector
+    Apic,
+}
+
+// Called at the start of every interrupt handler. This only touches
+// atomics, so it is safe to call from any interrupt context.
+pub(crate) fn record(vector: u8) {
+    VECTOR_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_spurious(source: SpuriousSource) {
+    let counter = match source {
+        SpuriousSource::PicPrimary => &SPURIOUS_PIC_PRIMARY,
+        SpuriousSource::PicSecondary => &SPURIOUS_PIC_SECONDARY,
+        SpuriousSource::Apic => &SPURIOUS_APIC,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+// Number of times `vector` was delivered since boot
+pub fn count(vector: u8) -> u64 {
+    VECTOR_COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+// Number of spurious interrupts observed from `source` since boot
+pub fn spurious_count(source: SpuriousSource) -> u64 {
+    match source {
+        SpuriousSource::PicPrimary => SPURIOUS_PIC_PRIMARY.load(Ordering::Relaxed),
+        SpuriousSource::PicSecondary => SPURIOUS_PIC_SECONDARY.load(Ordering::Relaxed),
+        SpuriousSource::Apic => SPURIOUS_APIC.load(Ordering::Relaxed),
+    }
+}
+
+// A copy of all counters taken at one point in time
+//
+// The counters are read one after the other while interrupts keep
+// firing, so the snapshot is not guaranteed to be globally consistent,
+// but every individual value is exact.
+#[derive(Clone)]
+pub struct InterruptStats {
+    pub counts: [u64; VECTOR_COUNT],
+    pub spurious_pic_primary: u64,
+    pub spurious_pic_secondary: u64,
+    pub spurious_apic: u64,
+}
+
+impl InterruptStats {
+    // Total number of interrupts and exceptions delivered
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // Total number of deliveries that were found to be spurious
+    pub fn total_spurious(&self) -> u64 {
+        self.spurious_pic_primary + self.spurious_pic_secondary + self.spurious_apic
+    }
+
+    // Iterates over `(vector, count)` for every vector that fired at least once
+    pub fn active_vectors(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(vector, &count)| (vector as u8, count))
+    }
+}
+
+pub fn snapshot() -> InterruptStats {
+    let mut counts = [0; VECTOR_COUNT];
+    for (count, counter) in counts.iter_mut().zip(VECTOR_COUNTS.iter()) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+
+    InterruptStats {
+        counts,
+        spurious_pic_primary: spurious_count(SpuriousSource::PicPrimary),
+        spurious_pic_secondary: spurious_count(SpuriousSource::PicSecondary),
+        spurious_apic: spurious_count(SpuriousSource::Apic),
+    }
+}
@@ -0,0 +1,139 @@
+// Entry points for exceptions whose handlers need access to the complete
+// register state of the interrupted code (e.g. to let a debugger inspect
+// and modify it), which `extern "x86-interrupt"` functions do not provide.
+//
+// The assembly stubs push all general purpose registers on top of the
+// interrupt stack frame pushed by the CPU, call `handle_trap` with a
+// pointer to the result and restore the (possibly modified) registers
+// before returning with `iretq`.
+
+use super::stats;
+use crate::percpu::KernelGs;
+use crate::println;
+use x86_64::VirtAddr;
+
+pub const DEBUG_VECTOR: u64 = 1;
+pub const BREAKPOINT_VECTOR: u64 = 3;
+
+// Register state of the interrupted code, in the order the stubs push it
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    // Zero for exceptions that do not push an error code
+    pub error_code: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// The CPU aligns the stack to 16 bytes before pushing its 5 words. Together
+// with the error code, the vector and the 15 registers, that keeps the
+// stack aligned for the call into Rust.
+core::arch::global_asm!(
+    r#"
+.global trap_debug_entry
+trap_debug_entry:
+    push 0
+    push 1
+    jmp trap_common
+
+.global trap_breakpoint_entry
+trap_breakpoint_entry:
+    push 0
+    push 3
+    jmp trap_common
+
+trap_common:
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+    mov rdi, rsp
+    cld
+    call handle_trap
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    // Skip the vector and error code
+    add rsp, 16
+    iretq
+"#
+);
+
+extern "C" {
+    fn trap_debug_entry();
+    fn trap_breakpoint_entry();
+}
+
+pub(super) fn debug_entry() -> VirtAddr {
+    VirtAddr::new(trap_debug_entry as *const () as u64)
+}
+
+pub(super) fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::new(trap_breakpoint_entry as *const () as u64)
+}
+
+#[no_mangle]
+extern "C" fn handle_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter_from(frame.cs);
+    stats::record(frame.vector as u8);
+    // The debugger is for the kernel. User code can set the trap flag
+    // itself, which only ends it.
+    super::kill_user_code_at(frame.cs, VirtAddr::new(frame.rip), frame.vector as u8);
+
+    // Let an attached debugger handle the exception first
+    if crate::gdb::handle_trap(frame) {
+        return;
+    }
+
+    match frame.vector {
+        // Breakpoint handler is invoked when the `int3` instruction is executed
+        BREAKPOINT_VECTOR => println!("EXCEPTION: BREAKPOINT\n{:#?}", frame),
+        // Raised after every instruction while the trap flag is set,
+        // which nothing but the debugger does
+        DEBUG_VECTOR => println!("EXCEPTION: DEBUG\n{:#?}", frame),
+        vector => panic!("unexpected trap vector {}", vector),
+    }
+}
@@ -12,12 +12,30 @@ use core::panic::PanicInfo;
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod elf;
+pub mod fs;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
+pub mod lock;
+pub mod log;
 pub mod memory;
+pub mod percpu;
+pub mod power;
+pub mod process;
 pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod symbols;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod usermode;
 pub mod vga_buffer;
 
 extern crate alloc;
@@ -49,6 +67,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    backtrace::print_backtrace();
     exit_qemu(QemuExitCode::Failed);
 
     hlt_loop();
@@ -96,10 +115,13 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 }
 
 pub fn init() {
+    percpu::init(0);
     gdt::init();
+    usermode::init();
     interrupts::init_idt();
     // Set up hardware interrupt controllers
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
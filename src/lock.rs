@@ -0,0 +1,43 @@
+// Spinlocks for kernel data that is shared with interrupt handlers and
+// other CPUs
+//
+// All locks disable interrupts while they are held and restore the
+// previous state when released, so an interrupt handler can never spin
+// on a lock held by the code it interrupted.
+//
+// - `SpinLock` is the plain variant.
+// - `TicketLock` hands the lock out in the order it was asked for, so
+//   that no CPU waits forever while others keep taking it.
+// - `RwSpinLock` admits many readers or a single writer. Waiting writers
+//   keep new readers out, so readers cannot starve them.
+//
+// With debug assertions enabled, locking a lock that the same CPU holds
+// already panics instead of spinning forever, and locks that are held for
+// longer than `LONG_HOLD_CYCLES` are reported with a warning.
+
+mod debug;
+mod rwlock;
+mod spinlock;
+mod ticket;
+
+pub use debug::LONG_HOLD_CYCLES;
+pub use rwlock::{RwSpinLock, RwSpinLockReadGuard, RwSpinLockWriteGuard};
+pub use spinlock::{SpinLock, SpinLockGuard};
+pub use ticket::{TicketLock, TicketLockGuard};
+
+use x86_64::instructions::interrupts;
+
+// Disables interrupts and returns whether they were enabled before
+fn disable_interrupts() -> bool {
+    let enabled = interrupts::are_enabled();
+    if enabled {
+        interrupts::disable();
+    }
+    enabled
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        interrupts::enable();
+    }
+}
@@ -0,0 +1,126 @@
+// Lock checks that are only done with debug assertions enabled
+//
+// Each lock tracks the CPU that holds it, where it was taken and since
+// when. Without debug assertions, `Tracker` is empty and does nothing.
+
+// Holding a lock for longer than this many cycles gets reported
+pub const LONG_HOLD_CYCLES: u64 = 100_000_000;
+
+#[cfg(debug_assertions)]
+mod imp {
+    use super::LONG_HOLD_CYCLES;
+    use crate::{per_cpu, percpu, time, warn};
+    use core::panic::Location;
+    use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+    const NO_CPU: usize = usize::MAX;
+
+    per_cpu! {
+        // Number of tracked locks the CPU holds
+        static HELD: AtomicUsize = AtomicUsize::new(0);
+    }
+
+    pub struct Tracker {
+        owner: AtomicUsize,
+        location: AtomicPtr<Location<'static>>,
+        since: AtomicU64,
+    }
+
+    // A lock that was held for too long, reported once no other
+    // lock is held anymore, since reporting takes locks itself
+    pub struct LongHold {
+        location: &'static Location<'static>,
+        cycles: u64,
+    }
+
+    impl Tracker {
+        pub const fn new() -> Self {
+            Tracker {
+                owner: AtomicUsize::new(NO_CPU),
+                location: AtomicPtr::new(core::ptr::null_mut()),
+                since: AtomicU64::new(0),
+            }
+        }
+
+        // Called with interrupts disabled before waiting for the lock
+        pub fn check_recursion(&self, location: &'static Location<'static>) {
+            if self.owner.load(Ordering::Relaxed) == percpu::cpu_id() {
+                let held_at = self.location.load(Ordering::Relaxed);
+                // Only the owning CPU writes the location, so it is valid
+                let held_at = unsafe { &*held_at };
+                panic!(
+                    "recursive locking at {}, lock already held on this CPU since {}",
+                    location, held_at
+                );
+            }
+        }
+
+        pub fn acquired(&self, location: &'static Location<'static>) {
+            self.owner.store(percpu::cpu_id(), Ordering::Relaxed);
+            self.location
+                .store(location as *const _ as *mut _, Ordering::Relaxed);
+            self.since.store(time::cycles(), Ordering::Relaxed);
+            HELD.get().fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Called right before the lock is released
+        pub fn releasing(&self) -> Option<LongHold> {
+            let cycles = time::cycles().wrapping_sub(self.since.load(Ordering::Relaxed));
+            let location = unsafe { &*self.location.load(Ordering::Relaxed) };
+            self.owner.store(NO_CPU, Ordering::Relaxed);
+            HELD.get().fetch_sub(1, Ordering::Relaxed);
+
+            if cycles > LONG_HOLD_CYCLES {
+                Some(LongHold { location, cycles })
+            } else {
+                None
+            }
+        }
+    }
+
+    impl LongHold {
+        // Called after the lock was released
+        pub fn report(self) {
+            if HELD.get().load(Ordering::Relaxed) == 0 {
+                warn!(
+                    "lock taken at {} was held for {} cycles",
+                    self.location, self.cycles
+                );
+            }
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod imp {
+    use core::panic::Location;
+
+    pub struct Tracker;
+
+    pub enum LongHold {}
+
+    impl Tracker {
+        pub const fn new() -> Self {
+            Tracker
+        }
+
+        #[inline]
+        pub fn check_recursion(&self, _location: &'static Location<'static>) {}
+
+        #[inline]
+        pub fn acquired(&self, _location: &'static Location<'static>) {}
+
+        #[inline]
+        pub fn releasing(&self) -> Option<LongHold> {
+            None
+        }
+    }
+
+    impl LongHold {
+        pub fn report(self) {
+            match self {}
+        }
+    }
+}
+
+pub(super) use imp::Tracker;
@@ -0,0 +1,167 @@
+use super::debug::Tracker;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Lowest bit: a writer holds the lock. Second bit: at least one writer
+// waits for it. The remaining bits count the readers.
+const WRITER: usize = 1 << 0;
+const WRITER_WAITING: usize = 1 << 1;
+const READER: usize = 1 << 2;
+
+// Only writers are tracked by the debug checks. A CPU that takes a
+// read lock twice might still deadlock if a writer comes in between.
+pub struct RwSpinLock<T: ?Sized> {
+    state: AtomicUsize,
+    tracker: Tracker,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinLock<T> {}
+
+pub struct RwSpinLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    interrupts_enabled: bool,
+}
+
+pub struct RwSpinLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    interrupts_enabled: bool,
+}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwSpinLock {
+            state: AtomicUsize::new(0),
+            tracker: Tracker::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwSpinLock<T> {
+    #[track_caller]
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        let interrupts_enabled = super::disable_interrupts();
+        // Would wait for this CPU's own write lock
+        self.tracker.check_recursion(Location::caller());
+
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & (WRITER | WRITER_WAITING) == 0
+                && self
+                    .state
+                    .compare_exchange_weak(
+                        state,
+                        state + READER,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+            {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        RwSpinLockReadGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        let location = Location::caller();
+        let interrupts_enabled = super::disable_interrupts();
+        self.tracker.check_recursion(location);
+
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & !WRITER_WAITING == 0 {
+                // Also clears the waiting bit. Other waiting writers set it
+                // again, before any reader can get in.
+                if self
+                    .state
+                    .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break;
+                }
+            } else if state & WRITER_WAITING == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            core::hint::spin_loop();
+        }
+
+        self.tracker.acquired(location);
+        RwSpinLockWriteGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+        super::restore_interrupts(self.interrupts_enabled);
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwSpinLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let long_hold = self.lock.tracker.releasing();
+        // Keeps the waiting bit of other writers
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+        super::restore_interrupts(self.interrupts_enabled);
+
+        if let Some(long_hold) = long_hold {
+            long_hold.report();
+        }
+    }
+}
+
+#[test_case]
+fn test_readers_share_the_lock() {
+    let lock = RwSpinLock::new(1);
+    {
+        let first = lock.read();
+        let second = lock.read();
+        assert_eq!(*first + *second, 2);
+    }
+    *lock.write() += 1;
+    assert_eq!(*lock.read(), 2);
+}
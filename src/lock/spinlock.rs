@@ -0,0 +1,131 @@
+use super::debug::Tracker;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    tracker: Tracker,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    interrupts_enabled: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            tracker: Tracker::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let location = Location::caller();
+        let interrupts_enabled = super::disable_interrupts();
+        self.tracker.check_recursion(location);
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Only read while waiting, to keep the cache line shared
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+
+        self.tracker.acquired(location);
+        SpinLockGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let location = Location::caller();
+        let interrupts_enabled = super::disable_interrupts();
+
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.tracker.acquired(location);
+            Some(SpinLockGuard {
+                lock: self,
+                interrupts_enabled,
+            })
+        } else {
+            super::restore_interrupts(interrupts_enabled);
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    // No locking needed, the mutable borrow guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let long_hold = self.lock.tracker.releasing();
+        self.lock.locked.store(false, Ordering::Release);
+        super::restore_interrupts(self.interrupts_enabled);
+
+        if let Some(long_hold) = long_hold {
+            long_hold.report();
+        }
+    }
+}
+
+#[test_case]
+fn test_lock_disables_interrupts() {
+    use x86_64::instructions::interrupts;
+
+    let lock = SpinLock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
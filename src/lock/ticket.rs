@@ -0,0 +1,135 @@
+use super::debug::Tracker;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Whoever wants the lock draws the next ticket and waits until its
+// number is served. The counters wrap around, which is fine as long as
+// fewer than 2^32 CPUs wait at once.
+pub struct TicketLock<T: ?Sized> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    tracker: Tracker,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+
+pub struct TicketLockGuard<'a, T: ?Sized> {
+    lock: &'a TicketLock<T>,
+    interrupts_enabled: bool,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(value: T) -> Self {
+        TicketLock {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            tracker: Tracker::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let location = Location::caller();
+        let interrupts_enabled = super::disable_interrupts();
+        self.tracker.check_recursion(location);
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+
+        self.tracker.acquired(location);
+        TicketLockGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    // Only succeeds if nobody holds or waits for the lock
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let location = Location::caller();
+        let interrupts_enabled = super::disable_interrupts();
+
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        if self
+            .next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            self.tracker.acquired(location);
+            Some(TicketLockGuard {
+                lock: self,
+                interrupts_enabled,
+            })
+        } else {
+            super::restore_interrupts(interrupts_enabled);
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let long_hold = self.lock.tracker.releasing();
+        // Only the holder changes it, so nothing can get in between
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+        super::restore_interrupts(self.interrupts_enabled);
+
+        if let Some(long_hold) = long_hold {
+            long_hold.report();
+        }
+    }
+}
+
+#[test_case]
+fn test_ticket_lock() {
+    let lock = TicketLock::new(0);
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(!lock.is_locked());
+    *lock.try_lock().unwrap() += 1;
+    assert_eq!(*lock.lock(), 2);
+}
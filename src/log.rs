@@ -0,0 +1,287 @@
+// A small logging facade in the spirit of the `log` crate.
+//
+// Messages are logged with one of the `error!`, `warn!`, `info!`, `debug!`
+// and `trace!` macros, tagged with the module they were logged from and the
+// time since boot, and dispatched to every registered sink whose level
+// allows it. Dispatching happens with interrupts disabled and without
+// allocating, so logging is safe from interrupt handlers.
+
+use crate::lock::SpinLock;
+use crate::{serial, time, vga_buffer};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub mod dmesg;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Pass the formatter through so that padding works
+        f.pad(self.as_str())
+    }
+}
+
+pub struct Record<'a> {
+    pub level: Level,
+    // Module path of the code that logged the message
+    pub target: &'a str,
+    pub uptime_ms: u64,
+    pub args: fmt::Arguments<'a>,
+}
+
+// Formats a record the same way for every sink, e.g.
+// `[    1.042] WARN  rust_os::task::keyboard: scancode queue full`
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:03}] {:<5} {}: {}",
+            self.uptime_ms / 1000,
+            self.uptime_ms % 1000,
+            self.level,
+            self.target,
+            self.args
+        )
+    }
+}
+
+// Destination for log records
+//
+// Sinks are called with interrupts disabled and must not allocate,
+// since the record might have been logged from an interrupt handler.
+pub trait Sink: Sync {
+    fn log(&self, record: &Record);
+}
+
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn log(&self, record: &Record) {
+        // Records reach the kernel log through its own sink
+        vga_buffer::_print_screen(format_args!("{}\n", record));
+    }
+}
+
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn log(&self, record: &Record) {
+        serial::_print(format_args!("{}\n", record));
+    }
+}
+
+pub static VGA: VgaSink = VgaSink;
+pub static SERIAL: SerialSink = SerialSink;
+
+const MAX_SINKS: usize = 8;
+
+struct Registration {
+    sink: &'static dyn Sink,
+    level: Level,
+}
+
+// Registered sinks. Until `init` is called, everything from
+// info upwards goes to the screen and the kernel log.
+static SINKS: SpinLock<[Option<Registration>; MAX_SINKS]> = SpinLock::new([
+    Some(Registration {
+        sink: &VGA,
+        level: Level::Info,
+    }),
+    Some(Registration {
+        sink: &dmesg::DMESG,
+        level: Level::Info,
+    }),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+]);
+
+// Most verbose level any sink accepts, so that disabled
+// messages can be skipped without taking the lock
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+// Per-module overrides, e.g. `("rust_os::task", Some(Level::Trace))`.
+// The longest matching prefix of a record's target wins.
+static FILTERS: SpinLock<&'static [(&'static str, Option<Level>)]> = SpinLock::new(&[]);
+
+// Boot-time logging configuration. `None` disables a sink.
+pub struct Config {
+    pub vga: Option<Level>,
+    pub serial: Option<Level>,
+    pub dmesg: Option<Level>,
+    pub filters: &'static [(&'static str, Option<Level>)],
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            vga: Some(Level::Info),
+            serial: Some(Level::Debug),
+            dmesg: Some(Level::Trace),
+            filters: &[],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManySinks;
+
+// Replaces the registered sinks with the built-in ones as configured
+pub fn init(config: Config) {
+    let mut sinks = SINKS.lock();
+    for slot in sinks.iter_mut() {
+        *slot = None;
+    }
+
+    let builtin: [(&'static dyn Sink, Option<Level>); 3] = [
+        (&VGA, config.vga),
+        (&SERIAL, config.serial),
+        (&dmesg::DMESG, config.dmesg),
+    ];
+    let enabled = builtin.iter().filter_map(|&(sink, level)| {
+        Some(Registration {
+            sink,
+            level: level?,
+        })
+    });
+    for (slot, registration) in sinks.iter_mut().zip(enabled) {
+        *slot = Some(registration);
+    }
+
+    *FILTERS.lock() = config.filters;
+    update_max_level(&sinks, config.filters);
+}
+
+// Adds another sink receiving records up to `level`
+pub fn register_sink(sink: &'static dyn Sink, level: Level) -> Result<(), TooManySinks> {
+    let mut sinks = SINKS.lock();
+    let slot = sinks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(TooManySinks)?;
+    *slot = Some(Registration { sink, level });
+    update_max_level(&sinks, *FILTERS.lock());
+    Ok(())
+}
+
+fn update_max_level(
+    sinks: &[Option<Registration>; MAX_SINKS],
+    filters: &[(&'static str, Option<Level>)],
+) {
+    let sink_max = sinks.iter().flatten().map(|r| r.level).max();
+    let filter_max = filters.iter().filter_map(|&(_, level)| level).max();
+    let max = sink_max.max(filter_max).map_or(0, |level| level as u8);
+    MAX_LEVEL.store(max, Ordering::Relaxed);
+}
+
+// Returns whether a message with the given level and target is logged at all
+pub fn enabled(level: Level, target: &str) -> bool {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    let filters = *FILTERS.lock();
+    let filter = filters
+        .iter()
+        .filter(|(prefix, _)| target.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len());
+    match filter {
+        Some(&(_, max)) => max.map_or(false, |max| level <= max),
+        None => true,
+    }
+}
+
+// The logging macros need to be able to call this from outside the
+// module, but it is an implementation detail.
+#[doc(hidden)]
+pub fn _log(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+
+    let record = Record {
+        level,
+        target,
+        uptime_ms: time::uptime_ms(),
+        args,
+    };
+    // The lock keeps interrupts disabled while the sinks run, so
+    // that we can safely log from interrupt handlers
+    for registration in SINKS.lock().iter().flatten() {
+        if level <= registration.level {
+            registration.sink.log(&record);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => (
+        $crate::log::_log($level, module_path!(), format_args!($($arg)+))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
+
+#[test_case]
+fn test_level_ordering() {
+    assert!(Level::Error < Level::Warn);
+    assert!(Level::Debug < Level::Trace);
+}
+
+// Ensure that logging at every level does not panic
+#[test_case]
+fn test_log_macros() {
+    error!("test_log_macros error");
+    warn!("test_log_macros warn");
+    info!("test_log_macros info");
+    debug!("test_log_macros debug");
+    trace!("test_log_macros trace");
+}
@@ -0,0 +1,354 @@
+// The kernel log: a fixed-size, lock-free ring buffer keeping the most
+// recent log records, so that they can still be read after they scrolled
+// off the screen (`dmesg` in the shell) or replayed after a panic.
+//
+// Every record gets a sequence number. Record `seq` lives in slot
+// `seq % SLOTS` and is overwritten by record `seq + SLOTS`. Each slot is
+// protected by a sequence lock: its state is odd while a writer fills it
+// and `2 * (seq + 1)` once record `seq` is complete. Readers copy a slot
+// and then check that the state did not change in the meantime.
+//
+// Console output from `print!` and `println!` is recorded as well, one
+// record per line at the info level with `console` as the target.
+
+use super::{Level, Record, Sink};
+use crate::lock::SpinLock;
+use crate::time;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+const SLOTS: usize = 256;
+// Longer messages are truncated
+pub const MESSAGE_LEN: usize = 120;
+
+struct Slot {
+    state: AtomicU64,
+    level: AtomicU8,
+    uptime_ms: AtomicU64,
+    len: UnsafeCell<usize>,
+    text: UnsafeCell<[u8; MESSAGE_LEN]>,
+}
+
+// The non-atomic fields are only accessed under the slot's sequence lock
+unsafe impl Sync for Slot {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    state: AtomicU64::new(0),
+    level: AtomicU8::new(0),
+    uptime_ms: AtomicU64::new(0),
+    len: UnsafeCell::new(0),
+    text: UnsafeCell::new([0; MESSAGE_LEN]),
+};
+
+static SLOT_TABLE: [Slot; SLOTS] = [EMPTY_SLOT; SLOTS];
+// Sequence number of the next record
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+// Records dropped because their slot was still being written or already
+// held a newer record, which can only happen if a whole buffer's worth of
+// records is logged while a single record is being written
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+fn published(seq: u64) -> u64 {
+    2 * (seq + 1)
+}
+
+impl Slot {
+    // Starts writing record `seq`. Fails if another writer that we
+    // interrupted is still filling the slot, or if a newer record is in
+    // it already because we were delayed for a whole round of the buffer.
+    fn claim(&self, seq: u64) -> bool {
+        let writing = published(seq) - 1;
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state % 2 == 1 || state >= writing {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                writing,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => state = current,
+            }
+        }
+    }
+}
+
+// The longest prefix of `text` that is valid UTF-8, as truncation might
+// have split a multi-byte character
+fn valid_prefix(text: &[u8]) -> &str {
+    match core::str::from_utf8(text) {
+        Ok(text) => text,
+        Err(error) => unsafe { core::str::from_utf8_unchecked(&text[..error.valid_up_to()]) },
+    }
+}
+
+#[derive(Clone)]
+pub struct Entry {
+    pub seq: u64,
+    pub uptime_ms: u64,
+    pub level: Level,
+    len: usize,
+    text: [u8; MESSAGE_LEN],
+}
+
+impl Entry {
+    // Message including the target, e.g. `rust_os::task::keyboard: ...`
+    pub fn text(&self) -> &str {
+        valid_prefix(&self.text[..self.len])
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:03}] {:<5} {}",
+            self.uptime_ms / 1000,
+            self.uptime_ms % 1000,
+            self.level,
+            self.text()
+        )
+    }
+}
+
+// Writes into a fixed buffer, silently dropping whatever does not fit
+struct TruncatingWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+pub struct DmesgSink;
+
+pub static DMESG: DmesgSink = DmesgSink;
+
+impl Sink for DmesgSink {
+    fn log(&self, record: &Record) {
+        let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+        let slot = &SLOT_TABLE[seq as usize % SLOTS];
+
+        if !slot.claim(seq) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        slot.level.store(record.level as u8, Ordering::Relaxed);
+        slot.uptime_ms.store(record.uptime_ms, Ordering::Relaxed);
+        let mut writer = TruncatingWriter {
+            buffer: unsafe { &mut *slot.text.get() },
+            len: 0,
+        };
+        let _ = write!(writer, "{}: {}", record.target, record.args);
+        unsafe { *slot.len.get() = writer.len };
+
+        slot.state.store(published(seq), Ordering::Release);
+    }
+}
+
+// The console line being printed, recorded once it is complete
+struct ConsoleLine {
+    len: usize,
+    text: [u8; MESSAGE_LEN],
+}
+
+impl ConsoleLine {
+    fn flush(&mut self) {
+        DMESG.log(&Record {
+            level: Level::Info,
+            target: "console",
+            uptime_ms: time::uptime_ms(),
+            args: format_args!("{}", valid_prefix(&self.text[..self.len])),
+        });
+        self.len = 0;
+    }
+}
+
+impl Write for ConsoleLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            match byte {
+                b'\n' => self.flush(),
+                // The shell echoes backspace to erase typed characters
+                0x08 => self.len = self.len.saturating_sub(1),
+                // Long lines are truncated like any other record
+                byte if self.len < MESSAGE_LEN => {
+                    self.text[self.len] = byte;
+                    self.len += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+static CONSOLE_LINE: SpinLock<ConsoleLine> = SpinLock::new(ConsoleLine {
+    len: 0,
+    text: [0; MESSAGE_LEN],
+});
+
+// Records console output
+pub fn record_console(args: fmt::Arguments) {
+    let _ = CONSOLE_LINE.lock().write_fmt(args);
+}
+
+fn level_from_u8(level: u8) -> Level {
+    match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+// Returns record `seq` if it is still in the buffer
+pub fn read(seq: u64) -> Option<Entry> {
+    let slot = &SLOT_TABLE[seq as usize % SLOTS];
+
+    if slot.state.load(Ordering::Acquire) != published(seq) {
+        return None;
+    }
+
+    let mut entry = Entry {
+        seq,
+        uptime_ms: slot.uptime_ms.load(Ordering::Relaxed),
+        level: level_from_u8(slot.level.load(Ordering::Relaxed)),
+        len: 0,
+        text: [0; MESSAGE_LEN],
+    };
+    unsafe {
+        entry.len = core::ptr::read_volatile(slot.len.get()).min(MESSAGE_LEN);
+        entry.text = core::ptr::read_volatile(slot.text.get());
+    }
+
+    // If a writer claimed the slot while we were copying, the copy is torn
+    core::sync::atomic::fence(Ordering::Acquire);
+    if slot.state.load(Ordering::Relaxed) != published(seq) {
+        return None;
+    }
+    Some(entry)
+}
+
+// Sequence number the next record will get
+pub fn next_seq() -> u64 {
+    NEXT_SEQ.load(Ordering::Relaxed)
+}
+
+// Sequence number of the oldest record that may still be in the buffer
+pub fn first_seq() -> u64 {
+    next_seq().saturating_sub(SLOTS as u64)
+}
+
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+// Calls `f` for every record in the buffer, oldest first. Records that
+// are overwritten while iterating are skipped.
+pub fn for_each(mut f: impl FnMut(&Entry)) {
+    let end = next_seq();
+    for seq in end.saturating_sub(SLOTS as u64)..end {
+        if let Some(entry) = read(seq) {
+            f(&entry);
+        }
+    }
+}
+
+// Writes the whole kernel log to the serial interface
+pub fn dump_serial() {
+    crate::serial_println!("--- kernel log (oldest first) ---");
+    for_each(|entry| {
+        crate::serial_println!("{:>6} {}", entry.seq, entry);
+    });
+    crate::serial_println!("--- end of kernel log ---");
+}
+
+// Ensure that logged messages end up in the buffer
+#[test_case]
+fn test_records_are_stored() {
+    use x86_64::instructions::interrupts;
+
+    // Interrupt handlers might log in between and take our sequence number
+    let seq = interrupts::without_interrupts(|| {
+        let seq = next_seq();
+        DMESG.log(&Record {
+            level: Level::Warn,
+            target: "test",
+            uptime_ms: 1234,
+            args: format_args!("test_records_are_stored {}", 42),
+        });
+        seq
+    });
+
+    let entry = read(seq).expect("record missing");
+    assert_eq!(entry.level, Level::Warn);
+    assert_eq!(entry.uptime_ms, 1234);
+    assert_eq!(entry.text(), "test: test_records_are_stored 42");
+}
+
+// Ensure that long messages are truncated instead of overflowing
+#[test_case]
+fn test_long_records_are_truncated() {
+    use x86_64::instructions::interrupts;
+
+    let long = [b'x'; 2 * MESSAGE_LEN];
+    let seq = interrupts::without_interrupts(|| {
+        let seq = next_seq();
+        DMESG.log(&Record {
+            level: Level::Info,
+            target: "test",
+            uptime_ms: 0,
+            args: format_args!("{}", core::str::from_utf8(&long).unwrap()),
+        });
+        seq
+    });
+
+    assert_eq!(read(seq).expect("record missing").text().len(), MESSAGE_LEN);
+}
+
+// Ensure that console output ends up in the buffer line by line
+#[test_case]
+fn test_console_output_is_stored() {
+    use x86_64::instructions::interrupts;
+
+    let seq = interrupts::without_interrupts(|| {
+        // Complete whatever line an interrupt handler may have started
+        crate::println!();
+        let seq = next_seq();
+        crate::print!("test_console_");
+        crate::println!("output_is_stored {}", 42);
+        seq
+    });
+
+    let entry = read(seq).expect("record missing");
+    assert_eq!(entry.level, Level::Info);
+    assert_eq!(entry.text(), "console: test_console_output_is_stored 42");
+}
+
+#[test_case]
+fn test_newer_records_are_not_overwritten() {
+    let slot = EMPTY_SLOT;
+    assert!(slot.claim(3));
+    // Still being written
+    assert!(!slot.claim(3 + SLOTS as u64));
+    let newer = published(3 + SLOTS as u64);
+    slot.state.store(newer, Ordering::Release);
+    // A writer of the older record that was delayed
+    assert!(!slot.claim(3));
+    assert_eq!(slot.state.load(Ordering::Relaxed), newer);
+    assert!(slot.claim(3 + 2 * SLOTS as u64));
+}
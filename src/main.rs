@@ -13,17 +13,22 @@ extern crate alloc;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use rust_os::println;
-use rust_os::task::keyboard;
-use rust_os::task::{executor::Executor, Task};
+use rust_os::shell;
+use rust_os::task::{executor::Executor, SpawnOptions};
 
-// Panic handler should never return, we will
-// let it loop infinitely for now
+// Panic handler should never return. It halts, or reboots or
+// powers off if the shell's `onpanic` command says so.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    rust_os::serial_println!("{}", info);
+    rust_os::backtrace::print_backtrace();
+    // The screen only holds the last few lines, so replay
+    // everything that was logged before the panic
+    rust_os::log::dmesg::dump_serial();
 
-    rust_os::hlt_loop();
+    rust_os::power::on_panic();
 }
 
 #[cfg(test)]
@@ -44,6 +49,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello World{}", "!");
 
     rust_os::init();
+    rust_os::log::init(Default::default());
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
@@ -51,13 +57,24 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    rust_os::power::init();
+    rust_os::smp::init();
+    // From here on, we are the thread "main", which runs the executor
+    rust_os::thread::init();
+
+    #[cfg(feature = "gdb")]
+    {
+        rust_os::gdb::init();
+        rust_os::gdb::breakpoint();
+    }
 
     #[cfg(test)]
     test_main();
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn_with(example_task(), SpawnOptions::named("example"));
+    executor.spawn_with(shell::run(), SpawnOptions::named("shell"));
     executor.run();
 }
 
@@ -1,9 +1,42 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
+// Virtual address at which the bootloader mapped the complete physical
+// memory. Zero until `init` is called.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Returns the offset passed to `init`, if it was called already
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
+
+pub mod address_space;
+pub mod tlb;
+
+pub use address_space::AddressSpace;
+
+// Start of the virtual address range that device memory is mapped to
+pub const MMIO_START: u64 = 0x5555_0000_0000;
+
+// The page table and frame allocator, once `install` handed them over
+static PAGING: spin::Mutex<Option<Paging>> = spin::Mutex::new(None);
+
+struct Paging {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+    // Next free page of the MMIO range
+    next_mmio: u64,
+}
+
 // Returns a mutable reference to the active level 4 page table.
 //
 // This function is unsafe because the caller must ensure that the
@@ -30,10 +63,192 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 // This function must not be called more than once to prevent mutable reference
 // aliasing.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+// Translates the given virtual address to the mapped physical address by
+// walking the active page tables, or returns `None` if it is not mapped.
+//
+// Unlike `OffsetPageTable::translate_addr` this only needs shared access
+// to the page tables, so it can be used from anywhere (e.g. exception
+// handlers) once `init` has been called.
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let physical_memory_offset = physical_memory_offset()?;
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut frame_addr = level_4_table_frame.start_address();
+
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let virt = physical_memory_offset + frame_addr.as_u64();
+        let table: &PageTable = unsafe { &*virt.as_ptr() };
+        let entry = &table[index];
+
+        if !entry.flags().contains(Flags::PRESENT) {
+            return None;
+        }
+
+        // Level 3 and 2 entries may map 1GiB and 2MiB pages directly
+        if entry.flags().contains(Flags::HUGE_PAGE) {
+            let page_size: u64 = match level {
+                1 => 1 << 30,
+                2 => 1 << 21,
+                _ => return None,
+            };
+            return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+        }
+
+        frame_addr = entry.addr();
+    }
+
+    Some(frame_addr + u64::from(addr.page_offset()))
+}
+
+// Hands the page table and frame allocator over to this module, after
+// the heap has been set up with them, so that the rest of the kernel can
+// map memory through `map_mmio` and friends
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut paging = PAGING.lock();
+        assert!(paging.is_none(), "paging already installed");
+        address_space::set_kernel_table(x86_64::registers::control::Cr3::read().0);
+        *paging = Some(Paging {
+            mapper,
+            frame_allocator,
+            next_mmio: MMIO_START,
+        });
+    });
+}
+
+fn with_paging<R>(f: impl FnOnce(&mut Paging) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut paging = PAGING.lock();
+        f(paging.as_mut().expect("paging not installed"))
+    })
+}
+
+// Maps `size` bytes of device memory starting at `phys` to a fresh range
+// of virtual addresses with caching disabled, and returns the virtual
+// address corresponding to `phys`
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+
+    with_paging(|paging| {
+        let start = paging.next_mmio;
+        for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+            let page = Page::containing_address(VirtAddr::new(start + i as u64 * 4096));
+            unsafe {
+                paging
+                    .mapper
+                    .map_to(page, frame, flags, &mut paging.frame_allocator)?
+                    .flush();
+            }
+        }
+        let pages = last_frame - first_frame + 1;
+        paging.next_mmio += pages * 4096;
+        Ok(VirtAddr::new(start) + (phys - first_frame.start_address()))
+    })
+}
+
+/// Maps `frame` to the page with the same address, e.g. for code that
+/// runs while paging is being switched on
+///
+/// # Safety
+///
+/// The caller must make sure that the frame is not in use for anything
+/// else.
+pub unsafe fn identity_map(frame: PhysFrame) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_paging(|paging| {
+        paging
+            .mapper
+            .identity_map(frame, flags, &mut paging.frame_allocator)?
+            .flush();
+        Ok(())
+    })
+}
+
+// Takes an unused frame from the frame allocator
+pub fn allocate_frame() -> Option<PhysFrame> {
+    with_paging(|paging| paging.frame_allocator.allocate_frame())
+}
+
+/// Gives `frame` back to the frame allocator
+///
+/// # Safety
+///
+/// The caller must make sure that the frame is not in use anymore, as
+/// the allocator keeps its free list in the freed frames.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    with_paging(|paging| paging.frame_allocator.deallocate_frame(frame));
+}
+
+/// Maps `page` to `frame`
+///
+/// # Safety
+///
+/// The caller must make sure that the frame is not in use for anything
+/// else.
+pub unsafe fn map(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_paging(|paging| {
+        paging
+            .mapper
+            .map_to(page, frame, flags, &mut paging.frame_allocator)?
+            .flush();
+        Ok(())
+    })
+}
+
+// Removes the mapping of `page` on all CPUs. The frame it was mapped
+// to is not freed.
+pub fn unmap(page: Page) -> Result<PhysFrame, UnmapError> {
+    let frame = with_paging(|paging| {
+        let (frame, flush) = paging.mapper.unmap(page)?;
+        flush.ignore();
+        Ok::<_, UnmapError>(frame)
+    })?;
+    // Only once the lock is released, since other CPUs might be
+    // waiting for it and would not react to the shootdown
+    tlb::shootdown(page);
+    Ok(frame)
+}
+
+/// Changes the flags of the mapping of `page` on all CPUs
+///
+/// # Safety
+///
+/// The caller must make sure that no memory that is in use becomes
+/// inaccessible or writable through the changed flags.
+pub unsafe fn protect(page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    with_paging(|paging| {
+        paging.mapper.update_flags(page, flags)?.ignore();
+        Ok::<_, FlagUpdateError>(())
+    })?;
+    // See `unmap`
+    tlb::shootdown(page);
+    Ok(())
+}
+
 // Maps page to the VGA buffer, i.e. writing to the start of the page would be
 // the same as writing directly to the VGA buffer
 pub fn create_example_mapping(
@@ -64,18 +279,25 @@ pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     // Next frame to return
     next: usize,
+    // Frames that were given back, each holding the address of the next
+    // one in its first 8 bytes (zero for the last one)
+    free_list: Option<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
-    // Create a FrameAllocator from the passed memory map
-    //
-    // This function is unsafe because the caller must guarantee
-    // that the passed memory map is valid, i.e. frames that are
-    // marked as USABLE are indeed really unused.
+    /// Create a FrameAllocator from the passed memory map
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the passed memory map is valid,
+    /// i.e. frames that are marked as USABLE are indeed really unused.
+    /// Freed frames are also linked through the physical memory mapping,
+    /// so `init` of this module has to be called before any is freed.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: None,
         }
     }
 
@@ -94,10 +316,35 @@ impl BootInfoFrameAllocator {
     }
 }
 
+// The first 8 bytes of `frame`, through the mapping of physical memory
+fn free_list_link(frame: PhysFrame) -> *mut u64 {
+    let offset = physical_memory_offset().expect("memory not initialised");
+    (offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            let next = unsafe { free_list_link(frame).read() };
+            self.free_list = match next {
+                0 => None,
+                address => Some(PhysFrame::containing_address(PhysAddr::new(address))),
+            };
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_list
+            .map_or(0, |next| next.start_address().as_u64());
+        free_list_link(frame).write(next);
+        self.free_list = Some(frame);
+    }
+}
@@ -0,0 +1,295 @@
+// Address spaces for user programs
+//
+// Each address space has a level 4 page table of its own. The entries
+// covering the user range point to tables private to the space, all other
+// entries are copied from the kernel's level 4 table, so the kernel is
+// mapped the same way in every address space. Since the kernel may add
+// level 4 entries later (e.g. for device memory), they are copied again
+// whenever an address space is activated.
+//
+// Dropping an address space frees the frames mapped in its user range,
+// along with the page tables of that range.
+
+use super::{physical_memory_offset, with_paging, BootInfoFrameAllocator};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, Translate, TranslateResult, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// The range of user addresses, covering level 4 entries 16 to 63 which
+// the kernel does not use
+pub const USER_START: u64 = 0x0000_0800_0000_0000;
+pub const USER_END: u64 = 0x0000_2000_0000_0000;
+
+const USER_ENTRIES: core::ops::Range<usize> = 16..64;
+
+// Level 4 table of the kernel, recorded by `super::install`
+static KERNEL_TABLE: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn set_kernel_table(frame: PhysFrame) {
+    KERNEL_TABLE.store(frame.start_address().as_u64(), Ordering::Relaxed);
+}
+
+// `None` until paging is installed
+fn kernel_table() -> Option<PhysFrame> {
+    match KERNEL_TABLE.load(Ordering::Relaxed) {
+        0 => None,
+        address => Some(PhysFrame::containing_address(PhysAddr::new(address))),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unmapped(pub VirtAddr);
+
+pub struct AddressSpace {
+    table: PhysFrame,
+}
+
+// The page table in `frame`, through the mapping of physical memory
+//
+// This function is unsafe because the caller must make sure that the
+// frame holds a page table and that it is not aliased.
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let offset = physical_memory_offset().expect("memory not initialised");
+    &mut *(offset + frame.start_address().as_u64()).as_mut_ptr::<PageTable>()
+}
+
+fn is_user(page: Page) -> bool {
+    (USER_START..USER_END).contains(&page.start_address().as_u64())
+}
+
+// Copies the kernel's entries into the level 4 table in `frame`
+fn copy_kernel_entries(frame: PhysFrame) {
+    let kernel = unsafe { table_at(kernel_table().expect("paging not installed")) };
+    let table = unsafe { table_at(frame) };
+    for (index, entry) in kernel.iter().enumerate() {
+        if USER_ENTRIES.contains(&index) {
+            assert!(entry.is_unused(), "kernel mapping in the user range");
+        } else {
+            table[index] = entry.clone();
+        }
+    }
+}
+
+impl AddressSpace {
+    // Creates an address space with nothing mapped in the user range, or
+    // returns `None` if there is no memory left
+    pub fn new() -> Option<Self> {
+        let table = super::allocate_frame()?;
+        unsafe { table_at(table) }.zero();
+        copy_kernel_entries(table);
+        Some(AddressSpace { table })
+    }
+
+    // The frame holding the level 4 table, e.g. for CR3
+    pub fn page_table(&self) -> PhysFrame {
+        self.table
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.table
+    }
+
+    fn with_mapper<R>(
+        &self,
+        f: impl FnOnce(&mut OffsetPageTable, &mut BootInfoFrameAllocator) -> R,
+    ) -> R {
+        let offset = physical_memory_offset().expect("memory not initialised");
+        with_paging(|paging| {
+            let mut mapper = unsafe { OffsetPageTable::new(table_at(self.table), offset) };
+            f(&mut mapper, &mut paging.frame_allocator)
+        })
+    }
+
+    // Maps `page` to a fresh zeroed frame, accessible from user mode with
+    // the given flags
+    pub fn map(&mut self, page: Page, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user(page), "{:?} is outside of the user range", page);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let active = self.is_active();
+
+        self.with_mapper(|mapper, frame_allocator| {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { table_at(frame) }.zero();
+            let flush = unsafe { mapper.map_to(page, frame, flags, frame_allocator)? };
+            // A new mapping is never cached, unless it replaced another
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            Ok(())
+        })
+    }
+
+    // Removes the mapping of `page` and frees its frame. Only the calling
+    // CPU runs user code in this address space, so it is the only one to
+    // flush.
+    pub fn unmap(&mut self, page: Page) -> Result<(), UnmapError> {
+        assert!(is_user(page), "{:?} is outside of the user range", page);
+        let active = self.is_active();
+
+        self.with_mapper(|mapper, frame_allocator| {
+            let (frame, flush) = mapper.unmap(page)?;
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Ok(())
+        })
+    }
+
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.with_mapper(|mapper, _| mapper.translate_addr(addr))
+    }
+
+    // The flags `page` is mapped with, or `None` if it is not mapped
+    pub fn flags(&self, page: Page) -> Option<PageTableFlags> {
+        self.with_mapper(|mapper, _| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        })
+    }
+
+    // Whether user code may access all of the `length` bytes at `addr`,
+    // for writing if `writable` is set
+    pub fn is_accessible(&self, addr: VirtAddr, length: usize, writable: bool) -> bool {
+        if length == 0 {
+            return true;
+        }
+        let end = match addr.as_u64().checked_add(length as u64) {
+            Some(end) if addr.as_u64() >= USER_START && end <= USER_END => end,
+            _ => return false,
+        };
+        let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            required |= PageTableFlags::WRITABLE;
+        }
+        let first = Page::containing_address(addr);
+        let last = Page::containing_address(VirtAddr::new(end - 1));
+        Page::range_inclusive(first, last).all(|page| {
+            self.flags(page)
+                .map_or(false, |flags| flags.contains(required))
+        })
+    }
+
+    // Copies from `addr` in this address space to `bytes`
+    pub fn read(&self, addr: VirtAddr, bytes: &mut [u8]) -> Result<(), Unmapped> {
+        self.for_each_chunk(addr, bytes.len(), |source, start| {
+            bytes[start..start + source.len()].copy_from_slice(source);
+        })
+    }
+
+    // Copies `bytes` to `addr` in this address space, which need not be
+    // the active one
+    pub fn write(&mut self, addr: VirtAddr, bytes: &[u8]) -> Result<(), Unmapped> {
+        self.for_each_chunk(addr, bytes.len(), |destination, start| {
+            destination.copy_from_slice(&bytes[start..start + destination.len()]);
+        })
+    }
+
+    pub fn zero(&mut self, addr: VirtAddr, length: usize) -> Result<(), Unmapped> {
+        self.for_each_chunk(addr, length, |destination, _| destination.fill(0))
+    }
+
+    // Calls `f` for the parts of `length` bytes at `addr` that are in the
+    // same page, with their offset from `addr`
+    fn for_each_chunk(
+        &self,
+        addr: VirtAddr,
+        length: usize,
+        mut f: impl FnMut(&mut [u8], usize),
+    ) -> Result<(), Unmapped> {
+        let offset = physical_memory_offset().expect("memory not initialised");
+        let mut done = 0;
+        while done < length {
+            let current = addr + done;
+            if !(USER_START..USER_END).contains(&current.as_u64()) {
+                return Err(Unmapped(current));
+            }
+            let phys = self.translate(current).ok_or(Unmapped(current))?;
+            let chunk = (4096 - usize::from(current.page_offset())).min(length - done);
+            let destination = unsafe {
+                core::slice::from_raw_parts_mut((offset + phys.as_u64()).as_mut_ptr::<u8>(), chunk)
+            };
+            f(destination, done);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    // Switches the calling CPU to this address space
+    pub fn activate(&self) {
+        switch_to(Some(self.table));
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Threads still using it would switch to freed page tables
+        debug_assert!(!self.is_active(), "dropping the active address space");
+
+        let table = unsafe { table_at(self.table) };
+        with_paging(|paging| {
+            for index in USER_ENTRIES {
+                let entry = &table[index];
+                if entry.flags().contains(PageTableFlags::PRESENT) {
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    unsafe { free_table(frame, 3, &mut paging.frame_allocator) };
+                }
+            }
+            unsafe { paging.frame_allocator.deallocate_frame(self.table) };
+        });
+    }
+}
+
+// Frees the page table of the given level in `frame`, along with the
+// tables and frames it maps
+//
+// This function is unsafe because nothing must use the table anymore.
+unsafe fn free_table(frame: PhysFrame, level: u8, frame_allocator: &mut BootInfoFrameAllocator) {
+    for entry in table_at(frame).iter() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let child = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            free_table(child, level - 1, frame_allocator);
+        } else {
+            frame_allocator.deallocate_frame(child);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+// Switches the calling CPU to the level 4 table in `table`, or to the
+// kernel's for `None`. Does nothing before paging is installed.
+pub(crate) fn switch_to(table: Option<PhysFrame>) {
+    let frame = match table {
+        Some(frame) => {
+            copy_kernel_entries(frame);
+            frame
+        }
+        None => match kernel_table() {
+            Some(frame) => frame,
+            None => return,
+        },
+    };
+    let (current, flags) = Cr3::read();
+    if current != frame {
+        unsafe { Cr3::write(frame, flags) };
+    }
+}
+
+// Switches the calling CPU back to the kernel's page table
+pub fn activate_kernel() {
+    switch_to(None);
+}
@@ -0,0 +1,40 @@
+// Keeping the TLBs of all CPUs coherent
+//
+// Every CPU caches translations in a TLB of its own. After a mapping was
+// removed or its permissions were reduced, the CPU that changed it has to
+// flush its own entry and have all other CPUs flush theirs, before
+// relying on the change ("TLB shootdown"). New mappings need no flush,
+// since the TLB never caches the absence of a mapping.
+
+use crate::smp;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::Page;
+
+// Number of shootdowns that had to interrupt other CPUs
+static SHOOTDOWNS: AtomicU64 = AtomicU64::new(0);
+
+// Flushes `page` from the TLBs of all CPUs, waiting until they are done.
+// Must not be called with locks held that other CPUs might be waiting
+// for with interrupts disabled.
+pub fn shootdown(page: Page) {
+    let addr = page.start_address();
+    tlb::flush(addr);
+    if smp::online_cpus() > 1 {
+        SHOOTDOWNS.fetch_add(1, Ordering::Relaxed);
+        smp::call_on_others(move || tlb::flush(addr));
+    }
+}
+
+// Like `shootdown`, for all pages except global ones
+pub fn shootdown_all() {
+    tlb::flush_all();
+    if smp::online_cpus() > 1 {
+        SHOOTDOWNS.fetch_add(1, Ordering::Relaxed);
+        smp::call_on_others(tlb::flush_all);
+    }
+}
+
+pub fn shootdowns() -> u64 {
+    SHOOTDOWNS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,183 @@
+// Per-CPU data
+//
+// The GS base of every CPU points to its entry in `CPUS`, so finding out
+// which CPU we run on is a single memory access. Variables declared with
+// `per_cpu!` hold one instance for every possible CPU and use that to
+// pick the calling CPU's instance.
+//
+// While running kernel code, the GS base always points to the per-CPU
+// data, and the kernel GS base holds user mode's GS base. Code that can
+// be entered from user mode has to `swapgs` before touching per-CPU data,
+// see `KernelGs` and the system call entry.
+
+use crate::smp::MAX_CPUS;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+// What GS points to. `cpu_id` and the system call entry in `usermode`
+// rely on the layout, with the fields at offsets 0, 8 and 16.
+#[repr(C)]
+struct CpuData {
+    id: AtomicUsize,
+    // Stack that system calls switch to
+    syscall_stack: AtomicU64,
+    // Where the system call entry keeps the user stack pointer until
+    // it is on the kernel stack
+    user_stack: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_DATA: CpuData = CpuData {
+    id: AtomicUsize::new(0),
+    syscall_stack: AtomicU64::new(0),
+    user_stack: AtomicU64::new(0),
+};
+
+static CPUS: [CpuData; MAX_CPUS] = [CPU_DATA; MAX_CPUS];
+
+// Set once the boot CPU's GS base is set up. Only the boot CPU runs
+// before that, and the APs set theirs before doing anything else.
+static READY: AtomicBool = AtomicBool::new(false);
+
+// Points the calling CPU's GS base to the data of CPU number `cpu`. Has
+// to be called on every CPU before anything else uses per-CPU data.
+pub(crate) fn init(cpu: usize) {
+    let data = &CPUS[cpu];
+    data.id.store(cpu, Ordering::Relaxed);
+
+    let pointer = VirtAddr::from_ptr(data);
+    GsBase::write(pointer);
+    KernelGsBase::write(VirtAddr::zero());
+    READY.store(true, Ordering::Release);
+}
+
+// Number of the calling CPU, the boot CPU being 0
+#[inline]
+pub fn cpu_id() -> usize {
+    // Per-CPU data may be used before `init` with GS still null, e.g. by
+    // locks, printing or `gdt::init` in tests that set up nothing else
+    if !READY.load(Ordering::Acquire) {
+        return 0;
+    }
+
+    let id: usize;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[0]",
+            out(reg) id,
+            options(nostack, preserves_flags, readonly)
+        );
+    }
+    id
+}
+
+// Sets the stack that system calls on the calling CPU run on
+pub(crate) fn set_syscall_stack(top: VirtAddr) {
+    CPUS[cpu_id()]
+        .syscall_stack
+        .store(top.as_u64(), Ordering::Relaxed);
+}
+
+// One instance of `T` for every CPU, declared with `per_cpu!`
+//
+// Each CPU usually only touches its own instance, so `T` need not be
+// `Sync`. It may still be used from interrupt handlers on the same CPU,
+// though, so updates that take more than a single step have to be done
+// with interrupts disabled.
+pub struct PerCpu<T> {
+    instances: [T; MAX_CPUS],
+}
+
+// Instances are only shared across CPUs through `get_for`,
+// which requires `T: Sync`
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(instances: [T; MAX_CPUS]) -> Self {
+        PerCpu { instances }
+    }
+
+    // The calling CPU's instance
+    #[inline]
+    pub fn get(&self) -> &T {
+        &self.instances[cpu_id()]
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    // The instance of CPU number `cpu`
+    pub fn get_for(&self, cpu: usize) -> &T {
+        &self.instances[cpu]
+    }
+}
+
+// Declares a static with one instance per CPU, each initialised
+// with the given constant expression
+//
+//     per_cpu! {
+//         static COUNTER: AtomicU64 = AtomicU64::new(0);
+//     }
+//
+//     COUNTER.get().fetch_add(1, Ordering::Relaxed);
+#[macro_export]
+macro_rules! per_cpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpu<$ty> = {
+            #[allow(clippy::declare_interior_mutable_const)]
+            const INIT: $ty = $init;
+            $crate::percpu::PerCpu::new([INIT; $crate::smp::MAX_CPUS])
+        };
+    };
+}
+
+// Makes GS point to the per-CPU data for as long as it lives, in an
+// interrupt handler that might have interrupted user mode. Must be
+// created before the handler touches any per-CPU data.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        Self::enter_from(stack_frame.code_segment)
+    }
+
+    // Like `enter`, given the code segment of the interrupted code
+    pub fn enter_from(code_segment: u64) -> Self {
+        // The privilege level of the interrupted code
+        let swapped = code_segment & 0b11 != 0;
+        if swapped {
+            unsafe { x86_64::instructions::segmentation::swap_gs() };
+        }
+        KernelGs { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { x86_64::instructions::segmentation::swap_gs() };
+        }
+    }
+}
+
+#[test_case]
+fn boot_cpu_is_cpu_zero() {
+    assert_eq!(cpu_id(), 0);
+}
+
+#[test_case]
+fn per_cpu_variable_has_instance_per_cpu() {
+    use core::sync::atomic::AtomicU64;
+
+    per_cpu! {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+    }
+
+    COUNTER.get().fetch_add(1, Ordering::Relaxed);
+    assert_eq!(COUNTER.get_for(0).load(Ordering::Relaxed), 1);
+    assert_eq!(COUNTER.get_for(1).load(Ordering::Relaxed), 0);
+}
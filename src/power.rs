@@ -0,0 +1,276 @@
+// Powering the machine off and resetting it
+//
+// Both try the ACPI way first and fall back to older mechanisms, ending
+// with one that always works (or at least stops the kernel).
+//
+// Shutting down writes the sleep type of the soft-off state S5, found in
+// the DSDT, to the PM1 control registers. QEMU and Bochs also power off
+// when a magic value is written to one of their ports.
+//
+// Rebooting uses the reset register from the FADT, then pulses the reset
+// line through the keyboard controller, and finally triple faults.
+//
+// Both also run from the panic handler, which must not allocate or map
+// memory, so `init` takes what they need from the ACPI tables up front.
+
+use crate::acpi::{self, AddressSpace, GenericAddress, SleepType};
+use crate::{info, memory, warn};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::{PhysAddr, VirtAddr};
+
+// PM1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+// Ports and values that power off emulators without ACPI support
+const EMULATOR_SHUTDOWN_PORTS: &[(u16, u16)] = &[
+    (0x604, 0x2000),  // QEMU with the default i440fx and q35 machines
+    (0xb004, 0x2000), // Bochs and older QEMU versions
+    (0x4004, 0x3400), // VirtualBox
+];
+
+const KEYBOARD_STATUS: u16 = 0x64;
+const KEYBOARD_COMMAND: u16 = 0x64;
+const KEYBOARD_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_PULSE_RESET: u8 = 0xfe;
+
+// What the panic handler does after printing the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicAction {
+    Halt,
+    Reboot,
+    Shutdown,
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+
+// What entering S5 takes, from the FADT and the DSDT
+struct SoftOff {
+    pm1a_control: u16,
+    pm1b_control: Option<u16>,
+    smi_command: Option<u16>,
+    acpi_enable: u8,
+    sleep_type: SleepType,
+}
+
+// The FADT's reset register, already mapped if it is memory-mapped
+struct Reset {
+    register: GenericAddress,
+    value: u8,
+    mapped: Option<VirtAddr>,
+}
+
+struct AcpiPower {
+    soft_off: Result<SoftOff, &'static str>,
+    reset: Option<Reset>,
+}
+
+static ACPI_POWER: OnceCell<AcpiPower> = OnceCell::uninit();
+
+// Reads what shutting down and rebooting need from the ACPI tables. Needs
+// `memory::install` to have been called.
+pub fn init() {
+    let fadt = acpi::fadt();
+    let soft_off = fadt.as_ref().ok_or("no FADT").and_then(|fadt| {
+        Ok(SoftOff {
+            pm1a_control: fadt.pm1a_control_block.ok_or("no PM1a control block")?,
+            pm1b_control: fadt.pm1b_control_block,
+            smi_command: fadt.smi_command_port,
+            acpi_enable: fadt.acpi_enable,
+            sleep_type: acpi::s5_sleep_type().ok_or("no _S5_ object in the DSDT")?,
+        })
+    });
+    let reset = fadt.as_ref().and_then(|fadt| {
+        let register = fadt.reset_register?;
+        let mapped = match register.space {
+            AddressSpace::SystemMemory => {
+                match memory::map_mmio(PhysAddr::new(register.address), 1) {
+                    Ok(addr) => Some(addr),
+                    Err(err) => {
+                        warn!("could not map the reset register: {:?}", err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        Some(Reset {
+            register,
+            value: fadt.reset_value,
+            mapped,
+        })
+    });
+
+    let _ = ACPI_POWER.try_init_once(|| AcpiPower { soft_off, reset });
+}
+
+// Waits for roughly `ms` milliseconds without relying on interrupts, as
+// each write to the POST code port takes about a microsecond
+fn io_delay_ms(ms: u64) {
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..ms * 1000 {
+        unsafe { port.write(0) };
+    }
+}
+
+pub fn shutdown() -> ! {
+    info!("shutting down");
+    interrupts::disable();
+
+    if let Err(reason) = acpi_shutdown() {
+        warn!("ACPI shutdown failed: {}", reason);
+    }
+
+    for &(port, value) in EMULATOR_SHUTDOWN_PORTS {
+        unsafe { Port::<u16>::new(port).write(value) };
+        io_delay_ms(10);
+    }
+
+    warn!("could not power off, halting");
+    halt()
+}
+
+fn acpi_shutdown() -> Result<(), &'static str> {
+    let power = ACPI_POWER.try_get().map_err(|_| "not initialised")?;
+    let soft_off = power.soft_off.as_ref().map_err(|&reason| reason)?;
+    let sleep_type = soft_off.sleep_type;
+
+    // The firmware owns the power management registers until we ask
+    // for them, which sets SCI_EN
+    let mut pm1a = Port::<u16>::new(soft_off.pm1a_control);
+    if unsafe { pm1a.read() } & SCI_EN == 0 {
+        if let Some(smi_command) = soft_off.smi_command.filter(|_| soft_off.acpi_enable != 0) {
+            unsafe { Port::<u8>::new(smi_command).write(soft_off.acpi_enable) };
+            for _ in 0..300 {
+                if unsafe { pm1a.read() } & SCI_EN != 0 {
+                    break;
+                }
+                io_delay_ms(1);
+            }
+        }
+    }
+
+    unsafe {
+        pm1a.write((u16::from(sleep_type.a) << SLP_TYP_SHIFT) | SLP_EN);
+        if let Some(pm1b_control) = soft_off.pm1b_control {
+            Port::<u16>::new(pm1b_control)
+                .write((u16::from(sleep_type.b) << SLP_TYP_SHIFT) | SLP_EN);
+        }
+    }
+    // Powering off is not instant
+    io_delay_ms(100);
+    Err("still running after entering S5")
+}
+
+pub fn reboot() -> ! {
+    info!("rebooting");
+    interrupts::disable();
+
+    if let Some(reset) = ACPI_POWER
+        .try_get()
+        .ok()
+        .and_then(|power| power.reset.as_ref())
+    {
+        write_reset_register(reset);
+        io_delay_ms(100);
+    }
+
+    keyboard_controller_reset();
+    io_delay_ms(100);
+
+    warn!("reset failed, triple faulting");
+    triple_fault()
+}
+
+fn write_reset_register(reset: &Reset) {
+    let (register, value) = (reset.register, reset.value);
+    match register.space {
+        AddressSpace::SystemIo => unsafe { Port::<u8>::new(register.address as u16).write(value) },
+        AddressSpace::SystemMemory => {
+            if let Some(addr) = reset.mapped {
+                unsafe { addr.as_mut_ptr::<u8>().write_volatile(value) };
+            }
+        }
+        // A register of a function on bus 0, written through the legacy
+        // configuration mechanism
+        AddressSpace::PciConfig => {
+            let device = (register.address >> 32) as u32 & 0x1f;
+            let function = (register.address >> 16) as u32 & 0x7;
+            let offset = register.address as u32 & 0xff;
+            let address = 1 << 31 | device << 11 | function << 8 | (offset & 0xfc);
+            unsafe {
+                Port::<u32>::new(0xcf8).write(address);
+                Port::<u8>::new(0xcfc + (offset & 3) as u16).write(value);
+            }
+        }
+        AddressSpace::Other(space) => warn!("reset register in unknown address space {}", space),
+    }
+}
+
+fn keyboard_controller_reset() {
+    let mut status = Port::<u8>::new(KEYBOARD_STATUS);
+    // Wait for the controller to accept commands, but not forever
+    for _ in 0..1000 {
+        if unsafe { status.read() } & KEYBOARD_INPUT_FULL == 0 {
+            break;
+        }
+        io_delay_ms(1);
+    }
+    unsafe { Port::<u8>::new(KEYBOARD_COMMAND).write(KEYBOARD_PULSE_RESET) };
+}
+
+// With an empty IDT, the breakpoint exception cannot be delivered, nor
+// can the resulting double fault. The CPU then resets.
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe { lidt(&empty) };
+    x86_64::instructions::interrupts::int3();
+    halt()
+}
+
+fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        action if action == PanicAction::Reboot as u8 => PanicAction::Reboot,
+        action if action == PanicAction::Shutdown as u8 => PanicAction::Shutdown,
+        _ => PanicAction::Halt,
+    }
+}
+
+// Called by the panic handler once the message is out. Waits a few
+// seconds before rebooting or powering off, so the message can be read.
+pub fn on_panic() -> ! {
+    const DELAY_MS: u64 = 5000;
+
+    match panic_action() {
+        PanicAction::Halt => crate::hlt_loop(),
+        PanicAction::Reboot => {
+            io_delay_ms(DELAY_MS);
+            reboot()
+        }
+        PanicAction::Shutdown => {
+            io_delay_ms(DELAY_MS);
+            shutdown()
+        }
+    }
+}
@@ -0,0 +1,368 @@
+// Processes: user programs running in their own address space
+//
+// Every process runs its program on a kernel thread of its own and owns
+// an address space and a table of open files. When the program exits, the
+// process becomes a zombie holding on to its exit status until its parent
+// collects it with `wait` or `waitpid`. Processes started by the kernel
+// have no parent and are waited for by the kernel. Children outliving
+// their parent are adopted by the kernel, which reaps them as soon as
+// they exit, as are detached processes.
+//
+// `kill` takes effect the next time the process enters or leaves the
+// kernel, i.e. at the latest at the next timer tick if it is running user
+// code. A process blocked in a system call is woken up, and the call gives
+// up waiting.
+
+use crate::elf::{self, ElfError};
+use crate::lock::SpinLock;
+use crate::thread::sync::Mutex;
+use crate::thread::{self, ThreadId, WaitQueue};
+use crate::usermode::{self, Exit};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+pub mod console;
+pub mod file;
+pub mod memory;
+
+pub use file::FileTable;
+pub use memory::Memory;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        // As usual, the first process gets 1
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    // Exited, but not waited for yet
+    Zombie(Exit),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    // `wait` was called by a process without children
+    NoChildren,
+    // The calling process was killed while waiting
+    Interrupted,
+}
+
+struct Process {
+    name: String,
+    // `None` for processes of the kernel
+    parent: Option<Pid>,
+    // Set by the process's thread once it runs
+    thread: Option<ThreadId>,
+    // Dropped once the process exits
+    memory: Option<Arc<Mutex<Memory>>>,
+    files: FileTable,
+    state: ProcessState,
+    // Reaped on exit instead of becoming a zombie
+    detached: bool,
+    killed: bool,
+}
+
+static PROCESSES: SpinLock<BTreeMap<Pid, Process>> = SpinLock::new(BTreeMap::new());
+
+// Woken whenever a process exits
+static EXITED: WaitQueue = WaitQueue::new();
+
+// Loads the executable in `image` and starts it in a new process, with
+// `parent` as its parent process and the console as standard input,
+// output and error
+pub fn spawn(
+    parent: Option<Pid>,
+    name: &str,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Pid, ElfError> {
+    let program = elf::load(image, argv, envp)?;
+    let program_break = program.program_break();
+    let (address_space, entry, stack_pointer) = program.into_parts();
+    let page_table = address_space.page_table();
+    let memory = Memory::new(address_space, program_break);
+    let pid = Pid::new();
+
+    let process = Process {
+        name: String::from(name),
+        parent,
+        thread: None,
+        memory: Some(Arc::new(Mutex::new(memory))),
+        files: FileTable::with_console(),
+        state: ProcessState::Running,
+        // A parent that has exited already cannot wait for it
+        detached: parent.map_or(false, |parent| !is_running(parent)),
+        killed: false,
+    };
+    PROCESSES.lock().insert(pid, process);
+
+    // Dropping the handle detaches the thread, the process table is what
+    // keeps track of it
+    thread::spawn("process", move || {
+        run(pid, page_table, entry, stack_pointer)
+    });
+    Ok(pid)
+}
+
+fn is_running(pid: Pid) -> bool {
+    matches!(
+        PROCESSES.lock().get(&pid),
+        Some(process) if process.state == ProcessState::Running
+    )
+}
+
+fn run(pid: Pid, page_table: PhysFrame, entry: VirtAddr, stack_pointer: VirtAddr) {
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.thread = Some(thread::current());
+    }
+    let exit = usermode::enter_address_space(page_table, entry, stack_pointer);
+    exit_process(pid, exit);
+}
+
+fn exit_process(pid: Pid, exit: Exit) {
+    // Dropped once the lock is released
+    let (memory, files) = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).expect("running process not found");
+        process.state = ProcessState::Zombie(exit);
+        process.thread = None;
+        let resources = (process.memory.take(), core::mem::take(&mut process.files));
+        if process.detached {
+            processes.remove(&pid);
+        }
+
+        // Zombie children are of no use to anyone anymore
+        processes.retain(|_, child| {
+            child.parent != Some(pid) || !matches!(child.state, ProcessState::Zombie(_))
+        });
+        for child in processes.values_mut() {
+            if child.parent == Some(pid) {
+                child.parent = None;
+                child.detached = true;
+            }
+        }
+        resources
+    };
+    drop((memory, files));
+    EXITED.wake_all();
+}
+
+// The process whose thread is the current one, if any
+pub fn current() -> Option<Pid> {
+    let thread = thread::current();
+    PROCESSES
+        .lock()
+        .iter()
+        .find(|(_, process)| process.thread == Some(thread))
+        .map(|(&pid, _)| pid)
+}
+
+// The memory of the current process, if any
+pub fn memory() -> Option<Arc<Mutex<Memory>>> {
+    with_current(|process| process.memory.clone()).flatten()
+}
+
+// Calls `f` with the open files of the current process, if any. `f` must
+// not block, the process table is locked meanwhile.
+pub fn with_files<R>(f: impl FnOnce(&mut FileTable) -> R) -> Option<R> {
+    with_current(|process| f(&mut process.files))
+}
+
+fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let thread = thread::current();
+    let mut processes = PROCESSES.lock();
+    let process = processes
+        .values_mut()
+        .find(|process| process.thread == Some(thread))?;
+    Some(f(process))
+}
+
+pub fn parent(pid: Pid) -> Result<Option<Pid>, ProcessError> {
+    let processes = PROCESSES.lock();
+    let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    Ok(process.parent)
+}
+
+pub fn state(pid: Pid) -> Result<ProcessState, ProcessError> {
+    let processes = PROCESSES.lock();
+    let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    Ok(process.state)
+}
+
+// Blocks until a child of `parent` (of the kernel for `None`) has exited,
+// then reaps it and returns its PID and exit status
+pub fn wait(parent: Option<Pid>) -> Result<(Pid, Exit), ProcessError> {
+    let mut result = Err(ProcessError::NoChildren);
+    EXITED.wait_until(|| {
+        let mut processes = PROCESSES.lock();
+        if current_is_killed(&processes) {
+            result = Err(ProcessError::Interrupted);
+            return true;
+        }
+        let mut children = processes
+            .iter()
+            .filter(|(_, process)| process.parent == parent && !process.detached);
+        let zombie = children
+            .clone()
+            .find_map(|(&pid, process)| match process.state {
+                ProcessState::Zombie(exit) => Some((pid, exit)),
+                ProcessState::Running => None,
+            });
+        match zombie {
+            Some((pid, exit)) => {
+                processes.remove(&pid);
+                result = Ok((pid, exit));
+                true
+            }
+            // Nothing to wait for
+            None => children.next().is_none(),
+        }
+    });
+    result
+}
+
+// Blocks until the process `pid` has exited, then reaps it and returns
+// its exit status
+pub fn waitpid(pid: Pid) -> Result<Exit, ProcessError> {
+    let mut result = Err(ProcessError::NoSuchProcess);
+    EXITED.wait_until(|| {
+        let mut processes = PROCESSES.lock();
+        if current_is_killed(&processes) {
+            result = Err(ProcessError::Interrupted);
+            return true;
+        }
+        match processes.get(&pid) {
+            Some(process) if process.detached => true,
+            Some(process) => match process.state {
+                ProcessState::Zombie(exit) => {
+                    processes.remove(&pid);
+                    result = Ok(exit);
+                    true
+                }
+                ProcessState::Running => false,
+            },
+            None => true,
+        }
+    });
+    result
+}
+
+// Like `waitpid`, but returns `None` right away if the process is still
+// running
+pub fn try_waitpid(pid: Pid) -> Result<Option<Exit>, ProcessError> {
+    let mut processes = PROCESSES.lock();
+    match processes.get(&pid) {
+        Some(process) if !process.detached => match process.state {
+            ProcessState::Zombie(exit) => {
+                processes.remove(&pid);
+                Ok(Some(exit))
+            }
+            ProcessState::Running => Ok(None),
+        },
+        _ => Err(ProcessError::NoSuchProcess),
+    }
+}
+
+// Lets the kernel reap the process when it exits, nobody will wait for it
+pub fn detach(pid: Pid) -> Result<(), ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    if let ProcessState::Zombie(_) = process.state {
+        processes.remove(&pid);
+    } else {
+        process.detached = true;
+    }
+    Ok(())
+}
+
+// Makes the process exit with `Exit::Killed`. Killing a zombie does
+// nothing.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let thread = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+        process.killed = true;
+        process.thread
+    };
+    // Blocking system calls check `is_killed` when woken up. If the thread
+    // waits for something else, e.g. a lock, it just checks again and goes
+    // back to sleep, still queued in its wait queue.
+    if let Some(thread) = thread {
+        thread::wake(thread);
+    }
+    Ok(())
+}
+
+// Whether the current thread is that of a killed process
+fn current_is_killed(processes: &BTreeMap<Pid, Process>) -> bool {
+    let mut threads = processes
+        .values()
+        .filter(|process| process.killed)
+        .filter_map(|process| process.thread)
+        .peekable();
+    // Spares looking up the current thread on every system call
+    if threads.peek().is_none() {
+        return false;
+    }
+    let current = thread::current();
+    threads.any(|thread| thread == current)
+}
+
+// Whether the current process has been killed, for system calls to stop
+// blocking
+pub(crate) fn is_killed() -> bool {
+    current_is_killed(&PROCESSES.lock())
+}
+
+// Ends the current process if it has been killed. Must only be called
+// where `usermode::exit_current` may be.
+pub(crate) fn exit_if_killed() {
+    if is_killed() {
+        usermode::exit_current(Exit::Killed);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: ProcessState,
+    pub open_files: usize,
+}
+
+pub fn list() -> Vec<ProcessInfo> {
+    PROCESSES
+        .lock()
+        .iter()
+        .map(|(&pid, process)| ProcessInfo {
+            pid,
+            parent: process.parent,
+            name: process.name.clone(),
+            state: process.state,
+            open_files: process.files.len(),
+        })
+        .collect()
+}
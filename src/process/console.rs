@@ -0,0 +1,143 @@
+// The console as a file: output goes to the screen, input comes from the
+// keyboard
+//
+// While a process waits in `read`, scancodes from the keyboard interrupt
+// go to it instead of the shell. They are decoded in the reading thread,
+// echoed to the screen and handed out as UTF-8. Killing the process
+// makes `read` give up with EINTR.
+
+use super::file::File;
+use crate::lock::SpinLock;
+use crate::syscall::Errno;
+use crate::thread::sync::Mutex;
+use crate::thread::WaitQueue;
+use crate::{print, process};
+use alloc::collections::VecDeque;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+// Scancodes that arrived for the waiting reader
+const QUEUE_SIZE: usize = 64;
+
+struct Input {
+    reading: bool,
+    // A ring buffer, as the interrupt handler must not allocate
+    scancodes: [u8; QUEUE_SIZE],
+    start: usize,
+    len: usize,
+}
+
+static INPUT: SpinLock<Input> = SpinLock::new(Input {
+    reading: false,
+    scancodes: [0; QUEUE_SIZE],
+    start: 0,
+    len: 0,
+});
+
+static INPUT_READY: WaitQueue = WaitQueue::new();
+
+struct Decoder {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    // Decoded input not read yet
+    pending: VecDeque<u8>,
+}
+
+// Only one thread reads at a time, the others wait for the lock
+static DECODER: Mutex<Option<Decoder>> = Mutex::new(None);
+
+// Called by the keyboard interrupt handler. Returns whether a reader took
+// the scancode.
+pub(crate) fn take_scancode(scancode: u8) -> bool {
+    {
+        let mut input = INPUT.lock();
+        if !input.reading {
+            return false;
+        }
+        // Drops the scancode if the reader is too slow
+        if input.len < QUEUE_SIZE {
+            let end = (input.start + input.len) % QUEUE_SIZE;
+            input.scancodes[end] = scancode;
+            input.len += 1;
+        }
+    }
+    INPUT_READY.wake_all();
+    true
+}
+
+fn next_scancode() -> Option<u8> {
+    let mut input = INPUT.lock();
+    if input.len == 0 {
+        return None;
+    }
+    let scancode = input.scancodes[input.start];
+    input.start = (input.start + 1) % QUEUE_SIZE;
+    input.len -= 1;
+    Some(scancode)
+}
+
+// Blocks until there is input, then reads as much of it as fits
+fn read_input(buffer: &mut [u8]) -> Result<usize, Errno> {
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+    let mut decoder = DECODER.lock();
+    let decoder = decoder.get_or_insert_with(|| Decoder {
+        keyboard: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+        pending: VecDeque::new(),
+    });
+
+    while decoder.pending.is_empty() {
+        INPUT.lock().reading = true;
+        INPUT_READY.wait_until(|| INPUT.lock().len > 0 || process::is_killed());
+        INPUT.lock().reading = false;
+        if process::is_killed() {
+            return Err(Errno::EINTR);
+        }
+
+        while let Some(scancode) = next_scancode() {
+            let key = match decoder.keyboard.add_byte(scancode) {
+                Ok(Some(event)) => decoder.keyboard.process_keyevent(event),
+                _ => None,
+            };
+            if let Some(DecodedKey::Unicode(character)) = key {
+                print!("{}", character);
+                let mut bytes = [0; 4];
+                let bytes = character.encode_utf8(&mut bytes).as_bytes();
+                decoder.pending.extend(bytes);
+            }
+        }
+    }
+
+    let count = buffer.len().min(decoder.pending.len());
+    for (byte, pending) in buffer.iter_mut().zip(decoder.pending.drain(..count)) {
+        *byte = pending;
+    }
+    Ok(count)
+}
+
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
+        read_input(buffer)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, Errno> {
+        // Invalid UTF-8 is shown as replacement characters
+        let mut rest = buffer;
+        while !rest.is_empty() {
+            match core::str::from_utf8(rest) {
+                Ok(text) => {
+                    print!("{}", text);
+                    break;
+                }
+                Err(error) => {
+                    let (valid, invalid) = rest.split_at(error.valid_up_to());
+                    let valid = core::str::from_utf8(valid).unwrap();
+                    print!("{}\u{fffd}", valid);
+                    rest = &invalid[error.error_len().unwrap_or(invalid.len())..];
+                }
+            }
+        }
+        Ok(buffer.len())
+    }
+}
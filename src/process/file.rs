@@ -0,0 +1,92 @@
+// Open files of a process
+//
+// A file descriptor is an index into the process's `FileTable`. Several
+// descriptors, also of different processes, may refer to the same open
+// file, which is closed once the last one is.
+
+use crate::syscall::Errno;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub use super::console::Console;
+
+// Descriptors of the console, opened for every new process
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+// Most files a process can have open at once
+pub const MAX_FILES: usize = 64;
+
+pub trait File: Send + Sync {
+    // Reads up to `buffer.len()` bytes, returns how many were read, zero
+    // at the end of the file
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    // Writes up to `buffer.len()` bytes, returns how many were written
+    fn write(&self, _buffer: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        FileTable { files: Vec::new() }
+    }
+
+    // A table with the console open as standard input, output and error
+    pub fn with_console() -> Self {
+        let console: Arc<dyn File> = Arc::new(Console);
+        let mut table = FileTable::new();
+        for _ in STDIN..=STDERR {
+            table.insert(console.clone()).unwrap();
+        }
+        table
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<dyn File>> {
+        self.files.get(fd).cloned().flatten()
+    }
+
+    // Opens `file` with the lowest free descriptor, which is returned
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Result<usize, Errno> {
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                Ok(fd)
+            }
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(Some(file));
+                Ok(self.files.len() - 1)
+            }
+            None => Err(Errno::EMFILE),
+        }
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Result<Arc<dyn File>, Errno> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(Errno::EBADF)
+    }
+
+    // Number of open descriptors
+    pub fn len(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
@@ -0,0 +1,152 @@
+// The user memory of a process
+//
+// Besides the segments and stack set up by the ELF loader, a process can
+// grow a heap right above its segments with `brk` and get anonymous
+// mappings with `mmap`. The latter are placed from `MMAP_START` upwards
+// and their addresses are not reused after `munmap`.
+
+use crate::elf;
+use crate::memory::address_space::USER_START;
+use crate::memory::AddressSpace;
+use crate::syscall::Errno;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// Start of the range for anonymous mappings, which is also as far as the
+// heap can grow
+pub const MMAP_START: u64 = 0x0000_1000_0000_0000;
+// End of the range, the stack's guard page
+const MMAP_END: u64 = elf::GUARD_PAGE;
+
+pub struct Memory {
+    address_space: AddressSpace,
+    heap_start: VirtAddr,
+    // The program break, where the heap ends
+    heap_end: VirtAddr,
+    // Where the next mapping without address goes
+    next_mapping: VirtAddr,
+}
+
+// The pages covering `length` bytes from the page-aligned `start`
+fn pages(start: VirtAddr, length: u64) -> impl Iterator<Item = Page> {
+    let first = Page::containing_address(start);
+    let end = Page::containing_address(start + length.max(1) - 1u64) + 1;
+    let count = if length == 0 { 0 } else { end - first };
+    (0..count).map(move |i| first + i)
+}
+
+impl Memory {
+    // The heap starts at `heap_start`, usually `Program::program_break`
+    pub fn new(address_space: AddressSpace, heap_start: VirtAddr) -> Self {
+        Memory {
+            address_space,
+            heap_start,
+            heap_end: heap_start,
+            next_mapping: VirtAddr::new(MMAP_START),
+        }
+    }
+
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    pub fn program_break(&self) -> VirtAddr {
+        self.heap_end
+    }
+
+    // Moves the program break to `end`, mapping or unmapping heap pages.
+    // Returns the new program break, which is the old one if `end` is out
+    // of range or there is not enough memory.
+    pub fn set_program_break(&mut self, end: VirtAddr) -> VirtAddr {
+        if end < self.heap_start || end.as_u64() > MMAP_START {
+            return self.heap_end;
+        }
+        let old_top = self.heap_end.align_up(4096u64);
+        let new_top = end.align_up(4096u64);
+        if new_top > old_top {
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            if self.map_range(old_top, new_top - old_top, flags).is_err() {
+                return self.heap_end;
+            }
+        } else {
+            self.unmap_range(new_top, old_top - new_top);
+        }
+        self.heap_end = end;
+        end
+    }
+
+    // Maps `length` bytes of zeroed memory with `flags`, at `address` if
+    // given, replacing whatever was mapped there, and returns its address
+    pub fn map_anonymous(
+        &mut self,
+        address: Option<VirtAddr>,
+        length: u64,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, Errno> {
+        if length == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let size = length.checked_add(4095).ok_or(Errno::ENOMEM)? & !4095;
+        let start = match address {
+            Some(address) => {
+                let in_range = address.as_u64().checked_add(size).map_or(false, |end| {
+                    address.as_u64() >= USER_START && end <= MMAP_END
+                });
+                if !address.is_aligned(4096u64) || !in_range {
+                    return Err(Errno::EINVAL);
+                }
+                self.unmap_range(address, size);
+                address
+            }
+            None => {
+                let start = self.next_mapping;
+                if MMAP_END - start.as_u64() < size {
+                    return Err(Errno::ENOMEM);
+                }
+                self.next_mapping = start + size;
+                start
+            }
+        };
+        self.map_range(start, size, flags)?;
+        Ok(start)
+    }
+
+    // Unmaps the pages in `length` bytes from `address`, mapped or not
+    pub fn unmap(&mut self, address: VirtAddr, length: u64) -> Result<(), Errno> {
+        let in_range = address.as_u64().checked_add(length).map_or(false, |end| {
+            address.as_u64() >= USER_START && end <= MMAP_END
+        });
+        if !address.is_aligned(4096u64) || length == 0 || !in_range {
+            return Err(Errno::EINVAL);
+        }
+        self.unmap_range(address, length);
+        Ok(())
+    }
+
+    // Maps all pages or none
+    fn map_range(
+        &mut self,
+        start: VirtAddr,
+        length: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), Errno> {
+        for (mapped, page) in pages(start, length).enumerate() {
+            if self.address_space.map(page, flags).is_err() {
+                self.unmap_range(start, mapped as u64 * 4096);
+                return Err(Errno::ENOMEM);
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap_range(&mut self, start: VirtAddr, length: u64) {
+        for page in pages(start, length) {
+            // Not mapped is fine, the frames of mapped pages are freed
+            let _ = self.address_space.unmap(page);
+        }
+    }
+}
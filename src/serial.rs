@@ -1,14 +1,26 @@
+use crate::lock::SpinLock;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: SpinLock<SerialPort> = {
         // 0x3F8 is the port number of the first serial interface,
         // the addresses of the other UART ports can be figured out
         // with this address.
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
+        SpinLock::new(serial_port)
+    };
+}
+
+lazy_static! {
+    // The second serial interface, reserved for the GDB stub. The stub
+    // holds it while waiting for the debugger, which the lock debugging
+    // would report, so it uses a plain mutex.
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+        serial_port.init();
         Mutex::new(serial_port)
     };
 }
@@ -16,17 +28,13 @@ lazy_static! {
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // Ensure that no interrupts occur while holding the lock
-    // so that we can safely invoke this function while
-    // handling interrupts
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed.");
-    });
+    // The lock keeps interrupts disabled while it is held, so that
+    // we can safely invoke this function while handling interrupts
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed.");
 }
 
 // Prints to the host through the serial interface
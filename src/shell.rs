@@ -0,0 +1,295 @@
+// A minimal interactive shell reading lines from the keyboard and
+// running the matching built-in command.
+
+use crate::interrupts::stats;
+use crate::log::dmesg;
+use crate::power::{self, PanicAction};
+use crate::task::{keyboard::ScancodeStream, stats as task_stats};
+use crate::{elf, print, println, process, thread, time, vga_buffer};
+use alloc::string::String;
+use alloc::vec::Vec;
+use futures_util::stream::StreamExt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+const PROMPT: &str = "> ";
+const BACKSPACE: char = '\u{8}';
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    // Called with everything after the command name
+    run: fn(&str),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the available commands",
+        run: help,
+    },
+    Command {
+        name: "dmesg",
+        help: "print the kernel log (`dmesg serial` writes it to serial)",
+        run: dmesg,
+    },
+    Command {
+        name: "irqstat",
+        help: "print interrupt counters",
+        run: irqstat,
+    },
+    Command {
+        name: "kill",
+        help: "stop a process: `kill <pid>`",
+        run: kill,
+    },
+    Command {
+        name: "onpanic",
+        help: "what to do after a panic: `halt`, `reboot` or `shutdown`",
+        run: onpanic,
+    },
+    Command {
+        name: "ps",
+        help: "list user processes",
+        run: ps,
+    },
+    Command {
+        name: "reboot",
+        help: "restart the machine",
+        run: reboot,
+    },
+    Command {
+        name: "run",
+        help: "start an embedded user program: `run <program> [args]`",
+        run: run_program,
+    },
+    Command {
+        name: "shutdown",
+        help: "power the machine off",
+        run: shutdown,
+    },
+    Command {
+        name: "threads",
+        help: "list kernel threads",
+        run: threads,
+    },
+    Command {
+        name: "top",
+        help: "list tasks with their poll counts and times",
+        run: top,
+    },
+    Command {
+        name: "uptime",
+        help: "print the time since boot",
+        run: uptime,
+    },
+];
+
+pub async fn run() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut line = String::new();
+
+    print!("{}", PROMPT);
+    while let Some(scancode) = scancodes.next().await {
+        let key = match keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+            _ => None,
+        };
+
+        match key {
+            Some(DecodedKey::Unicode('\n')) => {
+                println!();
+                execute(&line);
+                line.clear();
+                print!("{}", PROMPT);
+            }
+            Some(DecodedKey::Unicode(BACKSPACE)) => {
+                if line.pop().is_some() {
+                    print!("{}", BACKSPACE);
+                }
+            }
+            Some(DecodedKey::Unicode(character)) if !character.is_control() => {
+                line.push(character);
+                print!("{}", character);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn execute(line: &str) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+
+    let (name, args) = match line.find(' ') {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args),
+        None => println!("unknown command: {} (try `help`)", name),
+    }
+}
+
+fn help(_args: &str) {
+    for command in COMMANDS {
+        println!("{:<10} {}", command.name, command.help);
+    }
+}
+
+fn dmesg(args: &str) {
+    if args == "serial" {
+        dmesg::dump_serial();
+        return;
+    }
+
+    // Printing the records must not add them to the log again
+    dmesg::for_each(|entry| vga_buffer::_print_screen(format_args!("{}\n", entry)));
+    if dmesg::dropped() > 0 {
+        println!("({} records dropped)", dmesg::dropped());
+    }
+}
+
+fn irqstat(_args: &str) {
+    let snapshot = stats::snapshot();
+    for (vector, count) in snapshot.active_vectors() {
+        println!("vector {:>3}: {}", vector, count);
+    }
+    println!(
+        "spurious: pic1 {} pic2 {} apic {}",
+        snapshot.spurious_pic_primary, snapshot.spurious_pic_secondary, snapshot.spurious_apic
+    );
+}
+
+fn kill(args: &str) {
+    let pid = match args.parse() {
+        Ok(pid) => process::Pid::from_u64(pid),
+        Err(_) => {
+            println!("usage: kill <pid>");
+            return;
+        }
+    };
+    if let Err(error) = process::kill(pid) {
+        println!("cannot kill process {}: {:?}", pid.as_u64(), error);
+    }
+}
+
+fn onpanic(args: &str) {
+    let action = match args {
+        "" => {
+            println!("{:?}", power::panic_action());
+            return;
+        }
+        "halt" => PanicAction::Halt,
+        "reboot" => PanicAction::Reboot,
+        "shutdown" => PanicAction::Shutdown,
+        _ => {
+            println!("usage: onpanic [halt|reboot|shutdown]");
+            return;
+        }
+    };
+    power::set_panic_action(action);
+}
+
+fn ps(_args: &str) {
+    println!(
+        "{:>4} {:>6} {:<12} {:>5} {}",
+        "PID", "PARENT", "NAME", "FILES", "STATE"
+    );
+    for process in process::list() {
+        let parent = process.parent.map_or(0, |parent| parent.as_u64());
+        println!(
+            "{:>4} {:>6} {:<12} {:>5} {:?}",
+            process.pid.as_u64(),
+            parent,
+            process.name,
+            process.open_files,
+            process.state
+        );
+    }
+}
+
+fn reboot(_args: &str) {
+    power::reboot();
+}
+
+fn run_program(args: &str) {
+    let argv: Vec<&str> = args.split_whitespace().collect();
+    let image = match argv.first() {
+        Some(&name) => elf::embedded::find(name),
+        None => None,
+    };
+    let image = match image {
+        Some(image) => image,
+        None => {
+            let names: Vec<&str> = elf::embedded::names().collect();
+            println!("usage: run <program> [args], programs: {}", names.join(" "));
+            return;
+        }
+    };
+    let pid = match process::spawn(None, argv[0], image, &argv, &[]) {
+        Ok(pid) => pid,
+        Err(error) => {
+            println!("cannot load {}: {:?}", argv[0], error);
+            return;
+        }
+    };
+    println!("started process {}", pid.as_u64());
+
+    // The shell keeps reading commands, e.g. to `kill` the program, while
+    // a thread of its own waits for it
+    thread::spawn("run", move || match process::waitpid(pid) {
+        Ok(exit) => println!("process {} ended: {:?}", pid.as_u64(), exit),
+        Err(error) => println!("cannot wait for process {}: {:?}", pid.as_u64(), error),
+    });
+}
+
+fn shutdown(_args: &str) {
+    power::shutdown();
+}
+
+fn threads(_args: &str) {
+    println!("{:>4} {:<12} {}", "ID", "NAME", "STATE");
+    for thread in thread::list() {
+        println!(
+            "{:>4} {:<12} {:?}",
+            thread.id.as_u64(),
+            thread.name,
+            thread.state
+        );
+    }
+}
+
+fn top(_args: &str) {
+    println!(
+        "{:>4} {:<12} {:<6} {:<8} {:>8} {:>12} {:>12} {:>4}",
+        "ID", "NAME", "PRIO", "STATE", "POLLS", "AVG CYCLES", "MAX CYCLES", "LONG"
+    );
+    for task in task_stats::list() {
+        let average = task.poll_cycles.checked_div(task.polls).unwrap_or(0);
+        println!(
+            "{:>4} {:<12} {:<6} {:<8} {:>8} {:>12} {:>12} {:>4}",
+            task.id,
+            task.name.unwrap_or("-"),
+            task.priority,
+            task.state,
+            task.polls,
+            average,
+            task.max_poll_cycles,
+            task.long_polls
+        );
+    }
+}
+
+fn uptime(_args: &str) {
+    let ms = time::uptime_ms();
+    println!(
+        "up {}.{:03}s ({} ticks)",
+        ms / 1000,
+        ms % 1000,
+        time::ticks()
+    );
+}
@@ -0,0 +1,209 @@
+// Symmetric multiprocessing
+//
+// The boot CPU finds the other CPUs, the application processors (APs),
+// in the ACPI MADT and starts them one after another with the usual
+// INIT-SIPI-SIPI sequence. Every AP gets a stack, per-CPU data, a GDT
+// and a TSS of its own, loads the shared IDT and then runs the tasks
+// spawned with `task::multicore::spawn`. CPUs can also have each other
+// run functions, see `call_on`.
+//
+// Kernel threads only run on the boot CPU for now.
+
+use crate::interrupts::InterruptIndex;
+use crate::task::multicore;
+use crate::{acpi, apic, gdt, info, interrupts, per_cpu, percpu, time, usermode, warn};
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+mod call;
+mod trampoline;
+
+pub(crate) use call::handle_requests;
+pub use call::{call_on, call_on_all, call_on_others};
+
+// CPUs beyond this are not started
+pub const MAX_CPUS: usize = 16;
+// Size of the stack each AP runs its executor on
+const AP_STACK_SIZE: usize = 16 * 1024;
+
+// Timer ticks to wait for an AP after each startup IPI
+const STARTUP_TIMEOUT_TICKS: u64 = 2;
+// Timer ticks to wait for an AP after the last startup IPI
+const FINAL_STARTUP_TIMEOUT_TICKS: u64 = 100;
+
+// See `stats::ZERO` in the interrupts module
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU8 = AtomicU8::new(0);
+
+// APIC IDs of the CPUs, indexed by the number we give them. The boot
+// CPU is always CPU 0.
+static APIC_IDS: [AtomicU8; MAX_CPUS] = [ZERO; MAX_CPUS];
+// Number of CPUs started or being started, including the boot CPU
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+// Number of CPUs that finished their initialisation
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+per_cpu! {
+    // Whether the CPU is halted, or about to, waiting for work
+    static HALTED: AtomicBool = AtomicBool::new(false);
+}
+
+// Starts all other CPUs listed in the MADT. Needs `memory::install` to
+// have been called and interrupts to be enabled, and must only be
+// called once.
+pub fn init() {
+    apic::init();
+    let boot_apic_id = apic::id();
+    APIC_IDS[0].store(boot_apic_id, Ordering::Relaxed);
+    multicore::init_cpu(0);
+
+    let cpus = match acpi::local_apics() {
+        Some(cpus) => cpus,
+        None => {
+            warn!("no MADT found, only using the boot CPU");
+            return;
+        }
+    };
+    if cpus.len() > MAX_CPUS {
+        warn!("only starting {} of {} CPUs", MAX_CPUS, cpus.len());
+    }
+    let aps: Vec<u8> = cpus
+        .iter()
+        .map(|cpu| cpu.apic_id)
+        .filter(|&apic_id| apic_id != boot_apic_id)
+        .take(MAX_CPUS - 1)
+        .collect();
+
+    if !aps.is_empty() {
+        let mapped = unsafe { trampoline::install() };
+        for apic_id in aps {
+            start_ap(apic_id);
+        }
+        trampoline::remove(mapped);
+    }
+    info!("{} of {} CPUs online", online_cpus(), cpus.len());
+}
+
+// Starts the AP with the given APIC ID and waits until it is initialised
+fn start_ap(apic_id: u8) {
+    let cpu = CPU_COUNT.load(Ordering::Relaxed);
+    let stack = Box::leak(alloc::vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+    trampoline::set_parameters(stack_top, ap_main, cpu as u64);
+
+    multicore::init_cpu(cpu);
+    APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+    CPU_COUNT.store(cpu + 1, Ordering::SeqCst);
+    let online = ONLINE.load(Ordering::SeqCst);
+    let started = || ONLINE.load(Ordering::SeqCst) > online;
+
+    // The AP needs 10ms after the INIT to get ready for the startup IPI.
+    // The second startup IPI is ignored by an AP that is running already.
+    apic::send_init(apic_id);
+    wait_for(STARTUP_TIMEOUT_TICKS, || false);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, trampoline::PAGE);
+        if wait_for(STARTUP_TIMEOUT_TICKS, started) {
+            return;
+        }
+    }
+    if !wait_for(FINAL_STARTUP_TIMEOUT_TICKS, started) {
+        warn!("CPU with APIC ID {} did not start", apic_id);
+        CPU_COUNT.store(cpu, Ordering::SeqCst);
+    }
+}
+
+// Waits until `condition` holds, for at least `ticks` full timer ticks.
+// Returns whether it holds.
+fn wait_for(ticks: u64, condition: impl Fn() -> bool) -> bool {
+    // The current tick might be almost over
+    let deadline = time::ticks() + ticks + 1;
+    while time::ticks() < deadline {
+        if condition() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    condition()
+}
+
+// Where the trampoline leaves each AP, with interrupts disabled
+extern "C" fn ap_main(cpu: u64) -> ! {
+    percpu::init(cpu as usize);
+    gdt::init();
+    usermode::init();
+    interrupts::init_idt();
+    apic::enable();
+    // Nothing else wakes up the CPU when its executor is idle
+    apic::start_timer();
+
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+
+    x86_64::instructions::interrupts::enable();
+    multicore::run();
+}
+
+// Number of the calling CPU, between 0 and `cpu_count()`
+pub fn current_cpu() -> usize {
+    percpu::cpu_id()
+}
+
+fn apic_id(cpu: usize) -> u8 {
+    APIC_IDS[cpu].load(Ordering::Relaxed)
+}
+
+// Whether the caller runs on the CPU that booted the kernel
+pub fn is_boot_cpu() -> bool {
+    current_cpu() == 0
+}
+
+// Number of CPUs that were started, including the boot CPU
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+// Number of CPUs that are up and running their executors
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+// Halts the calling CPU until the next interrupt, unless `has_work`
+// says that there is something to do. Other CPUs that give this CPU
+// something to do have to call `wake` for it afterwards.
+//
+// Must be called with interrupts disabled, returns with them enabled.
+pub fn halt_unless(has_work: impl FnOnce() -> bool) {
+    use x86_64::instructions::interrupts;
+
+    let halted = HALTED.get();
+    // Set before looking for work, so that whoever makes work available
+    // after we looked is sure to see it set and wake us up
+    halted.store(true, Ordering::SeqCst);
+    if has_work() {
+        halted.store(false, Ordering::SeqCst);
+        interrupts::enable();
+    } else {
+        // A wakeup sent in between is only delivered after `sti`,
+        // and thus ends the `hlt` right away
+        interrupts::enable_and_hlt();
+        halted.store(false, Ordering::SeqCst);
+    }
+}
+
+// Makes sure that CPU number `cpu` notices new work, by interrupting it
+// if it is halted
+pub fn wake(cpu: usize) {
+    if cpu != current_cpu() && HALTED.get_for(cpu).load(Ordering::SeqCst) {
+        apic::send_ipi(apic_id(cpu), InterruptIndex::Wakeup.as_u8());
+    }
+}
+
+// Wakes up one halted CPU other than the calling one, if there is one
+pub fn wake_any() {
+    let current = current_cpu();
+    let halted = (0..online_cpus())
+        .find(|&cpu| cpu != current && HALTED.get_for(cpu).load(Ordering::SeqCst));
+    if let Some(cpu) = halted {
+        wake(cpu);
+    }
+}
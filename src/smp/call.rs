@@ -0,0 +1,93 @@
+// Running functions on other CPUs
+//
+// Each CPU has a list of requests that other CPUs add to before sending
+// it an `InterruptIndex::Call` IPI. The interrupt handler runs them and
+// counts them as done, while the caller waits for all of them to be done.
+//
+// A CPU waiting for its requests keeps handling requests sent to itself,
+// so that two CPUs calling each other at the same time, possibly with
+// interrupts disabled, do not wait for each other forever.
+
+use super::{apic_id, current_cpu, online_cpus};
+use crate::interrupts::InterruptIndex;
+use crate::{apic, per_cpu};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+type Function = Arc<dyn Fn() + Send + Sync>;
+
+struct Request {
+    function: Function,
+    // Number of CPUs that did not run the function yet
+    remaining: Arc<AtomicUsize>,
+}
+
+per_cpu! {
+    static REQUESTS: spin::Mutex<Vec<Request>> = spin::Mutex::new(Vec::new());
+}
+
+// Runs `f` on CPU number `cpu` and waits until it returned
+pub fn call_on<F>(cpu: usize, f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    assert!(cpu < online_cpus(), "CPU {} is not online", cpu);
+    if cpu == current_cpu() {
+        f();
+    } else {
+        call(Arc::new(f), core::iter::once(cpu));
+    }
+}
+
+// Runs `f` on every CPU except the calling one and waits until it
+// returned everywhere
+pub fn call_on_others<F>(f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let current = current_cpu();
+    call(
+        Arc::new(f),
+        (0..online_cpus()).filter(|&cpu| cpu != current),
+    );
+}
+
+// Runs `f` on every CPU, starting with the calling one
+pub fn call_on_all<F>(f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let f: Function = Arc::new(f);
+    f();
+    let current = current_cpu();
+    call(f, (0..online_cpus()).filter(|&cpu| cpu != current));
+}
+
+fn call(function: Function, cpus: impl Iterator<Item = usize> + Clone) {
+    let remaining = Arc::new(AtomicUsize::new(cpus.clone().count()));
+
+    for cpu in cpus {
+        let request = Request {
+            function: function.clone(),
+            remaining: remaining.clone(),
+        };
+        interrupts::without_interrupts(|| REQUESTS.get_for(cpu).lock().push(request));
+        apic::send_ipi(apic_id(cpu), InterruptIndex::Call.as_u8());
+    }
+
+    while remaining.load(Ordering::Acquire) > 0 {
+        handle_requests();
+        core::hint::spin_loop();
+    }
+}
+
+// Runs the functions other CPUs asked the calling CPU to run. Called by
+// the interrupt handler for `InterruptIndex::Call`.
+pub(crate) fn handle_requests() {
+    let requests = interrupts::without_interrupts(|| core::mem::take(&mut *REQUESTS.get().lock()));
+    for request in requests {
+        (request.function)();
+        request.remaining.fetch_sub(1, Ordering::Release);
+    }
+}
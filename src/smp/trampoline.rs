@@ -0,0 +1,190 @@
+// Where application processors start
+//
+// A startup IPI starts a CPU in real mode at the beginning of a page below
+// 1 MiB, so the trampoline is copied to `ADDRESS` before any AP is
+// started. From there it switches to protected mode with a temporary GDT,
+// enables long mode and paging with the boot CPU's page tables and calls
+// the entry point passed in `Parameters` on the stack passed with it.
+//
+// The bootloader is loaded at 0x7c00 and the memory behind it is marked
+// as used by the bootloader in the memory map, so the frame allocator
+// never hands out the trampoline's page. Once the kernel runs, nothing
+// else uses that memory anymore.
+//
+// Until paging is enabled, the code runs at physical addresses, which is
+// why every address in it is computed relative to `ADDRESS`. The page is
+// identity mapped while APs are started, so that execution can continue
+// at the same addresses afterwards.
+
+use crate::memory;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Page, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Physical address the trampoline is copied to. Has to match the
+// `.set` in the assembly below.
+const ADDRESS: u64 = 0x8000;
+// Number of the trampoline's page, as sent with the startup IPI
+pub(super) const PAGE: u8 = (ADDRESS >> 12) as u8;
+
+// Filled in by the boot CPU before starting each AP
+#[repr(C)]
+struct Parameters {
+    // Physical address of the level 4 page table, must be below 4 GiB
+    cr3: u64,
+    // Bits to set in the EFER
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_parameters: u8;
+    static ap_trampoline_end: u8;
+}
+
+core::arch::global_asm!(
+    r#"
+.pushsection .text.ap_trampoline, "ax"
+.set AP_TRAMPOLINE_ADDRESS, 0x8000
+
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    xorw %ax, %ax
+    movw %ax, %ds
+    lgdtl (ap_gdt_pointer - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS)
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl $0x08, $(ap_protected_mode - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS)
+
+.code32
+ap_protected_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    # Physical address extension, required for long mode
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (ap_parameter_cr3 - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS), %eax
+    movl %eax, %cr3
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl (ap_parameter_efer - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS), %eax
+    wrmsr
+    # Paging and write protection
+    movl %cr0, %eax
+    orl $((1 << 31) | (1 << 16)), %eax
+    movl %eax, %cr0
+    ljmpl $0x18, $(ap_long_mode - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS)
+
+.code64
+ap_long_mode:
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movq (ap_parameter_stack_top - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS), %rsp
+    movq (ap_parameter_argument - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS), %rdi
+    movq (ap_parameter_entry - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS), %rax
+    # Ends backtraces
+    xorq %rbp, %rbp
+    callq *%rax
+    ud2
+
+.balign 8
+ap_gdt:
+    .quad 0
+    # 32 bit code
+    .quad 0x00cf9a000000ffff
+    # 32 bit data
+    .quad 0x00cf92000000ffff
+    # 64 bit code
+    .quad 0x00af9a000000ffff
+ap_gdt_pointer:
+    .word ap_gdt_pointer - ap_gdt - 1
+    .long ap_gdt - ap_trampoline_start + AP_TRAMPOLINE_ADDRESS
+
+# Laid out like `Parameters`
+.balign 8
+.global ap_trampoline_parameters
+ap_trampoline_parameters:
+ap_parameter_cr3:
+    .quad 0
+ap_parameter_efer:
+    .quad 0
+ap_parameter_stack_top:
+    .quad 0
+ap_parameter_entry:
+    .quad 0
+ap_parameter_argument:
+    .quad 0
+
+.global ap_trampoline_end
+ap_trampoline_end:
+.popsection
+"#,
+    options(att_syntax)
+);
+
+fn virt(phys: u64) -> VirtAddr {
+    memory::physical_memory_offset().expect("memory not initialised") + phys
+}
+
+// Copies the trampoline to its page and identity maps it. Returns
+// whether the page had to be mapped, see `remove`.
+//
+// This function is unsafe because nothing may run on another
+// CPU that was started before.
+pub(super) unsafe fn install() -> bool {
+    let start = &ap_trampoline_start as *const u8;
+    let length = &ap_trampoline_end as *const u8 as usize - start as usize;
+    core::ptr::copy_nonoverlapping(start, virt(ADDRESS).as_mut_ptr::<u8>(), length);
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(ADDRESS));
+    match memory::identity_map(frame) {
+        Ok(()) => true,
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => false,
+        Err(error) => panic!("failed to map the AP trampoline: {:?}", error),
+    }
+}
+
+// Unmaps the trampoline's page again, if `install` mapped it
+pub(super) fn remove(mapped: bool) {
+    if mapped {
+        let page = Page::containing_address(VirtAddr::new(ADDRESS));
+        memory::unmap(page).expect("failed to unmap the AP trampoline");
+    }
+}
+
+// Sets what the next AP that is started will run
+pub(super) fn set_parameters(stack_top: u64, entry: extern "C" fn(u64) -> !, argument: u64) {
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    assert!(
+        cr3 < 1 << 32,
+        "level 4 page table not reachable in protected mode"
+    );
+    let efer = Efer::read() & (EferFlags::LONG_MODE_ENABLE | EferFlags::NO_EXECUTE_ENABLE);
+
+    let parameters = Parameters {
+        cr3,
+        efer: efer.bits(),
+        stack_top,
+        entry: entry as usize as u64,
+        argument,
+    };
+    unsafe {
+        let offset = &ap_trampoline_parameters as *const u8 as u64
+            - &ap_trampoline_start as *const u8 as u64;
+        core::ptr::write_volatile(virt(ADDRESS + offset).as_mut_ptr(), parameters);
+    }
+}
@@ -0,0 +1,150 @@
+use core::str;
+
+// Reserved space for the kernel symbol table. The kernel is linked with
+// this section zeroed, and `scripts/embed_symbols.py` fills it in from the
+// ELF's own symbol table after linking. Since the size of the section does
+// not change, no addresses move when it is filled in.
+//
+// Layout (all integers little endian):
+//   magic "KSYM" | entry count: u32 | string table offset: u32
+//   entries sorted by address, each:
+//     address: u64 | size: u32 | name offset: u32 | name length: u32
+//   string table with the (demangled) names
+pub const KSYMS_CAPACITY: usize = 512 * 1024;
+
+const KSYMS_MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 20;
+
+// `static mut` so that the compiler cannot assume the contents are all
+// zeros and constant fold our lookups away
+#[used]
+#[link_section = ".ksyms"]
+static mut KSYMS: [u8; KSYMS_CAPACITY] = [0; KSYMS_CAPACITY];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    // Start address of the symbol
+    pub address: u64,
+    // Offset of the looked up address from the start of the symbol
+    pub offset: u64,
+}
+
+struct Table {
+    entries: &'static [u8],
+    strings: &'static [u8],
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+// Returns the symbol table, or `None` if it was never filled in
+fn table() -> Option<Table> {
+    // The table is only ever written before the kernel starts
+    let ksyms: &'static [u8] = unsafe { &*core::ptr::addr_of!(KSYMS) };
+
+    if &ksyms[0..4] != KSYMS_MAGIC {
+        return None;
+    }
+
+    let count = read_u32(ksyms, 4) as usize;
+    let strings_start = read_u32(ksyms, 8) as usize;
+    let entries_end = HEADER_SIZE + count * ENTRY_SIZE;
+    if entries_end > strings_start || strings_start > KSYMS_CAPACITY {
+        return None;
+    }
+
+    Some(Table {
+        entries: &ksyms[HEADER_SIZE..entries_end],
+        strings: &ksyms[strings_start..],
+    })
+}
+
+impl Table {
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    fn address(&self, index: usize) -> u64 {
+        read_u64(self.entries, index * ENTRY_SIZE)
+    }
+
+    fn symbol(&self, index: usize, addr: u64) -> Option<Symbol> {
+        let entry = index * ENTRY_SIZE;
+        let address = read_u64(self.entries, entry);
+        let size = u64::from(read_u32(self.entries, entry + 8));
+        let name_start = read_u32(self.entries, entry + 12) as usize;
+        let name_end = name_start + read_u32(self.entries, entry + 16) as usize;
+
+        // Symbols without a size (e.g. from assembly) cover
+        // everything up to the next symbol
+        if size != 0 && addr >= address + size {
+            return None;
+        }
+
+        let name = str::from_utf8(self.strings.get(name_start..name_end)?).ok()?;
+        Some(Symbol {
+            name,
+            address,
+            offset: addr - address,
+        })
+    }
+}
+
+// Returns whether a symbol table was embedded into the kernel image
+pub fn available() -> bool {
+    table().is_some()
+}
+
+// Looks up the function containing the given address
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    let table = table()?;
+
+    // Binary search for the last symbol starting at or before `addr`
+    let (mut low, mut high) = (0, table.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if table.address(mid) <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    match low {
+        0 => None,
+        index => table.symbol(index - 1, addr),
+    }
+}
+
+// Kernels built without `scripts/embed_symbols.py` have no table to test
+#[test_case]
+fn test_resolve_finds_function() {
+    if !available() {
+        return;
+    }
+    let address = resolve as fn(u64) -> Option<Symbol> as usize as u64;
+    let symbol = resolve(address + 4).expect("resolve not found");
+    assert!(symbol.name.ends_with("symbols::resolve"));
+    assert_eq!(symbol.address, address);
+    assert_eq!(symbol.offset, 4);
+}
+
+#[test_case]
+fn test_resolve_below_first_symbol() {
+    let table = match table() {
+        Some(table) if table.len() > 0 => table,
+        _ => return,
+    };
+    assert!(resolve(table.address(0) - 1).is_none());
+}
@@ -0,0 +1,195 @@
+// System calls made by user code
+//
+// The ABI follows Linux on x86_64. The number of the system call goes in
+// rax, up to six arguments in rdi, rsi, rdx, r10, r8 and r9, and `syscall`
+// traps into the kernel. The result comes back in rax, with errors
+// returned as the negated error number, e.g. -38 (-ENOSYS) for an unknown
+// system call. All registers except rax, rcx and r11 are preserved.
+//
+//   nr  name         arguments                          returns
+//    0  read         fd, buffer, count                  bytes read, 0 at the end
+//    1  write        fd, buffer, count                  bytes written
+//    2  open         path, flags (O_*)                  file descriptor
+//    3  close        fd                                 0
+//    9  mmap         address, length, prot, flags       address of the mapping
+//   11  munmap       address, length                    0
+//   12  brk          end of the heap, 0 to query        the new end of the heap
+//   24  sched_yield                                     0
+//   35  nanosleep    request, remaining                 0
+//   39  getpid                                          PID
+//   60  exit         status                             does not return
+//
+// Reads and writes transfer at most 64 KiB per call. Descriptors 0 to 2
+// are the console, reading from it blocks for keyboard input. Paths refer
+// to the in-memory filesystem, see `fs`. `mmap` only supports anonymous
+// mappings (MAP_ANONYMOUS with MAP_PRIVATE or MAP_SHARED, MAP_FIXED is
+// honoured). `nanosleep` takes a `{ seconds, nanoseconds }` pair of 64-bit
+// integers and sets the remaining time, if asked for, to zero.
+//
+// Pointers into user memory are checked against the calling process's
+// address space, bad ones make the call fail with EFAULT, see `user`.
+
+use crate::usermode::{self, Exit};
+use crate::{process, thread};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+mod file;
+mod memory;
+pub mod user;
+
+pub use memory::{
+    MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE,
+};
+
+pub const READ: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const OPEN: u64 = 2;
+pub const CLOSE: u64 = 3;
+pub const MMAP: u64 = 9;
+pub const MUNMAP: u64 = 11;
+pub const BRK: u64 = 12;
+pub const SCHED_YIELD: u64 = 24;
+pub const NANOSLEEP: u64 = 35;
+pub const GETPID: u64 = 39;
+pub const EXIT: u64 = 60;
+
+// Size of the dispatch table, all numbers are below
+const TABLE_SIZE: usize = 64;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// User registers saved by the system call entry, see `usermode`
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct SyscallFrame {
+    // The system call number, replaced by the result
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    // Where `syscall` left the user code, and its flags
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn arguments(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    ENOENT = 2,
+    EINTR = 4,
+    EBADF = 9,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+type Handler = fn([u64; 6]) -> SyscallResult;
+
+static HANDLERS: [Option<Handler>; TABLE_SIZE] = {
+    let mut handlers: [Option<Handler>; TABLE_SIZE] = [None; TABLE_SIZE];
+    handlers[READ as usize] = Some(file::sys_read);
+    handlers[WRITE as usize] = Some(file::sys_write);
+    handlers[OPEN as usize] = Some(file::sys_open);
+    handlers[CLOSE as usize] = Some(file::sys_close);
+    handlers[MMAP as usize] = Some(memory::sys_mmap);
+    handlers[MUNMAP as usize] = Some(memory::sys_munmap);
+    handlers[BRK as usize] = Some(memory::sys_brk);
+    handlers[SCHED_YIELD as usize] = Some(sys_yield);
+    handlers[NANOSLEEP as usize] = Some(sys_nanosleep);
+    handlers[GETPID as usize] = Some(sys_getpid);
+    handlers[EXIT as usize] = Some(sys_exit);
+    handlers
+};
+
+// Called by the system call entry with interrupts disabled, on the
+// kernel stack of the calling user code
+pub(crate) fn dispatch(frame: &mut SyscallFrame) {
+    interrupts::enable();
+
+    let handler = HANDLERS.get(frame.rax as usize).copied().flatten();
+    let result = match handler {
+        Some(handler) => handler(frame.arguments()),
+        None => Err(Errno::ENOSYS),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+    // Killed while in the kernel
+    process::exit_if_killed();
+}
+
+fn sys_yield(_args: [u64; 6]) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_nanosleep(args: [u64; 6]) -> SyscallResult {
+    let [request, remaining, ..] = args;
+    let bytes = user::copy_from_user(request, 16)?;
+    let mut words = [0; 2];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(8)) {
+        let mut value = [0; 8];
+        value.copy_from_slice(chunk);
+        *word = u64::from_le_bytes(value);
+    }
+    // Both are signed, negative values end up too large
+    let [seconds, nanoseconds] = words;
+    if seconds > i64::MAX as u64 || nanoseconds >= NANOS_PER_SECOND {
+        return Err(Errno::EINVAL);
+    }
+    let duration = Duration::new(seconds, nanoseconds as u32);
+    // A killed process does not sleep on
+    if !thread::sleep_unless(duration, process::is_killed) {
+        return Err(Errno::EINTR);
+    }
+    if remaining != 0 {
+        user::copy_to_user(remaining, &[0; 16])?;
+    }
+    Ok(0)
+}
+
+// Code running in user mode without a process, e.g. through
+// `usermode::enter`, gets 0
+fn sys_getpid(_args: [u64; 6]) -> SyscallResult {
+    Ok(process::current().map_or(0, |pid| pid.as_u64()))
+}
+
+fn sys_exit(args: [u64; 6]) -> SyscallResult {
+    usermode::exit_current(Exit::Exited(args[0]))
+}
+
+#[test_case]
+fn test_unknown_syscall_fails() {
+    let mut frame = SyscallFrame {
+        rax: TABLE_SIZE as u64 + 1,
+        rdi: 0,
+        rsi: 0,
+        rdx: 0,
+        r10: 0,
+        r8: 0,
+        r9: 0,
+        rip: 0,
+        rflags: 0,
+        rsp: 0,
+    };
+    dispatch(&mut frame);
+    assert_eq!(frame.rax as i64, -(Errno::ENOSYS as i64));
+}
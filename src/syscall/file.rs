@@ -0,0 +1,58 @@
+// System calls on file descriptors
+
+use super::{user, Errno, SyscallResult};
+use crate::fs;
+use crate::process::{self, file::File};
+use alloc::sync::Arc;
+use alloc::vec;
+
+// Largest read or write done at once, more is left for the next call
+const MAX_TRANSFER: usize = 64 * 1024;
+
+fn file(fd: u64) -> Result<Arc<dyn File>, Errno> {
+    process::with_files(|files| files.get(fd as usize))
+        .flatten()
+        .ok_or(Errno::EBADF)
+}
+
+pub(super) fn sys_read(args: [u64; 6]) -> SyscallResult {
+    let [fd, buffer, count, ..] = args;
+    let count = (count as usize).min(MAX_TRANSFER);
+    let file = file(fd)?;
+    // Like on Linux, the buffer does not matter then
+    if count == 0 {
+        return Ok(0);
+    }
+    // Before reading, as the data would be lost otherwise
+    user::check(buffer, count, true)?;
+    let mut data = vec![0; count];
+    let read = file.read(&mut data)?;
+    user::copy_to_user(buffer, &data[..read])?;
+    Ok(read as u64)
+}
+
+pub(super) fn sys_write(args: [u64; 6]) -> SyscallResult {
+    let [fd, buffer, count, ..] = args;
+    let count = (count as usize).min(MAX_TRANSFER);
+    let file = file(fd)?;
+    if count == 0 {
+        return Ok(0);
+    }
+    let data = user::copy_from_user(buffer, count)?;
+    Ok(file.write(&data)? as u64)
+}
+
+pub(super) fn sys_open(args: [u64; 6]) -> SyscallResult {
+    let [path, flags, ..] = args;
+    let path = user::read_string(path, fs::MAX_PATH)?;
+    let file = fs::open(&path, flags)?;
+    let fd = process::with_files(|files| files.insert(file)).ok_or(Errno::EBADF)??;
+    Ok(fd as u64)
+}
+
+pub(super) fn sys_close(args: [u64; 6]) -> SyscallResult {
+    let removed = process::with_files(|files| files.remove(args[0] as usize));
+    // Dropped outside of the process table's lock
+    removed.ok_or(Errno::EBADF)??;
+    Ok(0)
+}
@@ -0,0 +1,62 @@
+// System calls managing user memory
+
+use super::{Errno, SyscallResult};
+use crate::process;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// Protection and flags of `mmap`, with Linux's values
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+pub(super) fn sys_mmap(args: [u64; 6]) -> SyscallResult {
+    let [address, length, protection, flags, ..] = args;
+    // Only memory that is not backed by a file, the descriptor and offset
+    // are ignored
+    let sharing = flags & (MAP_SHARED | MAP_PRIVATE);
+    if flags & MAP_ANONYMOUS == 0 || sharing == 0 || sharing == MAP_SHARED | MAP_PRIVATE {
+        return Err(Errno::EINVAL);
+    }
+    let address = if flags & MAP_FIXED != 0 {
+        Some(VirtAddr::try_new(address).map_err(|_| Errno::EINVAL)?)
+    } else {
+        None
+    };
+
+    // Mappings are always readable, also with PROT_NONE
+    let mut page_flags = PageTableFlags::empty();
+    if protection & PROT_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let memory = process::memory().ok_or(Errno::ENOMEM)?;
+    let start = memory.lock().map_anonymous(address, length, page_flags)?;
+    Ok(start.as_u64())
+}
+
+pub(super) fn sys_munmap(args: [u64; 6]) -> SyscallResult {
+    let [address, length, ..] = args;
+    let address = VirtAddr::try_new(address).map_err(|_| Errno::EINVAL)?;
+    let memory = process::memory().ok_or(Errno::EINVAL)?;
+    memory.lock().unmap(address, length)?;
+    Ok(0)
+}
+
+// Returns the new program break, or the current one if it could not be
+// moved, e.g. for the usual query with 0
+pub(super) fn sys_brk(args: [u64; 6]) -> SyscallResult {
+    let memory = process::memory().ok_or(Errno::ENOMEM)?;
+    let mut memory = memory.lock();
+    match VirtAddr::try_new(args[0]) {
+        Ok(end) => Ok(memory.set_program_break(end).as_u64()),
+        Err(_) => Ok(memory.program_break().as_u64()),
+    }
+}
@@ -0,0 +1,94 @@
+// Access to user memory from system calls
+//
+// Pointers passed by user code are never dereferenced directly. They are
+// checked against the address space of the calling process and copied
+// through the kernel's mapping of physical memory, so that a bad pointer
+// makes the system call fail with EFAULT instead of faulting the kernel.
+
+use super::Errno;
+use crate::memory::address_space::{USER_END, USER_START};
+use crate::process;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+// Makes sure the range is in the user range before it becomes a
+// `VirtAddr`, which must be canonical
+fn user_range(address: u64, length: usize) -> Result<VirtAddr, Errno> {
+    match address.checked_add(length as u64) {
+        Some(end) if address >= USER_START && end <= USER_END => Ok(VirtAddr::new(address)),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+// Fails unless the calling process may access all `length` bytes at
+// `address`, for writing if `writable` is set
+pub fn check(address: u64, length: usize, writable: bool) -> Result<(), Errno> {
+    let start = user_range(address, length)?;
+    let memory = process::memory().ok_or(Errno::EFAULT)?;
+    let memory = memory.lock();
+    if memory
+        .address_space()
+        .is_accessible(start, length, writable)
+    {
+        Ok(())
+    } else {
+        Err(Errno::EFAULT)
+    }
+}
+
+pub fn copy_from_user(address: u64, length: usize) -> Result<Vec<u8>, Errno> {
+    let start = user_range(address, length)?;
+    let memory = process::memory().ok_or(Errno::EFAULT)?;
+    let memory = memory.lock();
+    let address_space = memory.address_space();
+    if !address_space.is_accessible(start, length, false) {
+        return Err(Errno::EFAULT);
+    }
+    let mut bytes = vec![0; length];
+    address_space
+        .read(start, &mut bytes)
+        .map_err(|_| Errno::EFAULT)?;
+    Ok(bytes)
+}
+
+pub fn copy_to_user(address: u64, bytes: &[u8]) -> Result<(), Errno> {
+    let start = user_range(address, bytes.len())?;
+    let memory = process::memory().ok_or(Errno::EFAULT)?;
+    let mut memory = memory.lock();
+    if !memory
+        .address_space()
+        .is_accessible(start, bytes.len(), true)
+    {
+        return Err(Errno::EFAULT);
+    }
+    memory
+        .address_space_mut()
+        .write(start, bytes)
+        .map_err(|_| Errno::EFAULT)
+}
+
+// Reads a zero-terminated UTF-8 string of at most `max_length` bytes,
+// without the terminator
+pub fn read_string(address: u64, max_length: usize) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    let mut next = address;
+    loop {
+        // Up to the end of the page, so that the page after the terminator
+        // need not be mapped
+        let chunk = (4096 - (next % 4096) as usize).min(max_length + 1 - bytes.len());
+        let data = copy_from_user(next, chunk)?;
+        match data.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&data[..end]);
+                return String::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+            }
+            None if bytes.len() + chunk > max_length => return Err(Errno::ENAMETOOLONG),
+            None => {
+                bytes.extend_from_slice(&data);
+                next += chunk as u64;
+            }
+        }
+    }
+}
@@ -0,0 +1,19 @@
+// Channels for passing values between tasks
+//
+// - `mpsc`: many senders, one receiver, bounded or unbounded. The bounded
+//   variant never allocates when sending, so interrupt handlers can feed
+//   tasks through an `mpsc::IrqSender`.
+// - `oneshot`: a single value from one sender to one receiver.
+// - `broadcast`: every value is delivered to every receiver.
+//
+// Receivers implement `Stream` and senders implement `Sink`, so the
+// combinators of `futures_util` can be used on them.
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+// Returned when sending fails because the receiving side is gone.
+// Contains the value that could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
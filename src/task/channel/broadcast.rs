@@ -0,0 +1,256 @@
+use super::SendError;
+use crate::task::sync::with_state;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::future::poll_fn;
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+
+// Creates a channel that delivers every value to every receiver
+//
+// The channel keeps the last `capacity` values. A receiver that falls
+// further behind misses the oldest values and learns about it through
+// `RecvError::Lagged`. New receivers are created with `Sender::subscribe`
+// and only see values sent after subscribing.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must be positive");
+
+    let shared = Arc::new(Shared {
+        capacity,
+        state: spin::Mutex::new(State {
+            values: VecDeque::with_capacity(capacity),
+            next_seq: 0,
+            senders: 1,
+            receivers: 0,
+            wakers: BTreeMap::new(),
+            next_receiver_id: 0,
+        }),
+    });
+    let receiver = Receiver::new(shared.clone());
+    (Sender { shared }, receiver)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    // All senders are gone and every value was received
+    Closed,
+    // The receiver fell behind and missed this many values
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+struct Shared<T> {
+    capacity: usize,
+    state: spin::Mutex<State<T>>,
+}
+
+struct State<T> {
+    // The most recent values, the last one has sequence number `next_seq - 1`
+    values: VecDeque<T>,
+    next_seq: u64,
+    senders: usize,
+    receivers: usize,
+    // Receivers waiting for the next value
+    wakers: BTreeMap<u64, Waker>,
+    next_receiver_id: u64,
+}
+
+impl<T> State<T> {
+    fn first_seq(&self) -> u64 {
+        self.next_seq - self.values.len() as u64
+    }
+
+    fn wake_receivers(&mut self) -> BTreeMap<u64, Waker> {
+        core::mem::take(&mut self.wakers)
+    }
+}
+
+fn wake_all(wakers: BTreeMap<u64, Waker>) {
+    wakers.into_values().for_each(Waker::wake);
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    // Sends `value` to all current receivers, returning how many there
+    // are. Never waits: if the channel is full, the oldest value is dropped.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let capacity = self.shared.capacity;
+        let result = with_state(&self.shared.state, |state| {
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if state.values.len() == capacity {
+                state.values.pop_front();
+            }
+            state.values.push_back(value);
+            state.next_seq += 1;
+            Ok((state.receivers, state.wake_receivers()))
+        });
+
+        result.map(|(receivers, wakers)| {
+            wake_all(wakers);
+            receivers
+        })
+    }
+
+    pub fn subscribe(&self) -> Receiver<T> {
+        Receiver::new(self.shared.clone())
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        with_state(&self.shared.state, |state| state.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        with_state(&self.shared.state, |state| state.senders += 1);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let wakers = with_state(&self.shared.state, |state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                // Let the receivers see that the channel is closed
+                state.wake_receivers()
+            } else {
+                BTreeMap::new()
+            }
+        });
+        wake_all(wakers);
+    }
+}
+
+impl<T: Clone> Sink<T> for Sender<T> {
+    type Error = SendError<T>;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), SendError<T>> {
+        self.send(item).map(|_| ())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    id: u64,
+    // Sequence number of the next value to receive
+    next_seq: u64,
+}
+
+impl<T> Receiver<T> {
+    fn new(shared: Arc<Shared<T>>) -> Self {
+        let (id, next_seq) = with_state(&shared.state, |state| {
+            state.receivers += 1;
+            state.next_receiver_id += 1;
+            (state.next_receiver_id - 1, state.next_seq)
+        });
+        Receiver {
+            shared,
+            id,
+            next_seq,
+        }
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let next_seq = &mut self.next_seq;
+        with_state(&self.shared.state, |state| {
+            let first_seq = state.first_seq();
+            if *next_seq < first_seq {
+                let missed = first_seq - *next_seq;
+                *next_seq = first_seq;
+                return Err(TryRecvError::Lagged(missed));
+            }
+
+            match state.values.get((*next_seq - first_seq) as usize) {
+                Some(value) => {
+                    *next_seq += 1;
+                    Ok(value.clone())
+                }
+                None if state.senders == 0 => Err(TryRecvError::Closed),
+                None => Err(TryRecvError::Empty),
+            }
+        })
+    }
+
+    // Waits for the next value
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let result = match self.try_recv() {
+            Err(TryRecvError::Empty) => {
+                // Register under the lock, so that no value can be
+                // sent between checking and registering
+                let id = self.id;
+                with_state(&self.shared.state, |state| {
+                    state.wakers.insert(id, cx.waker().clone());
+                });
+                self.try_recv()
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+            Err(TryRecvError::Lagged(missed)) => Poll::Ready(Err(RecvError::Lagged(missed))),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let id = self.id;
+        with_state(&self.shared.state, |state| {
+            state.receivers -= 1;
+            state.wakers.remove(&id);
+        });
+    }
+}
+
+// Yields `Err(RecvError::Lagged(_))` when values were missed and ends
+// once the channel is closed
+impl<T: Clone> Stream for Receiver<T> {
+    type Item = Result<T, RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<T, RecvError>>> {
+        match self.get_mut().poll_recv(cx) {
+            Poll::Ready(Err(RecvError::Closed)) => Poll::Ready(None),
+            Poll::Ready(result) => Poll::Ready(Some(result)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use super::SendError;
+use crate::task::sync::with_state;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::{ArrayQueue, PushError, SegQueue};
+use futures_util::future::poll_fn;
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+// Creates a channel that holds up to `capacity` values. Senders wait
+// while it is full. Its storage is allocated up front.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel capacity must be positive");
+    let chan = Chan::new(Queue::Bounded(ArrayQueue::new(capacity)));
+    (Sender::new(chan.clone()), Receiver { chan })
+}
+
+// Creates a channel without a limit on the number of queued values
+pub fn unbounded<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(Queue::Unbounded(SegQueue::new()));
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+enum Queue<T> {
+    Bounded(ArrayQueue<T>),
+    Unbounded(SegQueue<T>),
+}
+
+// State shared by both sides of a channel
+struct Chan<T> {
+    queue: Queue<T>,
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+    receiver_waker: AtomicWaker,
+    // Senders waiting for space in a full bounded channel
+    sender_wakers: spin::Mutex<Vec<Waker>>,
+}
+
+impl<T> Chan<T> {
+    fn new(queue: Queue<T>) -> Arc<Self> {
+        Arc::new(Chan {
+            queue,
+            senders: AtomicUsize::new(1),
+            receiver_closed: AtomicBool::new(false),
+            receiver_waker: AtomicWaker::new(),
+            sender_wakers: spin::Mutex::new(Vec::new()),
+        })
+    }
+
+    // Lock-free and allocation-free for bounded channels
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.receiver_closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(value));
+        }
+
+        match &self.queue {
+            Queue::Bounded(queue) => {
+                if let Err(PushError(value)) = queue.push(value) {
+                    return Err(TrySendError::Full(value));
+                }
+            }
+            Queue::Unbounded(queue) => queue.push(value),
+        }
+        self.receiver_waker.wake();
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<T> {
+        let value = match &self.queue {
+            Queue::Bounded(queue) => queue.pop().ok(),
+            Queue::Unbounded(queue) => queue.pop().ok(),
+        };
+        if value.is_some() {
+            if let Queue::Bounded(_) = self.queue {
+                self.wake_senders();
+            }
+        }
+        value
+    }
+
+    fn is_full(&self) -> bool {
+        match &self.queue {
+            Queue::Bounded(queue) => queue.is_full(),
+            Queue::Unbounded(_) => false,
+        }
+    }
+
+    fn wake_senders(&self) {
+        let wakers = with_state(&self.sender_wakers, core::mem::take);
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    // Ready once there is room for a value or the receiver is gone
+    fn poll_space(&self, cx: &mut Context) -> Poll<()> {
+        if !self.is_full() || self.receiver_closed.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+
+        with_state(&self.sender_wakers, |wakers| {
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        });
+
+        // Check again in case the receiver made room in the meantime
+        if !self.is_full() || self.receiver_closed.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn add_sender(self: &Arc<Self>) -> Arc<Self> {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+        self.clone()
+    }
+
+    fn drop_sender(&self) {
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Let the receiver see that the channel is closed
+            self.receiver_waker.wake();
+        }
+    }
+}
+
+// Sending half of a bounded channel
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+    // Value passed to `Sink::start_send` that did not fit yet
+    buffered: Option<T>,
+}
+
+impl<T> Sender<T> {
+    fn new(chan: Arc<Chan<T>>) -> Self {
+        Sender {
+            chan,
+            buffered: None,
+        }
+    }
+
+    // Waits until there is room in the channel, then sends `value`
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = value;
+        loop {
+            match self.chan.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(rejected)) => return Err(SendError(rejected)),
+                Err(TrySendError::Full(rejected)) => value = rejected,
+            }
+            poll_fn(|cx| self.chan.poll_space(cx)).await;
+        }
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+
+    // Creates a sender for use in interrupt handlers
+    pub fn irq_sender(&self) -> IrqSender<T> {
+        IrqSender {
+            chan: self.chan.add_sender(),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.receiver_closed.load(Ordering::Acquire)
+    }
+
+    // Tries to move the buffered value into the channel
+    fn poll_flush_buffered(&mut self, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        while let Some(value) = self.buffered.take() {
+            match self.chan.try_send(value) {
+                Ok(()) => {}
+                Err(TrySendError::Disconnected(value)) => {
+                    return Poll::Ready(Err(SendError(value)))
+                }
+                Err(TrySendError::Full(value)) => {
+                    self.buffered = Some(value);
+                    if self.chan.poll_space(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender::new(self.chan.add_sender())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+// `Sender` is never pinned structurally
+impl<T> Unpin for Sender<T> {}
+
+impl<T> Sink<T> for Sender<T> {
+    type Error = SendError<T>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        self.get_mut().poll_flush_buffered(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), SendError<T>> {
+        let this = self.get_mut();
+        debug_assert!(this.buffered.is_none(), "start_send without poll_ready");
+        this.buffered = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        self.get_mut().poll_flush_buffered(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        self.get_mut().poll_flush_buffered(cx)
+    }
+}
+
+// Sender of a bounded channel for use in interrupt handlers: sending
+// never blocks, takes a lock or allocates, it fails if the channel is full.
+//
+// Dropping the last reference to the channel frees its memory, so an
+// `IrqSender` should not be dropped in an interrupt handler.
+pub struct IrqSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> IrqSender<T> {
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+}
+
+impl<T> Drop for IrqSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+// Sending half of an unbounded channel. Sending allocates, so unlike
+// the bounded `Sender` it is not suitable for interrupt handlers.
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.try_send(value).map_err(|error| match error {
+            TrySendError::Full(value) | TrySendError::Disconnected(value) => SendError(value),
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.receiver_closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        UnboundedSender {
+            chan: self.chan.add_sender(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> Sink<T> for UnboundedSender<T> {
+    type Error = SendError<T>;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), SendError<T>> {
+        self.send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Receiving half of a bounded or unbounded channel. As a `Stream`, it
+// ends once all senders are gone and every queued value was received.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self.chan.pop() {
+            Some(value) => Ok(value),
+            None if self.chan.senders.load(Ordering::Acquire) == 0 => {
+                // A value might have been sent right before the last sender left
+                self.chan.pop().ok_or(TryRecvError::Disconnected)
+            }
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    // Waits for the next value, `None` means that the channel is closed
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        self.chan.receiver_waker.register(cx.waker());
+
+        // Check again as a value might have been sent
+        // while we were registering the waker
+        match self.try_recv() {
+            Ok(value) => {
+                self.chan.receiver_waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    // Stops accepting new values. Values that are already queued can
+    // still be received.
+    pub fn close(&mut self) {
+        self.chan.receiver_closed.store(true, Ordering::Release);
+        self.chan.wake_senders();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
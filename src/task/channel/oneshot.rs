@@ -0,0 +1,150 @@
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+// Creates a channel for sending a single value. Sending never blocks,
+// takes a lock or allocates, so it can be done from interrupt handlers.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        state: AtomicU8::new(EMPTY),
+        value: UnsafeCell::new(None),
+        receiver_waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+// Returned by the receiver if the sender was dropped without sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+// Nothing happened yet
+const EMPTY: u8 = 0;
+// The sender is writing the value
+const SENDING: u8 = 1;
+// The value can be taken by the receiver
+const SENT: u8 = 2;
+// The sender was dropped without sending
+const SENDER_DROPPED: u8 = 3;
+// The receiver was dropped, or it took the value
+const CLOSED: u8 = 4;
+
+struct Inner<T> {
+    state: AtomicU8,
+    value: UnsafeCell<Option<T>>,
+    receiver_waker: AtomicWaker,
+}
+
+// `value` is only written by the sender while in `SENDING` and only
+// read by the receiver once in `SENT`
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    // Fails, returning the value, if the receiver is gone
+    pub fn send(self, value: T) -> Result<(), T> {
+        let inner = &self.inner;
+        if inner
+            .state
+            .compare_exchange(EMPTY, SENDING, Ordering::Acquire, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(value);
+        }
+
+        unsafe { *inner.value.get() = Some(value) };
+        inner.state.store(SENT, Ordering::Release);
+        inner.receiver_waker.wake();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.load(Ordering::Acquire) == CLOSED
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Fails if we sent something or the receiver is gone already
+        if self
+            .inner
+            .state
+            .compare_exchange(EMPTY, SENDER_DROPPED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            self.inner.receiver_waker.wake();
+        }
+    }
+}
+
+// Future resolving to the value sent by the `Sender`
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    // Returns `Ok(None)` if no value was sent yet
+    pub fn try_recv(&mut self) -> Result<Option<T>, RecvError> {
+        let inner = &self.inner;
+        match inner.state.load(Ordering::Acquire) {
+            SENT => {
+                let value = unsafe { (*inner.value.get()).take() };
+                inner.state.store(CLOSED, Ordering::Release);
+                Ok(value)
+            }
+            EMPTY | SENDING => Ok(None),
+            // Either the sender is gone or we already took the value
+            _ => Err(RecvError),
+        }
+    }
+
+    // Prevents the sender from sending. A value that was
+    // already sent can still be received.
+    pub fn close(&mut self) {
+        let _ =
+            self.inner
+                .state
+                .compare_exchange(EMPTY, CLOSED, Ordering::AcqRel, Ordering::Acquire);
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+        // A value that was sent but never received is dropped with `Inner`
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let this = self.get_mut();
+        if let Some(value) = this.try_recv()? {
+            return Poll::Ready(Ok(value));
+        }
+
+        this.inner.receiver_waker.register(cx.waker());
+
+        // Check again in case the value was sent
+        // while we were registering the waker
+        match this.try_recv()? {
+            Some(value) => Poll::Ready(Ok(value)),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// Cooperative scheduling helpers. Since the executor cannot preempt a
+// task, long-running tasks have to give others a chance to run by
+// awaiting `yield_now` or `consume_budget` every now and then.
+
+use crate::per_cpu;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// Number of `consume_budget` calls a task may make per poll
+pub const POLL_BUDGET: usize = 64;
+
+per_cpu! {
+    // Budget left for the task that is currently being polled
+    static BUDGET: AtomicUsize = AtomicUsize::new(POLL_BUDGET);
+}
+
+// Called by the executor before polling a task
+pub(crate) fn reset_budget() {
+    BUDGET.get().store(POLL_BUDGET, Ordering::Relaxed);
+}
+
+// Gives up the rest of the task's turn: the task is queued again
+// behind all other ready tasks of the same priority
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// Uses up one unit of the task's budget, yielding once it is exhausted.
+// Meant to be awaited in every iteration of long-running loops.
+pub async fn consume_budget() {
+    let exhausted = BUDGET
+        .get()
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |budget| {
+            budget.checked_sub(1)
+        })
+        .map_or(true, |budget| budget == 1);
+
+    if exhausted {
+        yield_now().await;
+    }
+}
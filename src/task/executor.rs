@@ -1,99 +1,361 @@
-use super::{Task, TaskId};
+use super::stats::{self, TaskStats};
+use super::{coop, join, JoinHandle, Priority, SpawnOptions, Task, TaskId};
+use crate::{smp, warn};
 use alloc::task::Wake;
-use alloc::{collections::BTreeMap, sync::Arc};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Waker};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+
+// Number of wakeups that fit into each ready queue. Every task is queued
+// at most once, so this is only exceeded with more tasks than that.
+const READY_QUEUE_CAPACITY: usize = 256;
+
+// After being passed over this many times in a row in favour of higher
+// priority tasks, the next ready task of a lower priority gets its turn
+const STARVATION_LIMIT: u32 = 8;
+
+// Futures spawned through a `Spawner`, waiting to be turned into tasks
+type SpawnQueue = SegQueue<(Pin<Box<dyn Future<Output = ()> + Send>>, SpawnOptions)>;
 
 // Wakers will push IDs of woken tasks to the queue,
 // meanwhile the Executor will consume the IDs and
 // retrieves the woken tasks by their IDs from `tasks`
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    ready_queue: Arc<ReadyQueue>,
+    waker_cache: BTreeMap<TaskId, CachedWaker>,
+    spawn_queue: Arc<SpawnQueue>,
+    // How often each priority had ready tasks but was passed over
+    passed_over: [u32; Priority::COUNT],
+}
+
+// Queues of woken tasks, one per priority, filled by wakers (possibly
+// from interrupt handlers) and drained by the executor
+//
+// The queues have a fixed size so that waking never allocates. If one is
+// full, the wakeup is not lost: the task stays marked as queued in its
+// waker and `overflowed` tells the executor to look for such tasks.
+struct ReadyQueue {
+    queues: [ArrayQueue<TaskId>; Priority::COUNT],
+    overflowed: AtomicBool,
+    overflow_count: AtomicU64,
+    // The CPU running the executor, woken up by wakeups from other CPUs
+    cpu: AtomicUsize,
+}
+
+struct CachedWaker {
+    state: Arc<TaskWaker>,
+    waker: Waker,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            ready_queue: Arc::new(ReadyQueue {
+                queues: [
+                    ArrayQueue::new(READY_QUEUE_CAPACITY),
+                    ArrayQueue::new(READY_QUEUE_CAPACITY),
+                    ArrayQueue::new(READY_QUEUE_CAPACITY),
+                ],
+                overflowed: AtomicBool::new(false),
+                overflow_count: AtomicU64::new(0),
+                cpu: AtomicUsize::new(smp::current_cpu()),
+            }),
             waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(SegQueue::new()),
+            passed_over: [0; Priority::COUNT],
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    // Returns a handle that can spawn tasks onto this executor while
+    // it is running, e.g. from within its own tasks
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+            ready_queue: self.ready_queue.clone(),
+        }
+    }
+
+    // Spawns `future` as a new task. The returned handle can be awaited
+    // for the task's output, or dropped to let the task run detached.
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        self.spawn_with(future, SpawnOptions::default())
+    }
+
+    pub fn spawn_with_priority<F>(&mut self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let options = SpawnOptions {
+            priority,
+            ..SpawnOptions::default()
+        };
+        self.spawn_with(future, options)
+    }
+
+    pub fn spawn_with<F>(&mut self, future: F, options: SpawnOptions) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (future, handle) = join::joinable(future);
+        self.spawn_task(Task::new(future), options);
+        handle
+    }
+
+    fn spawn_task(&mut self, task: Task, options: SpawnOptions) {
         let task_id = task.id;
         if self.tasks.insert(task.id, task).is_some() {
             panic!("tasks with same ID already exists in tasks")
         }
-        self.task_queue.push(task_id).expect("queue full");
+
+        let state = Arc::new(TaskWaker {
+            task_id,
+            priority: options.priority,
+            stats: TaskStats::register(task_id, options.name, options.priority),
+            queued: AtomicBool::new(false),
+            ready_queue: self.ready_queue.clone(),
+        });
+        state.wake_task();
+        let waker = Waker::from(state.clone());
+        self.waker_cache
+            .insert(task_id, CachedWaker { state, waker });
+    }
+
+    // Number of wakeups that did not fit into the ready queue and had
+    // to be picked up by scanning all tasks instead
+    pub fn queue_overflows(&self) -> u64 {
+        self.ready_queue.overflow_count.load(Ordering::Relaxed)
     }
 
+    // Runs the executor's tasks forever. Tasks of the multi-core executor
+    // are polled in between, see `multicore`.
     pub fn run(&mut self) -> ! {
+        self.ready_queue
+            .cpu
+            .store(smp::current_cpu(), Ordering::Relaxed);
         loop {
             self.run_ready_tasks();
+            super::multicore::run_pending();
             self.sleep_if_idle();
         }
     }
 
+    // Polls tasks until none of them is ready to make progress, then
+    // returns instead of waiting for interrupts. Mostly useful for tests.
+    pub fn run_until_idle(&mut self) {
+        while !self.is_idle() {
+            self.run_ready_tasks();
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.ready_queue.queues.iter().all(|queue| queue.is_empty())
+            && !self.ready_queue.overflowed.load(Ordering::Acquire)
+            && self.spawn_queue.is_empty()
+    }
+
+    // Turns everything spawned through a `Spawner` into tasks
+    fn spawn_queued(&mut self) {
+        while let Ok((future, options)) = self.spawn_queue.pop() {
+            self.spawn_task(Task::from_pinned(future), options);
+        }
+    }
+
+    // Polls as many tasks as were ready when it was called, so that tasks
+    // that keep waking themselves cannot keep the executor busy forever
     fn run_ready_tasks(&mut self) {
+        self.spawn_queued();
+
+        let ready: usize = self
+            .ready_queue
+            .queues
+            .iter()
+            .map(|queue| queue.len())
+            .sum();
+        for _ in 0..ready {
+            match self.next_ready() {
+                Some(task_id) => self.poll_task(task_id),
+                None => break,
+            }
+        }
+
+        // Pick up the wakeups that did not fit into the queue
+        if self.ready_queue.overflowed.swap(false, Ordering::AcqRel) {
+            warn!("executor ready queue overflowed; scanning all tasks");
+            let woken: Vec<TaskId> = self
+                .waker_cache
+                .iter()
+                .filter(|(_, cached)| cached.state.queued.load(Ordering::Acquire))
+                .map(|(&task_id, _)| task_id)
+                .collect();
+            for task_id in woken {
+                self.poll_task(task_id);
+            }
+        }
+    }
+
+    // Picks the next task from the highest priority queue, unless a lower
+    // priority queue was passed over too often
+    fn next_ready(&mut self) -> Option<TaskId> {
+        let queues = &self.ready_queue.queues;
+        let passed_over = &mut self.passed_over;
+
+        let starved = (0..Priority::COUNT)
+            .find(|&level| passed_over[level] >= STARVATION_LIMIT && !queues[level].is_empty());
+        let level = starved.or_else(|| {
+            (0..Priority::COUNT)
+                .rev()
+                .find(|&level| !queues[level].is_empty())
+        })?;
+        let task_id = queues[level].pop().ok()?;
+
+        passed_over[level] = 0;
+        for lower in 0..level {
+            if !queues[lower].is_empty() {
+                passed_over[lower] += 1;
+            }
+        }
+        Some(task_id)
+    }
+
+    fn poll_task(&mut self, task_id: TaskId) {
         let Self {
-            tasks,
-            task_queue,
-            waker_cache,
+            tasks, waker_cache, ..
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // If the task is complete, we can remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
-            }
+        let (task, cached) = match (tasks.get_mut(&task_id), waker_cache.get(&task_id)) {
+            (Some(task), Some(cached)) => (task, cached),
+            _ => return, // task no longer exists
+        };
+
+        // Clear the flag before polling, so that wakeups
+        // during the poll queue the task again
+        cached.state.queued.store(false, Ordering::Release);
+
+        let mut context = Context::from_waker(&cached.waker);
+        coop::reset_budget();
+        let start = cached.state.stats.start_poll();
+        let result = task.poll(&mut context);
+        cached.state.stats.end_poll(start, result.is_ready());
+
+        if result.is_ready() {
+            // If the task is complete, we can remove it and its cached waker
+            tasks.remove(&task_id);
+            waker_cache.remove(&task_id);
         }
     }
 
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts;
 
         interrupts::disable();
 
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
+        if !self.is_idle() {
+            interrupts::enable();
+        } else if crate::thread::has_ready() {
+            // Let other threads run instead of halting the CPU for them
             interrupts::enable();
+            crate::thread::yield_now();
+        } else {
+            smp::halt_unless(|| !self.is_idle() || super::multicore::has_work());
         }
     }
 }
 
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // The remaining tasks will never run again
+        for &task_id in self.tasks.keys() {
+            stats::unregister(task_id);
+        }
+    }
+}
+
+// A cloneable handle for spawning tasks onto a running `Executor`
+//
+// Spawned futures go through a lock-free queue that the executor drains
+// before polling, so a `Spawner` can be used from tasks, interrupt
+// handlers and other CPUs alike. Like a wakeup, spawning gets a halted
+// executor going again. Note that spawning allocates, which is only
+// safe in interrupt handlers if the heap allocator is interrupt-safe.
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<SpawnQueue>,
+    // For the CPU of the executor, which may have to be woken up
+    ready_queue: Arc<ReadyQueue>,
+}
+
+impl Spawner {
+    // Spawns `future` onto the executor this handle was created from.
+    // If the executor is dropped before it gets to run the task, the
+    // returned handle yields `JoinError::Cancelled`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        self.spawn_with(future, SpawnOptions::default())
+    }
+
+    pub fn spawn_with_priority<F>(&self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        let options = SpawnOptions {
+            priority,
+            ..SpawnOptions::default()
+        };
+        self.spawn_with(future, options)
+    }
+
+    pub fn spawn_with<F>(&self, future: F, options: SpawnOptions) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        let (future, handle) = join::joinable(future);
+        self.spawn_queue.push((Box::pin(future), options));
+        smp::wake(self.ready_queue.cpu.load(Ordering::Relaxed));
+        handle
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    stats: Arc<TaskStats>,
+    // Whether the task is waiting to be polled, so that
+    // repeated wakeups only queue it once
+    queued: AtomicBool,
+    ready_queue: Arc<ReadyQueue>,
 }
 
 impl TaskWaker {
     // By adding the task ID to the queue, the executor will run it
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
-    }
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return; // already queued
+        }
+        self.stats.set_ready();
 
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+        let queue = &self.ready_queue.queues[self.priority.index()];
+        if queue.push(self.task_id).is_err() {
+            // The task stays marked as queued, the executor
+            // will find it when it scans for overflowed tasks
+            self.ready_queue
+                .overflow_count
+                .fetch_add(1, Ordering::Relaxed);
+            self.ready_queue.overflowed.store(true, Ordering::Release);
+        }
+        smp::wake(self.ready_queue.cpu.load(Ordering::Relaxed));
     }
 }
 
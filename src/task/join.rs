@@ -0,0 +1,151 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+// Why a task did not produce an output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    // The task was aborted, or dropped before it could finish
+    Cancelled,
+}
+
+enum Stage<T> {
+    Running,
+    Finished(T),
+    Cancelled,
+    // The output was already handed to the `JoinHandle`
+    Consumed,
+}
+
+// Shared between a spawned task and its `JoinHandle`
+struct JoinState<T> {
+    stage: Mutex<Stage<T>>,
+    aborted: AtomicBool,
+    // Woken once the task finishes or is cancelled
+    joiner: AtomicWaker,
+    // The waker of the task itself, so that `abort` can get
+    // the executor to poll (and thereby drop) the task
+    task: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    fn complete(&self, stage: Stage<T>) {
+        *self.stage.lock() = stage;
+        self.joiner.wake();
+    }
+}
+
+// Wraps a spawned future so that its output is stored for the `JoinHandle`
+pub(crate) struct Joinable<F: Future> {
+    future: F,
+    state: Arc<JoinState<F::Output>>,
+}
+
+// Returns the future to spawn as a task and the handle to await its output
+pub(crate) fn joinable<F: Future>(future: F) -> (Joinable<F>, JoinHandle<F::Output>) {
+    let state = Arc::new(JoinState {
+        stage: Mutex::new(Stage::Running),
+        aborted: AtomicBool::new(false),
+        joiner: AtomicWaker::new(),
+        task: AtomicWaker::new(),
+    });
+    let handle = JoinHandle {
+        state: state.clone(),
+    };
+    (Joinable { future, state }, handle)
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // Safe since `future` is never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+
+        // Finishing the task makes the executor drop it, and
+        // the wrapped future with it
+        if this.state.aborted.load(Ordering::Acquire) {
+            this.state.complete(Stage::Cancelled);
+            return Poll::Ready(());
+        }
+
+        this.state.task.register(cx.waker());
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                this.state.complete(Stage::Finished(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for Joinable<F> {
+    // The task might be dropped without finishing, e.g. together with
+    // its executor, in which case it will never produce an output
+    fn drop(&mut self) {
+        let mut stage = self.state.stage.lock();
+        if let Stage::Running = *stage {
+            *stage = Stage::Cancelled;
+            drop(stage);
+            self.state.joiner.wake();
+        }
+    }
+}
+
+// A handle to a spawned task which can be awaited for the task's output
+//
+// Dropping the handle detaches the task, i.e. it keeps running but its
+// output is discarded.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    // Cancels the task. Its future is dropped the next time the executor
+    // gets to it, and anyone awaiting the handle gets `JoinError::Cancelled`
+    // unless the task already finished.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.task.wake();
+    }
+
+    // Lets the task run to completion without anyone waiting for it
+    pub fn detach(self) {}
+
+    // Whether the task finished or was cancelled
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.state.stage.lock(), Stage::Running)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // Register before checking so that a completion in between
+        // cannot be missed
+        self.state.joiner.register(cx.waker());
+
+        let mut stage = self.state.stage.lock();
+        match core::mem::replace(&mut *stage, Stage::Consumed) {
+            Stage::Finished(output) => Poll::Ready(Ok(output)),
+            Stage::Cancelled => {
+                *stage = Stage::Cancelled;
+                Poll::Ready(Err(JoinError::Cancelled))
+            }
+            Stage::Running => {
+                *stage = Stage::Running;
+                Poll::Pending
+            }
+            Stage::Consumed => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
@@ -1,13 +1,12 @@
-use crate::{print, println};
+use super::channel::mpsc::{self, IrqSender, Receiver, TrySendError};
+use crate::{print, warn};
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
     task::{Context, Poll},
 };
-use crossbeam_queue::ArrayQueue;
 use futures_util::stream::Stream;
 use futures_util::stream::StreamExt;
-use futures_util::task::AtomicWaker;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
 // Use OnceCell to perform safe one-time initialization of
@@ -16,40 +15,42 @@ use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 // We use this over lazy_static since OnceCell has the advantage
 // of ensuring that the initialization does not happen in the
 // interrupt handler.
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-
-// Can be safely stored in a static and modifier concurrently
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODE_SENDER: OnceCell<IrqSender<u8>> = OnceCell::uninit();
 
 // pub(crate) to only limit visibility of this function to `lib.rs`
 pub(crate) fn add_scancode(scancode: u8) {
-    // We take care not to initialise the queue in this function
-    // since it is the interrupt handler that calls this function.
-    // Heap allocations should not occur while handling interrupts
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            // Inform waker that there are available scancodes
-            WAKER.wake();
+    // A process reading from the console gets the keyboard first
+    if crate::process::console::take_scancode(scancode) {
+        return;
+    }
+    // Sending on a bounded channel neither blocks nor allocates,
+    // which makes it safe to do in the interrupt handler
+    if let Ok(sender) = SCANCODE_SENDER.try_get() {
+        match sender.try_send(scancode) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("scancode queue full; dropping keyboard input")
+            }
+            // Nobody is listening for keypresses anymore
+            Err(TrySendError::Disconnected(_)) => {}
         }
     } else {
-        println!("WARNING: scancode queue uninitialised");
+        warn!("scancode queue uninitialised");
     }
 }
 
 pub struct ScancodeStream {
-    // Prevent construction from outside of the module
-    _private: (),
+    receiver: Receiver<u8>,
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(100);
         // Ensure that only one ScancodeStream can be created
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
+        SCANCODE_SENDER
+            .try_init_once(|| sender.irq_sender())
             .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        ScancodeStream { receiver }
     }
 }
 
@@ -58,24 +59,8 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     // Allows us to keep calling the method until a None is returned
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("not initialised");
-
-        if let Ok(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-
-        WAKER.register(&cx.waker());
-
-        // Check again as there might be a value pushed to
-        // the queue while we were registering the waker
-        match queue.pop() {
-            Ok(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            Err(crossbeam_queue::PopError) => Poll::Pending,
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        self.receiver.poll_recv(cx)
     }
 }
 
@@ -1,11 +1,21 @@
 use alloc::boxed::Box;
+use core::fmt;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
+pub use coop::{consume_budget, yield_now};
+pub use join::{JoinError, JoinHandle};
+
+pub mod channel;
+pub mod coop;
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod multicore;
 pub mod simple_executor;
+pub mod stats;
+pub mod sync;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
@@ -17,6 +27,52 @@ impl TaskId {
     }
 }
 
+// Ready tasks of higher priority are always polled first, except that a
+// lower priority is served after having been passed over a few times in
+// a row, so that busy high priority tasks cannot starve it completely
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub(crate) const COUNT: usize = 3;
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        })
+    }
+}
+
+// How a task is spawned, see `Executor::spawn_with`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpawnOptions {
+    // Shown in task listings
+    pub name: Option<&'static str>,
+    pub priority: Priority,
+}
+
+impl SpawnOptions {
+    pub fn named(name: &'static str) -> Self {
+        SpawnOptions {
+            name: Some(name),
+            ..SpawnOptions::default()
+        }
+    }
+}
+
 pub struct Task {
     // A pin is necessary because futures
     // might be self-referential
@@ -34,6 +90,14 @@ impl Task {
         }
     }
 
+    // Creates a task from an already pinned future without boxing it again
+    fn from_pinned(future: Pin<Box<dyn Future<Output = ()>>>) -> Task {
+        Task {
+            future,
+            id: TaskId::new(),
+        }
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
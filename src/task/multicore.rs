@@ -0,0 +1,252 @@
+// Executor for `Send` tasks that runs on all CPUs at once
+//
+// Every CPU has a local queue of ready tasks. A woken task is queued on
+// the CPU that polled it last, and a CPU that runs out of tasks steals
+// half of the tasks of another CPU's queue, so tasks migrate between CPUs
+// as needed. Tasks spawned from outside of this executor go through a
+// global queue that every CPU looks at.
+//
+// The application processors run nothing but this executor. The boot
+// CPU's `Executor::run` polls its tasks as well whenever its own tasks
+// are idle, so that they also run on machines with a single CPU.
+//
+// Halted CPUs are woken with an IPI when they are given a task, or
+// notice stealable tasks at their next timer interrupt.
+
+use super::stats::TaskStats;
+use super::{coop, join, JoinHandle, SpawnOptions, TaskId};
+use crate::{per_cpu, smp};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use conquer_once::spin::OnceCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Waker};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use lazy_static::lazy_static;
+
+// Number of tasks that fit into each CPU's queue. Tasks that do not fit
+// go to the global queue.
+const LOCAL_QUEUE_CAPACITY: usize = 256;
+
+// Tasks `run_pending` polls at most, so that the boot CPU
+// gets back to its own tasks
+const POLL_BATCH: usize = 64;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Task {
+    // `None` once the task completed. Only one CPU can poll the
+    // task at a time, the lock makes sure of that.
+    future: spin::Mutex<Option<BoxFuture>>,
+    // Whether the task is in one of the queues
+    queued: AtomicBool,
+    // The CPU that polled the task last, wakeups queue it there
+    cpu: AtomicUsize,
+    stats: Arc<TaskStats>,
+}
+
+per_cpu! {
+    // Created by `init_cpu` before the CPU runs any tasks
+    static LOCAL_QUEUE: OnceCell<ArrayQueue<Arc<Task>>> = OnceCell::uninit();
+}
+
+per_cpu! {
+    // Whether the CPU is polling a task of this executor right now
+    static POLLING: AtomicBool = AtomicBool::new(false);
+}
+
+lazy_static! {
+    static ref GLOBAL_QUEUE: SegQueue<Arc<Task>> = SegQueue::new();
+}
+
+// Creates the local queue of CPU number `cpu`. Has to be called before
+// the CPU is started, so that wakeups never allocate.
+pub(crate) fn init_cpu(cpu: usize) {
+    let _ = LOCAL_QUEUE
+        .get_for(cpu)
+        .try_init_once(|| ArrayQueue::new(LOCAL_QUEUE_CAPACITY));
+}
+
+// `None` for CPUs without a queue, e.g. the boot CPU before `smp::init`
+fn local_queue(cpu: usize) -> Option<&'static ArrayQueue<Arc<Task>>> {
+    LOCAL_QUEUE.get_for(cpu).try_get().ok()
+}
+
+// Queues `task` on `cpu`, or globally if its queue is full or missing
+fn push_local(cpu: usize, task: Arc<Task>) {
+    match local_queue(cpu) {
+        Some(queue) => {
+            if let Err(error) = queue.push(task) {
+                GLOBAL_QUEUE.push(error.0);
+            }
+        }
+        None => GLOBAL_QUEUE.push(task),
+    }
+}
+
+// Spawns `future` as a task that may run on any CPU. The returned handle
+// can be awaited for the task's output, or dropped to let the task run
+// detached.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    spawn_with(future, SpawnOptions::default())
+}
+
+// Like `spawn`. This executor has no priorities, `options.priority`
+// only shows up in task listings.
+pub fn spawn_with<F>(future: F, options: SpawnOptions) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let (future, handle) = join::joinable(future);
+    let id = TaskId::new();
+    let task = Arc::new(Task {
+        future: spin::Mutex::new(Some(Box::pin(future))),
+        queued: AtomicBool::new(true),
+        cpu: AtomicUsize::new(smp::current_cpu()),
+        stats: TaskStats::register(id, options.name, options.priority),
+    });
+    task.stats.set_ready();
+
+    // A task spawned by a task stays on this CPU, unless another CPU
+    // has nothing to do and steals it
+    if POLLING.get().load(Ordering::Relaxed) {
+        push_local(smp::current_cpu(), task);
+    } else {
+        GLOBAL_QUEUE.push(task);
+    }
+    smp::wake_any();
+    handle
+}
+
+impl Task {
+    fn schedule(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return; // already queued
+        }
+        self.stats.set_ready();
+
+        let cpu = self.cpu.load(Ordering::Relaxed);
+        push_local(cpu, self.clone());
+        smp::wake(cpu);
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.schedule();
+    }
+}
+
+// Runs the tasks of this executor on the calling CPU, forever
+pub fn run() -> ! {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        run_pending();
+        interrupts::disable();
+        smp::halt_unless(has_work);
+    }
+}
+
+// Polls tasks that are ready on the calling CPU or can be stolen from
+// other CPUs, until there are none left or a batch of them was polled
+pub fn run_pending() {
+    let cpu = smp::current_cpu();
+    for _ in 0..POLL_BATCH {
+        match next_task(cpu) {
+            Some(task) => poll(task),
+            None => break,
+        }
+    }
+}
+
+// Whether `run_pending` would find a task to poll on the calling CPU
+pub fn has_work() -> bool {
+    !GLOBAL_QUEUE.is_empty()
+        || (0..smp::cpu_count())
+            .any(|cpu| local_queue(cpu).map_or(false, |queue| !queue.is_empty()))
+}
+
+fn next_task(cpu: usize) -> Option<Arc<Task>> {
+    local_queue(cpu)
+        .and_then(|queue| queue.pop().ok())
+        .or_else(|| GLOBAL_QUEUE.pop().ok())
+        .or_else(|| steal(cpu))
+}
+
+// Moves half of the tasks of the first other CPU that has any to
+// `cpu`'s queue, and returns one of them
+fn steal(cpu: usize) -> Option<Arc<Task>> {
+    let count = smp::cpu_count();
+
+    for offset in 1..count {
+        let victim = match local_queue((cpu + offset) % count) {
+            Some(victim) => victim,
+            None => continue,
+        };
+
+        let mut stolen = None;
+        for _ in 0..(victim.len() + 1) / 2 {
+            let task = match victim.pop() {
+                Ok(task) => task,
+                Err(_) => break,
+            };
+            if stolen.is_none() {
+                stolen = Some(task);
+            } else {
+                push_local(cpu, task);
+            }
+        }
+        if stolen.is_some() {
+            return stolen;
+        }
+    }
+    None
+}
+
+fn poll(task: Arc<Task>) {
+    let cpu = smp::current_cpu();
+    let mut future = match task.future.try_lock() {
+        Some(future) => future,
+        None => {
+            // Woken while being polled on another CPU, which is not done
+            // with it yet. Keep it queued and try again later.
+            push_local(cpu, task.clone());
+            return;
+        }
+    };
+    let pinned = match future.as_mut() {
+        Some(pinned) => pinned,
+        None => return, // completed already
+    };
+
+    // Clear the flag before polling, so that wakeups
+    // during the poll queue the task again
+    task.queued.store(false, Ordering::Release);
+    task.cpu.store(cpu, Ordering::Relaxed);
+
+    let waker = Waker::from(task.clone());
+    let mut context = Context::from_waker(&waker);
+    POLLING.get().store(true, Ordering::Relaxed);
+    coop::reset_budget();
+    let start = task.stats.start_poll();
+    let result = pinned.as_mut().poll(&mut context);
+    task.stats.end_poll(start, result.is_ready());
+    POLLING.get().store(false, Ordering::Relaxed);
+
+    if result.is_ready() {
+        *future = None;
+    }
+}
@@ -0,0 +1,193 @@
+// Accounting for tasks run by an `Executor`: how often and how long they
+// were polled and what they are doing right now. Every executor registers
+// its tasks here, so that they can be listed from anywhere, e.g. by the
+// shell's `top` command.
+
+use super::{Priority, TaskId};
+use crate::{time, warn};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+// Finished tasks are kept around for a while, so that short-lived
+// tasks show up in listings at all
+const MAX_FINISHED: usize = 16;
+
+// Polls taking longer than this many cycles are logged. At the default,
+// that is somewhere around 50ms on current CPUs.
+static LONG_POLL_CYCLES: AtomicU64 = AtomicU64::new(100_000_000);
+
+static TASKS: spin::Mutex<BTreeMap<TaskId, Arc<TaskStats>>> = spin::Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    // Woken and waiting to be polled
+    Ready,
+    // Being polled right now
+    Running,
+    // Waiting to be woken
+    Pending,
+    Finished,
+}
+
+impl TaskState {
+    fn from_u8(state: u8) -> TaskState {
+        match state {
+            0 => TaskState::Ready,
+            1 => TaskState::Running,
+            2 => TaskState::Pending,
+            _ => TaskState::Finished,
+        }
+    }
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Pending => "pending",
+            TaskState::Finished => "finished",
+        })
+    }
+}
+
+pub(super) struct TaskStats {
+    id: TaskId,
+    name: Option<&'static str>,
+    priority: Priority,
+    state: AtomicU8,
+    polls: AtomicU64,
+    poll_cycles: AtomicU64,
+    max_poll_cycles: AtomicU64,
+    long_polls: AtomicU64,
+}
+
+impl TaskStats {
+    // Creates the accounting for a new task and makes it visible in `list`
+    pub(super) fn register(
+        id: TaskId,
+        name: Option<&'static str>,
+        priority: Priority,
+    ) -> Arc<Self> {
+        let stats = Arc::new(TaskStats {
+            id,
+            name,
+            priority,
+            state: AtomicU8::new(TaskState::Pending as u8),
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            max_poll_cycles: AtomicU64::new(0),
+            long_polls: AtomicU64::new(0),
+        });
+        TASKS.lock().insert(id, stats.clone());
+        stats
+    }
+
+    // Called when the task is woken, possibly from an interrupt handler
+    pub(super) fn set_ready(&self) {
+        self.state.store(TaskState::Ready as u8, Ordering::Release);
+    }
+
+    // Returns the cycle counter at the start of the poll
+    pub(super) fn start_poll(&self) -> u64 {
+        self.state
+            .store(TaskState::Running as u8, Ordering::Release);
+        time::cycles()
+    }
+
+    pub(super) fn end_poll(&self, start: u64, finished: bool) {
+        let cycles = time::cycles().wrapping_sub(start);
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.max_poll_cycles.fetch_max(cycles, Ordering::Relaxed);
+
+        if cycles > LONG_POLL_CYCLES.load(Ordering::Relaxed) {
+            self.long_polls.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "task {} ({}) blocked its executor for {} cycles",
+                self.id.0,
+                self.name.unwrap_or("unnamed"),
+                cycles
+            );
+        }
+
+        if finished {
+            self.state
+                .store(TaskState::Finished as u8, Ordering::Release);
+            prune_finished();
+        } else {
+            // Unless the task was woken while it was being polled
+            let _ = self.state.compare_exchange(
+                TaskState::Running as u8,
+                TaskState::Pending as u8,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id.0,
+            name: self.name,
+            priority: self.priority,
+            state: TaskState::from_u8(self.state.load(Ordering::Acquire)),
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_cycles: self.poll_cycles.load(Ordering::Relaxed),
+            max_poll_cycles: self.max_poll_cycles.load(Ordering::Relaxed),
+            long_polls: self.long_polls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Forgets a task that will never be polled again, e.g. because
+// its executor was dropped
+pub(super) fn unregister(id: TaskId) {
+    TASKS.lock().remove(&id);
+}
+
+// Forgets the oldest finished tasks beyond `MAX_FINISHED`
+fn prune_finished() {
+    let mut tasks = TASKS.lock();
+    let finished: Vec<TaskId> = tasks
+        .values()
+        .filter(|stats| stats.state.load(Ordering::Acquire) == TaskState::Finished as u8)
+        .map(|stats| stats.id)
+        .collect();
+    for id in finished
+        .iter()
+        .take(finished.len().saturating_sub(MAX_FINISHED))
+    {
+        tasks.remove(id);
+    }
+}
+
+// A copy of a task's accounting taken at one point in time
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: Option<&'static str>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub polls: u64,
+    // Time spent in `poll`, measured with the CPU's cycle counter
+    pub poll_cycles: u64,
+    pub max_poll_cycles: u64,
+    // Polls that took longer than the long poll threshold
+    pub long_polls: u64,
+}
+
+// Lists all tasks and the most recently finished ones, ordered by ID
+pub fn list() -> Vec<TaskInfo> {
+    TASKS.lock().values().map(|stats| stats.info()).collect()
+}
+
+pub fn long_poll_threshold() -> u64 {
+    LONG_POLL_CYCLES.load(Ordering::Relaxed)
+}
+
+// Polls longer than `cycles` will be logged and counted as long polls
+pub fn set_long_poll_threshold(cycles: u64) {
+    LONG_POLL_CYCLES.store(cycles, Ordering::Relaxed);
+}
@@ -0,0 +1,27 @@
+// Async synchronization primitives
+//
+// Unlike `spin::Mutex`, these never spin while waiting: a task that has
+// to wait registers its waker and returns `Pending`, so holding a lock
+// across an `.await` does not block the executor. They only rely on
+// wakers and therefore work with any executor. Waiters are served in
+// FIFO order.
+
+use x86_64::instructions::interrupts;
+
+mod barrier;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+
+pub use barrier::{Barrier, BarrierWait, BarrierWaitResult};
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
+
+// Runs `f` on the state behind `lock` with interrupts disabled, so that
+// the primitives can also be released or notified from interrupt handlers
+pub(super) fn with_state<T, R>(lock: &spin::Mutex<T>, f: impl FnOnce(&mut T) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut lock.lock()))
+}
@@ -0,0 +1,119 @@
+use super::with_state;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+// Lets a fixed number of tasks wait until all of them reached the same
+// point. The barrier can be reused: once it released a group of tasks,
+// it waits for the next group.
+pub struct Barrier {
+    parties: usize,
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    // Wakers of the tasks that arrived in the current generation
+    arrived: BTreeMap<u64, Waker>,
+    generation: u64,
+    next_id: u64,
+}
+
+impl Barrier {
+    pub const fn new(parties: usize) -> Self {
+        Barrier {
+            parties,
+            state: spin::Mutex::new(State {
+                arrived: BTreeMap::new(),
+                generation: 0,
+                next_id: 0,
+            }),
+        }
+    }
+
+    // Completes once `parties` tasks are waiting. Dropping the future
+    // before that takes the task out of the count again.
+    pub fn wait(&self) -> BarrierWait<'_> {
+        BarrierWait {
+            barrier: self,
+            waiting: None,
+        }
+    }
+}
+
+// Tells exactly one task of each group that it was the last to arrive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+// Future returned by `Barrier::wait`
+pub struct BarrierWait<'a> {
+    barrier: &'a Barrier,
+    // Our ID and the generation we are waiting in
+    waiting: Option<(u64, u64)>,
+}
+
+impl Future for BarrierWait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<BarrierWaitResult> {
+        let parties = self.barrier.parties;
+        let waiting = self.waiting;
+
+        let result = with_state(&self.barrier.state, |state| match waiting {
+            Some((_, generation)) if generation != state.generation => {
+                Ok((BarrierWaitResult(false), Vec::new()))
+            }
+            Some((id, _)) => {
+                state.arrived.insert(id, cx.waker().clone());
+                Err(None)
+            }
+            None if state.arrived.len() + 1 >= parties => {
+                // Last one to arrive, release everybody
+                state.generation += 1;
+                let woken = core::mem::take(&mut state.arrived).into_values().collect();
+                Ok((BarrierWaitResult(true), woken))
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.arrived.insert(id, cx.waker().clone());
+                Err(Some((id, state.generation)))
+            }
+        });
+
+        match result {
+            Ok((result, woken)) => {
+                self.waiting = None;
+                woken.into_iter().for_each(Waker::wake);
+                Poll::Ready(result)
+            }
+            Err(waiting) => {
+                if waiting.is_some() {
+                    self.waiting = waiting;
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for BarrierWait<'_> {
+    fn drop(&mut self) {
+        if let Some((id, generation)) = self.waiting {
+            with_state(&self.barrier.state, |state| {
+                if state.generation == generation {
+                    state.arrived.remove(&id);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use super::with_state;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+// Wakes up tasks waiting in `notified().await`
+//
+// `notify_one` wakes a single waiter, or, if nobody is waiting, stores a
+// permit so that the next `notified()` completes right away. This way a
+// notification sent just before the receiver starts waiting is not lost.
+// `notify_waiters` wakes everyone currently waiting and stores nothing.
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permit: bool,
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    None,
+    One,
+    All,
+}
+
+struct Waiter {
+    id: u64,
+    waker: Waker,
+    notification: Notification,
+}
+
+impl State {
+    fn notify_one(&mut self) -> Option<Waker> {
+        let waiter = self
+            .waiters
+            .iter_mut()
+            .find(|waiter| waiter.notification == Notification::None);
+        match waiter {
+            Some(waiter) => {
+                waiter.notification = Notification::One;
+                Some(waiter.waker.clone())
+            }
+            None => {
+                self.permit = true;
+                None
+            }
+        }
+    }
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+
+    pub fn notify_one(&self) {
+        if let Some(waker) = with_state(&self.state, State::notify_one) {
+            waker.wake();
+        }
+    }
+
+    pub fn notify_waiters(&self) {
+        let woken: Vec<Waker> = with_state(&self.state, |state| {
+            state
+                .waiters
+                .iter_mut()
+                .filter(|waiter| waiter.notification == Notification::None)
+                .map(|waiter| {
+                    waiter.notification = Notification::All;
+                    waiter.waker.clone()
+                })
+                .collect()
+        });
+        woken.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+// Future returned by `Notify::notified`
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    // ID of our entry in the wait queue once we had to wait
+    waiter: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let waiter = self.waiter;
+
+        let notified = with_state(&self.notify.state, |state| match waiter {
+            None if state.permit => {
+                state.permit = false;
+                Ok(())
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back(Waiter {
+                    id,
+                    waker: cx.waker().clone(),
+                    notification: Notification::None,
+                });
+                Err(id)
+            }
+            Some(id) => {
+                let position = state
+                    .waiters
+                    .iter()
+                    .position(|waiter| waiter.id == id)
+                    .expect("notify waiter vanished");
+                if state.waiters[position].notification == Notification::None {
+                    state.waiters[position].waker = cx.waker().clone();
+                    Err(id)
+                } else {
+                    state.waiters.remove(position);
+                    Ok(())
+                }
+            }
+        });
+
+        match notified {
+            Ok(()) => {
+                self.waiter = None;
+                Poll::Ready(())
+            }
+            Err(id) => {
+                self.waiter = Some(id);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let id = match self.waiter {
+            Some(id) => id,
+            None => return,
+        };
+
+        let waker = with_state(&self.notify.state, |state| {
+            let position = state.waiters.iter().position(|waiter| waiter.id == id)?;
+            let waiter = state.waiters.remove(position).unwrap();
+            // Pass on a `notify_one` that we received but will never act on
+            if waiter.notification == Notification::One {
+                state.notify_one()
+            } else {
+                None
+            }
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
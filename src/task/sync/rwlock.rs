@@ -0,0 +1,107 @@
+use super::{Semaphore, SemaphorePermit};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+// Upper bound on the number of concurrent readers
+const MAX_READERS: usize = 1 << 16;
+
+// A reader-writer lock whose guards may be held across `.await`
+//
+// Readers take one permit of the underlying semaphore and writers take
+// all of them. Since permits are handed out in FIFO order, a waiting
+// writer keeps new readers from overtaking it, so writers cannot starve.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+// The semaphore ensures that there is either one writer or only readers
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            semaphore: Semaphore::new(MAX_READERS),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let permit = self.semaphore.acquire_many(MAX_READERS).await;
+        RwLockWriteGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore.try_acquire().map(|permit| RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore
+            .try_acquire_many(MAX_READERS)
+            .map(|permit| RwLockWriteGuard {
+                lock: self,
+                _permit: permit,
+            })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
@@ -0,0 +1,227 @@
+use super::with_state;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+// A counting semaphore. Permits are handed out in the order they were
+// asked for, so a large request is not starved by a stream of small ones.
+pub struct Semaphore {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+struct Waiter {
+    id: u64,
+    permits: usize,
+    waker: Waker,
+    // Set once the permits were handed to this waiter
+    assigned: bool,
+}
+
+impl State {
+    // Whether there are waiters still waiting for their permits
+    fn has_queued(&self) -> bool {
+        self.waiters.iter().any(|waiter| !waiter.assigned)
+    }
+
+    // Hands out permits to waiters at the front of the queue. Returns the
+    // wakers of the waiters that got their permits.
+    fn assign_permits(&mut self) -> Vec<Waker> {
+        let mut woken = Vec::new();
+        for waiter in self.waiters.iter_mut().filter(|waiter| !waiter.assigned) {
+            if waiter.permits > self.permits {
+                break;
+            }
+            self.permits -= waiter.permits;
+            waiter.assigned = true;
+            woken.push(waiter.waker.clone());
+        }
+        woken
+    }
+
+    fn release(&mut self, permits: usize) -> Vec<Waker> {
+        self.permits += permits;
+        self.assign_permits()
+    }
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        with_state(&self.state, |state| state.permits)
+    }
+
+    // Waits for a single permit, which is returned when the
+    // returned `SemaphorePermit` is dropped
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            waiter: None,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    // Fails if the permits are not available right away or if other
+    // tasks are already waiting for permits
+    pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        let acquired = with_state(&self.state, |state| {
+            let acquired = !state.has_queued() && state.permits >= permits;
+            if acquired {
+                state.permits -= permits;
+            }
+            acquired
+        });
+        if acquired {
+            Some(SemaphorePermit {
+                semaphore: self,
+                permits,
+            })
+        } else {
+            None
+        }
+    }
+
+    // Returns permits to the semaphore without a `SemaphorePermit`,
+    // e.g. to raise the number of permits
+    pub fn add_permits(&self, permits: usize) {
+        let woken = with_state(&self.state, |state| state.release(permits));
+        woken.into_iter().for_each(Waker::wake);
+    }
+}
+
+// Future returned by `Semaphore::acquire`. Dropping it while it waits
+// gives up its place in the queue.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    // ID of our entry in the wait queue once we had to wait
+    waiter: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let this = &mut *self;
+        let permits = this.permits;
+        let waiter = this.waiter;
+
+        let acquired = with_state(&this.semaphore.state, |state| match waiter {
+            None if !state.has_queued() && state.permits >= permits => {
+                state.permits -= permits;
+                Ok(())
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back(Waiter {
+                    id,
+                    permits,
+                    waker: cx.waker().clone(),
+                    assigned: false,
+                });
+                Err(id)
+            }
+            Some(id) => {
+                let position = state
+                    .waiters
+                    .iter()
+                    .position(|waiter| waiter.id == id)
+                    .expect("semaphore waiter vanished");
+                if state.waiters[position].assigned {
+                    state.waiters.remove(position);
+                    Ok(())
+                } else {
+                    state.waiters[position].waker = cx.waker().clone();
+                    Err(id)
+                }
+            }
+        });
+
+        match acquired {
+            Ok(()) => {
+                this.waiter = None;
+                Poll::Ready(SemaphorePermit {
+                    semaphore: this.semaphore,
+                    permits,
+                })
+            }
+            Err(id) => {
+                this.waiter = Some(id);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let id = match self.waiter {
+            Some(id) => id,
+            None => return,
+        };
+
+        let woken = with_state(&self.semaphore.state, |state| {
+            let position = match state.waiters.iter().position(|waiter| waiter.id == id) {
+                Some(position) => position,
+                None => return Vec::new(),
+            };
+            let waiter = state.waiters.remove(position).unwrap();
+            if waiter.assigned {
+                // We got permits but nobody will use them
+                state.release(waiter.permits)
+            } else {
+                // We might have been blocking the waiters behind us
+                state.assign_permits()
+            }
+        });
+        woken.into_iter().for_each(Waker::wake);
+    }
+}
+
+// Permits acquired from a `Semaphore`, returned to it on drop
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    // Keeps the permits acquired for good instead of returning them
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+// Preemptive kernel threads
+//
+// Every thread runs on its own stack. The timer interrupt preempts the
+// running thread once its time slice is used up and switches to the next
+// ready thread in round-robin order. The flow of control that calls
+// `init` becomes the thread "main", and an idle thread halts the CPU
+// whenever no other thread is ready.
+//
+// Async tasks keep working as before: an `Executor` simply runs inside
+// one of the threads, usually "main".
+//
+// Threads only run on the boot CPU.
+
+use crate::time;
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+mod context;
+mod join;
+mod scheduler;
+pub mod sync;
+mod wait_queue;
+
+pub use join::JoinHandle;
+pub use scheduler::{current, list, ThreadInfo, ThreadState};
+pub use wait_queue::WaitQueue;
+
+// Size of the stack of every spawned thread
+pub const STACK_SIZE: usize = 16 * 1024;
+// Timer ticks a thread may run before it is preempted
+pub const TIME_SLICE_TICKS: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+// Written to the lowest word of every stack and checked on every switch,
+// to catch stack overflows before they corrupt too much of the heap
+const STACK_CANARY: u64 = 0x5741_434b_4341_4e59;
+
+struct Stack {
+    words: Box<[u64]>,
+}
+
+impl Stack {
+    fn new() -> Self {
+        let mut words = alloc::vec![0; STACK_SIZE / 8].into_boxed_slice();
+        words[0] = STACK_CANARY;
+        Stack { words }
+    }
+
+    fn words_mut(&mut self) -> &mut [u64] {
+        &mut self.words
+    }
+
+    fn overflowed(&self) -> bool {
+        self.words[0] != STACK_CANARY
+    }
+}
+
+// Turns the caller into the thread "main" and starts preempting it.
+// The heap has to be initialised before.
+pub fn init() {
+    scheduler::init();
+}
+
+// Starts a new thread running `f`. The thread exits when `f` returns,
+// its result can be collected with `JoinHandle::join`.
+pub fn spawn<F, T>(name: &'static str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = join::Packet::new();
+    let their_packet = packet.clone();
+    let main = move || their_packet.finish(f());
+
+    // Double boxing gives us a thin pointer to pass to the new thread
+    let main: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(main));
+    let argument = Box::into_raw(main) as u64;
+
+    let mut stack = Stack::new();
+    let rsp = context::prepare_stack(&mut stack, thread_start, argument);
+    let id = scheduler::spawn(name, stack, rsp);
+    JoinHandle::new(id, packet)
+}
+
+extern "C" fn thread_start(argument: u64) -> ! {
+    // We got here through a context switch, which
+    // always happens with interrupts disabled
+    interrupts::enable();
+
+    let f = unsafe { Box::from_raw(argument as *mut Box<dyn FnOnce() + Send>) };
+    f();
+    exit();
+}
+
+// Gives up the rest of the time slice to the next ready thread
+pub fn yield_now() {
+    interrupts::without_interrupts(|| scheduler::reschedule(ThreadState::Ready));
+}
+
+// Blocks the current thread for at least `duration`, with the
+// resolution of one timer tick
+pub fn sleep(duration: Duration) {
+    sleep_unless(duration, || false);
+}
+
+// Like `sleep`, but gives up early once `stop` returns true. It is checked
+// whenever the thread is woken up, see `wake`. Returns whether the whole
+// duration passed.
+pub fn sleep_unless(duration: Duration, stop: impl FnMut() -> bool) -> bool {
+    let deadline = time::ticks() + time::duration_to_ticks(duration);
+    scheduler::sleep_until(deadline, stop)
+}
+
+// Wakes the thread `id` if it is blocked, so that whatever it waits for
+// checks its condition again. Blocking has to cope with such early
+// wakeups anyway.
+pub(crate) fn wake(id: ThreadId) {
+    scheduler::unblock(id);
+}
+
+// Makes `top` the current thread's stack for entering the kernel from
+// user mode, loading it whenever the thread is switched to. Other CPUs
+// do not run threads, they keep whatever was loaded last.
+pub(crate) fn set_kernel_stack(top: Option<VirtAddr>) {
+    if crate::smp::is_boot_cpu() {
+        scheduler::set_kernel_stack(top);
+    }
+}
+
+// Makes `table` the level 4 page table of the current thread, switching
+// to it whenever the thread is switched to. Like the kernel stack, only
+// tracked on the boot CPU.
+pub(crate) fn set_page_table(table: Option<PhysFrame>) {
+    if crate::smp::is_boot_cpu() {
+        scheduler::set_page_table(table);
+    }
+}
+
+// Whether another thread is waiting for the CPU. Always false on
+// other CPUs than the boot CPU, which do not run threads.
+pub fn has_ready() -> bool {
+    crate::smp::is_boot_cpu() && scheduler::has_ready()
+}
+
+// Ends the current thread
+pub fn exit() -> ! {
+    interrupts::disable();
+    scheduler::reschedule(ThreadState::Exited);
+    unreachable!("exited thread was scheduled again");
+}
+
+// Called by the timer interrupt handler after the end of interrupt was
+// signalled, so that the next thread can receive timer interrupts
+pub(crate) fn on_timer_tick() {
+    scheduler::tick();
+}
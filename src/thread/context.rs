@@ -0,0 +1,81 @@
+// Switching between the stacks of kernel threads
+//
+// A thread that is not running has the callee-saved registers of the
+// System V ABI pushed on its stack, followed by the address to continue
+// at, and its saved stack pointer points at them. Everything else is
+// either caller-saved, and thus already saved by the compiler around the
+// call to `context_switch`, or part of an interrupt frame further up the
+// stack. The kernel is built without SSE, so there is no FPU state.
+
+use super::Stack;
+
+extern "C" {
+    // Saves the current context on the current stack, stores the stack
+    // pointer in `*old_rsp` and continues with the context saved at `new_rsp`
+    fn context_switch(old_rsp: *mut u64, new_rsp: u64);
+    // Where new threads start, see `prepare_stack`
+    fn thread_trampoline();
+}
+
+core::arch::global_asm!(
+    r#"
+.global context_switch
+context_switch:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global thread_trampoline
+thread_trampoline:
+    mov rdi, r12
+    and rsp, -16
+    call r13
+    ud2
+"#
+);
+
+// Switches to the context saved at `new_rsp`. Returns once another
+// thread switches back to the context saved in `*old_rsp`.
+//
+// Must be called with interrupts disabled and `old_rsp` must stay valid
+// until the switch completed.
+pub(super) unsafe fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    context_switch(old_rsp, new_rsp);
+}
+
+// Lays out a fresh stack as if `context_switch` had saved a context on it
+// that continues at `thread_trampoline`, which in turn calls
+// `entry(argument)`. Returns the stack pointer to switch to.
+pub(super) fn prepare_stack(
+    stack: &mut Stack,
+    entry: extern "C" fn(u64) -> !,
+    argument: u64,
+) -> u64 {
+    // In the order `context_switch` pops them
+    let initial = [
+        0,                                     // r15
+        0,                                     // r14
+        entry as *const () as u64,             // r13
+        argument,                              // r12
+        0,                                     // rbx
+        0,                                     // rbp, ends backtraces
+        thread_trampoline as *const () as u64, // return address
+    ];
+
+    let words = stack.words_mut();
+    let start = words.len() - initial.len();
+    words[start..].copy_from_slice(&initial);
+    &words[start] as *const u64 as u64
+}
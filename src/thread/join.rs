@@ -0,0 +1,57 @@
+use super::{ThreadId, WaitQueue};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Where a thread leaves its result for `JoinHandle::join`
+pub(super) struct Packet<T> {
+    result: spin::Mutex<Option<T>>,
+    finished: AtomicBool,
+    joiners: WaitQueue,
+}
+
+impl<T> Packet<T> {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(Packet {
+            result: spin::Mutex::new(None),
+            finished: AtomicBool::new(false),
+            joiners: WaitQueue::new(),
+        })
+    }
+
+    // Called by the thread itself right before it exits
+    pub(super) fn finish(&self, result: T) {
+        *self.result.lock() = Some(result);
+        self.finished.store(true, Ordering::Release);
+        self.joiners.wake_all();
+    }
+}
+
+// Handle to a spawned thread. Dropping it detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(id: ThreadId, packet: Arc<Packet<T>>) -> Self {
+        JoinHandle { id, packet }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.finished.load(Ordering::Acquire)
+    }
+
+    // Blocks until the thread returned and yields what it returned
+    pub fn join(self) -> T {
+        let packet = &self.packet;
+        packet
+            .joiners
+            .wait_until(|| packet.finished.load(Ordering::Acquire));
+        let result = packet.result.lock().take();
+        result.expect("thread finished without a result")
+    }
+}
@@ -0,0 +1,392 @@
+// Round-robin scheduling of kernel threads
+//
+// All scheduler state is only touched with interrupts disabled, so the
+// timer interrupt can never find it locked.
+
+use super::{context, Stack, ThreadId, TIME_SLICE_TICKS};
+use crate::time;
+use alloc::collections::{BTreeMap, BinaryHeap, VecDeque};
+use alloc::{boxed::Box, vec::Vec};
+use core::cmp::Reverse;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    // Waiting for its turn
+    Ready,
+    Running,
+    // Waiting for something else than the CPU
+    Blocked,
+    Exited,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    // Set when the thread is woken before it got to block, so that
+    // it does not block at all then
+    wakeup_pending: bool,
+    // Stack pointer saved by the last context switch away from the thread
+    rsp: u64,
+    // `None` for "main", which keeps running on the boot stack
+    stack: Option<Stack>,
+    // Stack for entering the kernel while the thread runs user code,
+    // see `usermode::enter`
+    kernel_stack: Option<VirtAddr>,
+    // Level 4 page table of the thread's user address space, `None` for
+    // the kernel's
+    page_table: Option<PhysFrame>,
+}
+
+struct Scheduler {
+    // Boxed so that a thread's saved stack pointer does not move while
+    // the context switch writes to it
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    idle: ThreadId,
+    // Ticks left in the current thread's time slice
+    slice_left: u64,
+    // Exited threads whose stacks can be freed once we are off them. Still
+    // boxed, as the switch away from them writes to their `rsp`.
+    #[allow(clippy::vec_box)]
+    exited: Vec<Box<Thread>>,
+    // Sleeping threads with the tick at which to wake them, earliest first
+    sleepers: BinaryHeap<Reverse<(u64, ThreadId)>>,
+}
+
+static SCHEDULER: spin::Mutex<Option<Scheduler>> = spin::Mutex::new(None);
+
+pub(super) fn init() {
+    let main = Box::new(Thread {
+        id: ThreadId::new(),
+        name: "main",
+        state: ThreadState::Running,
+        wakeup_pending: false,
+        rsp: 0,
+        stack: None,
+        kernel_stack: None,
+        page_table: None,
+    });
+
+    let mut idle_stack = Stack::new();
+    let idle_rsp = context::prepare_stack(&mut idle_stack, idle_thread, 0);
+    let idle = Box::new(Thread {
+        id: ThreadId::new(),
+        name: "idle",
+        state: ThreadState::Ready,
+        wakeup_pending: false,
+        rsp: idle_rsp,
+        stack: Some(idle_stack),
+        kernel_stack: None,
+        page_table: None,
+    });
+
+    let mut threads = BTreeMap::new();
+    let current = main.id;
+    let idle_id = idle.id;
+    threads.insert(main.id, main);
+    threads.insert(idle.id, idle);
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        assert!(scheduler.is_none(), "threads already initialised");
+        *scheduler = Some(Scheduler {
+            threads,
+            ready: VecDeque::new(),
+            current,
+            idle: idle_id,
+            slice_left: TIME_SLICE_TICKS,
+            exited: Vec::new(),
+            sleepers: BinaryHeap::new(),
+        });
+    });
+}
+
+// Runs whenever no other thread is ready. It is never queued as ready,
+// the scheduler falls back to it instead.
+extern "C" fn idle_thread(_argument: u64) -> ! {
+    interrupts::enable();
+    loop {
+        reap();
+        interrupts::disable();
+        if has_ready() {
+            reschedule(ThreadState::Ready);
+            interrupts::enable();
+        } else {
+            // Wakes up at the next interrupt, which might make a thread ready
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+pub(super) fn spawn(name: &'static str, stack: Stack, rsp: u64) -> ThreadId {
+    // Good opportunity to free stacks with interrupts enabled, unless
+    // called on another CPU while the boot CPU may still be switching
+    // away from an exited thread
+    if crate::smp::is_boot_cpu() {
+        reap();
+    }
+
+    let thread = Box::new(Thread {
+        id: ThreadId::new(),
+        name,
+        state: ThreadState::Ready,
+        wakeup_pending: false,
+        rsp,
+        stack: Some(stack),
+        kernel_stack: None,
+        page_table: None,
+    });
+    let id = thread.id;
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("threads not initialised");
+        scheduler.threads.insert(id, thread);
+        scheduler.ready.push_back(id);
+    });
+    id
+}
+
+fn assert_boot_cpu() {
+    assert!(
+        crate::smp::is_boot_cpu(),
+        "threads only run on the boot CPU"
+    );
+}
+
+// Frees the stacks of exited threads. Only safe on the boot CPU, where
+// threads run: once any thread runs there, the switch away from them is
+// complete.
+fn reap() {
+    assert_boot_cpu();
+    let exited = interrupts::without_interrupts(|| match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => core::mem::take(&mut scheduler.exited),
+        None => Vec::new(),
+    });
+    drop(exited);
+}
+
+pub(super) fn has_ready() -> bool {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map_or(false, |scheduler| !scheduler.ready.is_empty())
+    })
+}
+
+pub(super) fn tick() {
+    let expired = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => {
+            let now = time::ticks();
+            while let Some(&Reverse((deadline, id))) = scheduler.sleepers.peek() {
+                if deadline > now {
+                    break;
+                }
+                scheduler.sleepers.pop();
+                scheduler.unblock(id);
+            }
+
+            scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+            scheduler.slice_left == 0
+        }
+        None => false,
+    };
+    if expired {
+        reschedule(ThreadState::Ready);
+    }
+}
+
+// Leaves the current thread in `state` and switches to the next ready
+// thread, if there is one. For a thread that stays ready, this returns
+// once it is scheduled again. Must be called with interrupts disabled.
+pub(super) fn reschedule(state: ThreadState) {
+    let (old_rsp, new_rsp) = {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = match guard.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        let current = scheduler.current;
+        if state == ThreadState::Blocked {
+            let thread = scheduler.threads.get_mut(&current).unwrap();
+            if thread.wakeup_pending {
+                // Already woken, no need to block
+                thread.wakeup_pending = false;
+                return;
+            }
+        }
+        scheduler.slice_left = TIME_SLICE_TICKS;
+
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            // Nothing else to do, keep running
+            None if state == ThreadState::Ready => return,
+            None => scheduler.idle,
+        };
+        if next == current {
+            return;
+        }
+
+        let thread = scheduler.threads.get_mut(&current).unwrap();
+        if thread.stack.as_ref().map_or(false, Stack::overflowed) {
+            panic!(
+                "thread {} ({}) overflowed its stack",
+                current.0, thread.name
+            );
+        }
+        thread.state = state;
+        let old_rsp: *mut u64 = &mut thread.rsp;
+
+        match state {
+            ThreadState::Ready if current != scheduler.idle => scheduler.ready.push_back(current),
+            ThreadState::Exited => {
+                // Its stack is freed by another thread later
+                let thread = scheduler.threads.remove(&current).unwrap();
+                scheduler.exited.push(thread);
+            }
+            _ => {}
+        }
+
+        let thread = scheduler.threads.get_mut(&next).unwrap();
+        thread.state = ThreadState::Running;
+        scheduler.current = next;
+        // The CPU's kernel stack belongs to whichever thread runs user code
+        if let Some(top) = thread.kernel_stack {
+            crate::usermode::load_kernel_stack(top);
+        }
+        crate::memory::address_space::switch_to(thread.page_table);
+        (old_rsp, thread.rsp)
+    };
+
+    // The lock is released, the next thread might need it
+    unsafe { context::switch(old_rsp, new_rsp) };
+}
+
+impl Scheduler {
+    fn unblock(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            if thread.state == ThreadState::Blocked {
+                thread.state = ThreadState::Ready;
+                self.ready.push_back(id);
+            } else {
+                thread.wakeup_pending = true;
+            }
+        }
+    }
+}
+
+// Blocks the current thread until `unblock` is called for it. Returns
+// right away if that happened since the thread last blocked. Must be
+// called with interrupts disabled.
+pub(super) fn block() {
+    assert_boot_cpu();
+    reschedule(ThreadState::Blocked);
+}
+
+// Makes a blocked thread ready again. Can be called from interrupt handlers.
+pub(super) fn unblock(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.unblock(id);
+        }
+    });
+}
+
+// Blocks the current thread until the timer reached `deadline`, or until
+// `stop` returns true when the thread is woken before. Returns whether the
+// deadline was reached.
+pub(super) fn sleep_until(deadline: u64, mut stop: impl FnMut() -> bool) -> bool {
+    let stopped = interrupts::without_interrupts(|| {
+        let initialised = match SCHEDULER.lock().as_mut() {
+            Some(scheduler) => {
+                if time::ticks() < deadline {
+                    let current = scheduler.current;
+                    scheduler.sleepers.push(Reverse((deadline, current)));
+                }
+                true
+            }
+            None => false,
+        };
+
+        if initialised {
+            while time::ticks() < deadline {
+                if stop() {
+                    return true;
+                }
+                block();
+            }
+        }
+        false
+    });
+    if stopped {
+        return false;
+    }
+
+    // Without threads, there is nothing else to run in the meantime
+    while time::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    true
+}
+
+// Remembers the current thread's kernel stack for user mode, so that it
+// is loaded again whenever the thread is switched to
+pub(super) fn set_kernel_stack(top: Option<VirtAddr>) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            let current = scheduler.current;
+            scheduler.threads.get_mut(&current).unwrap().kernel_stack = top;
+        }
+    });
+}
+
+// Remembers the current thread's user address space, so that it is
+// switched to whenever the thread is
+pub(super) fn set_page_table(table: Option<PhysFrame>) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            let current = scheduler.current;
+            scheduler.threads.get_mut(&current).unwrap().page_table = table;
+        }
+    });
+}
+
+pub fn current() -> ThreadId {
+    assert_boot_cpu();
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .expect("threads not initialised")
+            .current
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+}
+
+// Lists all threads that did not exit, ordered by ID
+pub fn list() -> Vec<ThreadInfo> {
+    interrupts::without_interrupts(|| match SCHEDULER.lock().as_ref() {
+        Some(scheduler) => scheduler
+            .threads
+            .values()
+            .map(|thread| ThreadInfo {
+                id: thread.id,
+                name: thread.name,
+                state: thread.state,
+            })
+            .collect(),
+        None => Vec::new(),
+    })
+}
@@ -0,0 +1,165 @@
+// Blocking synchronization primitives for threads
+//
+// Unlike `spin::Mutex`, a thread waiting for these does not spin but
+// blocks until it is woken, so they suit long critical sections. They
+// must not be used from interrupt handlers, which cannot block.
+
+use super::{current, ThreadId, WaitQueue};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Marks a mutex without owner
+const NO_OWNER: u64 = u64::MAX;
+
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    // ID of the thread holding the lock, to detect a thread locking twice
+    owner: AtomicU64,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+// `locked` ensures exclusive access to `data`
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            owner: AtomicU64::new(NO_OWNER),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    // Blocks until the lock is available. Panics if the
+    // current thread already holds it, which would deadlock.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let me = current();
+        if self.owner.load(Ordering::Relaxed) == me.as_u64() {
+            panic!("thread {} locked a mutex it already holds", me.as_u64());
+        }
+
+        loop {
+            if let Some(guard) = self.try_lock_as(me) {
+                return guard;
+            }
+            self.waiters
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.try_lock_as(current())
+    }
+
+    fn try_lock_as(&self, me: ThreadId) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.owner.store(me.as_u64(), Ordering::Relaxed);
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+// A condition variable for waiting on changes to data protected by a
+// `Mutex`. As usual, waiting may wake up spuriously, so the condition
+// has to be checked again after waking up (or `wait_while` be used).
+pub struct Condvar {
+    // Bumped by every notification, so that notifications sent between
+    // unlocking the mutex and blocking are not lost
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // Unlocks the mutex, blocks until notified and locks it again
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+
+        self.waiters
+            .wait_until(|| self.generation.load(Ordering::Acquire) != generation);
+        mutex.lock()
+    }
+
+    // Waits until `condition` returns false for the protected data
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
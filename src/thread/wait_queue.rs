@@ -0,0 +1,100 @@
+use super::{current, scheduler, ThreadId};
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts;
+
+// Threads blocked until some condition holds
+//
+// Waiting threads do not use the CPU. Whoever changes the condition has
+// to call `wake_one` or `wake_all` afterwards, which can also be done
+// from interrupt handlers.
+//
+// A waiting thread may also be woken by something else, e.g. by
+// `thread::wake`. It stays queued until its condition holds, so that
+// `wake_one` never picks a thread that is not waiting anymore.
+pub struct WaitQueue {
+    waiters: spin::Mutex<VecDeque<Waiter>>,
+}
+
+struct Waiter {
+    thread: ThreadId,
+    // Woken by `wake_one` or `wake_all` and not done checking the
+    // condition since, so `wake_one` picks another thread
+    notified: bool,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Blocks the current thread until `condition` returns true. The
+    // condition is checked with interrupts disabled and after queueing
+    // the thread, so a wakeup cannot get lost between checking it and
+    // going to sleep.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        interrupts::without_interrupts(|| {
+            if condition() {
+                return;
+            }
+            let me = current();
+            self.waiters.lock().push_back(Waiter {
+                thread: me,
+                notified: false,
+            });
+            loop {
+                let done = condition();
+                {
+                    let mut waiters = self.waiters.lock();
+                    let index = waiters
+                        .iter()
+                        .position(|waiter| waiter.thread == me)
+                        .expect("wait queue entry vanished");
+                    if done {
+                        waiters.remove(index);
+                        return;
+                    }
+                    waiters[index].notified = false;
+                }
+                scheduler::block();
+            }
+        });
+    }
+
+    // Wakes the thread that has been waiting the longest and was not
+    // woken yet. Returns whether there was one.
+    pub fn wake_one(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            match waiters.iter_mut().find(|waiter| !waiter.notified) {
+                Some(waiter) => {
+                    waiter.notified = true;
+                    scheduler::unblock(waiter.thread);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    // Wakes all waiting threads, returning how many were not woken yet
+    pub fn wake_all(&self) -> usize {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let mut count = 0;
+            for waiter in waiters.iter_mut().filter(|waiter| !waiter.notified) {
+                waiter.notified = true;
+                scheduler::unblock(waiter.thread);
+                count += 1;
+            }
+            count
+        })
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        WaitQueue::new()
+    }
+}
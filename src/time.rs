@@ -0,0 +1,57 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+// Frequency of the oscillator driving the programmable interval timer
+pub const PIT_BASE_FREQUENCY: u64 = 1_193_182;
+// Makes the PIT fire IRQ0 roughly 100 times per second, often enough
+// for the thread scheduler's time slices
+const PIT_DIVISOR: u64 = 11932;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+// Number of timer interrupts since the PICs were enabled
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Programs the PIT's channel 0, which drives the timer interrupt
+pub fn init() {
+    use x86_64::instructions::port::Port;
+
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut data = Port::<u8>::new(PIT_CHANNEL_0);
+    unsafe {
+        // Channel 0, low byte then high byte, mode 3 (square wave)
+        command.write(0x36);
+        data.write(PIT_DIVISOR as u8);
+        data.write((PIT_DIVISOR >> 8) as u8);
+    }
+}
+
+// Called by the timer interrupt handler
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Milliseconds since the timer was started, with the
+// resolution of one timer tick
+pub fn uptime_ms() -> u64 {
+    ticks() * PIT_DIVISOR * 1000 / PIT_BASE_FREQUENCY
+}
+
+// Number of timer ticks that take at least `duration`
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let tick_nanos = u128::from(PIT_DIVISOR) * 1_000_000_000;
+    let ticks =
+        (duration.as_nanos() * u128::from(PIT_BASE_FREQUENCY) + tick_nanos - 1) / tick_nanos;
+    ticks as u64
+}
+
+// Value of the CPU's time stamp counter. Its frequency depends on the
+// CPU, so it is only useful for comparing durations with each other.
+pub fn cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
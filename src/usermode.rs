@@ -0,0 +1,239 @@
+// Running code in user mode (ring 3)
+//
+// `enter` jumps to user code with `sysret` and only returns once the code
+// exits through the exit system call or is killed by an exception. The
+// kernel state at that point is saved at the top of a fresh kernel stack,
+// which is also where system calls and interrupts from user mode run, so
+// leaving user mode for good just means switching back to it.
+//
+// System calls enter the kernel at `syscall_entry`, which switches to the
+// kernel stack, saves the user registers as a `SyscallFrame` and hands it
+// to `syscall::dispatch`. Interrupts are disabled until the stack switch is
+// done. All registers except rax, rcx and r11 are preserved.
+
+use crate::memory::address_space::{self, USER_END};
+use crate::syscall::SyscallFrame;
+use crate::{gdt, percpu, thread};
+use alloc::vec;
+use core::mem::size_of;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+// Size of the kernel stack that system calls and interrupts from user
+// mode run on
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+// Why user code stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    // Through the exit system call, with the status it was given
+    Exited(u64),
+    // Killed by the exception with this vector
+    Faulted(u8),
+    // Stopped by `process::kill`
+    Killed,
+}
+
+// Lives at the top of the kernel stack, right above where the stack
+// pointer starts when entering the kernel from user mode
+struct StackHeader {
+    // Stack pointer saved by `usermode_enter`, to continue at in `enter`
+    return_rsp: u64,
+    exit: Option<Exit>,
+}
+
+extern "C" {
+    // Saves the callee-saved registers, stores the stack pointer in
+    // `*return_rsp` and jumps to `entry` in user mode
+    fn usermode_enter(entry: u64, user_stack: u64, return_rsp: *mut u64);
+    // Returns from `usermode_enter` with the stack pointer it saved
+    fn usermode_return(return_rsp: u64) -> !;
+    fn syscall_entry();
+}
+
+// `CpuData` holds the system call stack at gs:[8] and a slot for the user
+// stack pointer at gs:[16], see `percpu`
+core::arch::global_asm!(
+    r#"
+.global usermode_enter
+usermode_enter:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdx], rsp
+
+    mov rcx, rdi
+    mov rsp, rsi
+    // Only the interrupt flag and the always set bit 1
+    mov r11, 0x202
+    // Do not leak kernel values to user mode
+    xor eax, eax
+    xor ebx, ebx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    swapgs
+    sysretq
+
+.global usermode_return
+usermode_return:
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[16], rsp
+    mov rsp, gs:[8]
+    // In the order of `SyscallFrame`, backwards
+    push qword ptr gs:[16]
+    push r11
+    push rcx
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi, rsp
+    call syscall_dispatch
+    // The handler may have enabled interrupts, but the user stack
+    // pointer must not be in use by the kernel when one arrives
+    cli
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop rcx
+    pop r11
+    pop rsp
+    swapgs
+    sysretq
+"#
+);
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    // `sysret` to a non-canonical address would fault in kernel mode
+    // with the user stack, so such callers are not returned to
+    if frame.rip >= USER_END {
+        exit_current(Exit::Faulted(13));
+    }
+    crate::syscall::dispatch(frame);
+}
+
+// Sets up `syscall` on the calling CPU, after its GDT is loaded
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT layout does not fit syscall");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // Interrupts stay off until the stack is switched, and the user's
+    // direction and trap flags must not leak into the kernel
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) };
+}
+
+// Makes `top` the stack that system calls and interrupts from user mode
+// on the calling CPU start at
+pub(crate) fn load_kernel_stack(top: VirtAddr) {
+    gdt::set_kernel_stack(top);
+    percpu::set_syscall_stack(top);
+}
+
+// Runs user code starting at `entry` with the stack pointer `user_stack`
+// until it exits. The code and stack have to be mapped user accessible.
+pub fn enter(entry: VirtAddr, user_stack: VirtAddr) -> Exit {
+    assert!(entry.as_u64() < USER_END && user_stack.as_u64() < USER_END);
+
+    let mut kernel_stack = vec![0u64; KERNEL_STACK_SIZE / 8].into_boxed_slice();
+    let end = kernel_stack.as_mut_ptr_range().end as u64;
+    // Stack pointers have to be aligned to 16 bytes at that point
+    let header = ((end - size_of::<StackHeader>() as u64) & !15) as *mut StackHeader;
+    unsafe {
+        header.write(StackHeader {
+            return_rsp: 0,
+            exit: None,
+        })
+    };
+    let top = VirtAddr::from_ptr(header);
+
+    let interrupts_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    thread::set_kernel_stack(Some(top));
+    load_kernel_stack(top);
+
+    unsafe {
+        usermode_enter(
+            entry.as_u64(),
+            user_stack.as_u64(),
+            &mut (*header).return_rsp,
+        )
+    };
+
+    // Back through `exit_current`, with interrupts disabled
+    thread::set_kernel_stack(None);
+    let exit = unsafe { (*header).exit.take() }.expect("left user mode without exiting");
+    drop(kernel_stack);
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    exit
+}
+
+// Like `enter`, but with the level 4 page table `table` active while the
+// user code runs, e.g. that of an `AddressSpace`. The kernel's page table
+// is active again afterwards.
+pub fn enter_address_space(table: PhysFrame, entry: VirtAddr, user_stack: VirtAddr) -> Exit {
+    interrupts::without_interrupts(|| {
+        thread::set_page_table(Some(table));
+        address_space::switch_to(Some(table));
+    });
+    let exit = enter(entry, user_stack);
+    interrupts::without_interrupts(|| {
+        thread::set_page_table(None);
+        address_space::activate_kernel();
+    });
+    exit
+}
+
+// Stops the user code running on this CPU, continuing after the call to
+// `enter` that started it. Must only be called in system calls and in
+// handlers of exceptions that happened in user mode.
+pub(crate) fn exit_current(exit: Exit) -> ! {
+    interrupts::disable();
+    let header = gdt::kernel_stack().as_mut_ptr::<StackHeader>();
+    unsafe {
+        (*header).exit = Some(exit);
+        usermode_return((*header).return_rsp)
+    }
+}
@@ -1,6 +1,6 @@
+use crate::lock::SpinLock;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
 #[allow(dead_code)]
@@ -46,6 +46,9 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+// Erases the previous character instead of being printed
+const BACKSPACE: u8 = 0x08;
+
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
@@ -70,7 +73,7 @@ impl Writer {
                 // Printable ASCII (anything between a
                 // space and tilde) and newline
                 // https://en.wikipedia.org/wiki/Code_page_437#Character_set
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                0x20..=0x7e | b'\n' | BACKSPACE => self.write_byte(byte),
                 // We print ■ for unprintable chars
                 _ => self.write_byte(0xfe),
             }
@@ -80,6 +83,7 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            BACKSPACE => self.backspace(),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -118,6 +122,21 @@ impl Writer {
         self.column_position = 0;
     }
 
+    // Erase the last character of the current row, but never
+    // go back to the previous row
+    fn backspace(&mut self) {
+        if self.column_position == 0 {
+            return;
+        }
+
+        self.column_position -= 1;
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(blank);
+    }
+
     // Write blank characters to an entire row to clear it
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
@@ -141,7 +160,7 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: SpinLock<Writer> = SpinLock::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -169,13 +188,21 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // Ensure that no interrupts occur while holding the WRITER lock
-    // so that we can safely invoke this function in interrupt handlers
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    // The lock keeps interrupts disabled while it is held, so that
+    // we can safely invoke this function in interrupt handlers
+    let mut writer = WRITER.lock();
+    writer.write_fmt(args).unwrap();
+    crate::log::dmesg::record_console(args);
+}
+
+// Like `_print`, but leaves the output out of the kernel log, e.g.
+// because it was taken from there
+#[doc(hidden)]
+pub fn _print_screen(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 // Ensuring that writing to the VGA buffer does not cause
@@ -216,3 +243,20 @@ fn test_println_output() {
         }
     });
 }
+
+// Ensure that backspace erases the previously printed character
+#[test_case]
+fn test_backspace() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\nab\x08").expect("write failed!");
+
+        let row = BUFFER_HEIGHT - 1;
+        assert_eq!(writer.buffer.chars[row][0].read().ascii_character, b'a');
+        assert_eq!(writer.buffer.chars[row][1].read().ascii_character, b' ');
+        assert_eq!(writer.column_position, 1);
+    });
+}
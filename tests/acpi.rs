@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::acpi;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// QEMU's tables, with the test runner's `-smp 4`
+#[test_case]
+fn madt_lists_cpus_and_io_apic() {
+    let madt = acpi::madt().expect("no MADT");
+    assert_eq!(madt.processors.len(), 4);
+    assert_eq!(madt.local_apic_address.as_u64(), 0xfee0_0000);
+    assert!(madt.has_legacy_pics);
+
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_eq!(madt.io_apics[0].address.as_u64(), 0xfec0_0000);
+    assert_eq!(madt.io_apics[0].gsi_base, 0);
+    // The PIT is wired to the second I/O APIC input
+    assert_eq!(madt.isa_irq_to_gsi(0), 2);
+    assert_eq!(madt.isa_irq_to_gsi(1), 1);
+}
+
+#[test_case]
+fn fadt_has_power_management_ports() {
+    let fadt = acpi::fadt().expect("no FADT");
+    assert!(fadt.pm1a_control_block.is_some());
+    assert!(fadt.pm_timer_block.is_some());
+    assert_eq!(fadt.pm1_control_length, 2);
+    assert_ne!(fadt.dsdt.as_u64(), 0);
+    assert!(!fadt.hardware_reduced);
+}
+
+#[test_case]
+fn hpet_is_memory_mapped() {
+    let hpet = acpi::hpet().expect("no HPET");
+    assert_eq!(hpet.address.as_u64(), 0xfed0_0000);
+    assert!(hpet.comparators >= 3);
+}
+
+#[test_case]
+fn root_table_lists_the_tables() {
+    let signatures = acpi::table_signatures();
+    assert!(signatures.contains(b"APIC"));
+    assert!(signatures.contains(b"FACP"));
+}
+
+#[test_case]
+fn dsdt_has_soft_off_state() {
+    assert!(acpi::s5_sleep_type().is_some());
+}
@@ -0,0 +1,178 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use futures_util::{SinkExt, StreamExt};
+use rust_os::task::{
+    channel::{broadcast, mpsc, oneshot, SendError},
+    executor::Executor,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn bounded_sender_waits_for_space() {
+    static RECEIVED: spin::Mutex<Vec<u32>> = spin::Mutex::new(Vec::new());
+
+    let (sender, mut receiver) = mpsc::channel(2);
+    let mut executor = Executor::new();
+    executor.spawn(async move {
+        for i in 0..10 {
+            sender.send(i).await.unwrap();
+        }
+    });
+    executor.spawn(async move {
+        while let Some(value) = receiver.next().await {
+            RECEIVED.lock().push(value);
+        }
+    });
+    executor.run_until_idle();
+
+    assert_eq!(*RECEIVED.lock(), (0..10).collect::<Vec<_>>());
+}
+
+#[test_case]
+fn unbounded_sink_and_stream() {
+    static SUM: spin::Mutex<u32> = spin::Mutex::new(0);
+
+    let (mut sender, receiver) = mpsc::unbounded();
+    let mut executor = Executor::new();
+    executor.spawn(async move {
+        for i in 1..=10 {
+            SinkExt::send(&mut sender, i).await.unwrap();
+        }
+    });
+    executor.spawn(async move {
+        *SUM.lock() = receiver
+            .fold(0, |sum, value| async move { sum + value })
+            .await;
+    });
+    executor.run_until_idle();
+
+    assert_eq!(*SUM.lock(), 55);
+}
+
+#[test_case]
+fn irq_sender_fails_when_full() {
+    let (sender, mut receiver) = mpsc::channel(2);
+    let irq_sender = sender.irq_sender();
+    drop(sender);
+
+    assert_eq!(irq_sender.try_send(1), Ok(()));
+    assert_eq!(irq_sender.try_send(2), Ok(()));
+    assert_eq!(irq_sender.try_send(3), Err(mpsc::TrySendError::Full(3)));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(irq_sender.try_send(3), Ok(()));
+
+    drop(irq_sender);
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert_eq!(receiver.try_recv(), Ok(3));
+    assert_eq!(receiver.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+}
+
+#[test_case]
+fn send_fails_after_receiver_dropped() {
+    let (sender, receiver) = mpsc::channel(2);
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.try_send(1), Err(mpsc::TrySendError::Disconnected(1)));
+}
+
+#[test_case]
+fn oneshot_delivers_value() {
+    static RESULT: spin::Mutex<Option<Result<u32, oneshot::RecvError>>> = spin::Mutex::new(None);
+
+    let (sender, receiver) = oneshot::channel();
+    let mut executor = Executor::new();
+    executor.spawn(async move {
+        *RESULT.lock() = Some(receiver.await);
+    });
+    executor.run_until_idle();
+    assert_eq!(*RESULT.lock(), None);
+
+    sender.send(42).unwrap();
+    executor.run_until_idle();
+    assert_eq!(*RESULT.lock(), Some(Ok(42)));
+}
+
+#[test_case]
+fn oneshot_reports_dropped_side() {
+    let (sender, receiver) = oneshot::channel::<u32>();
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.send(1), Err(1));
+
+    let (sender, mut receiver) = oneshot::channel::<u32>();
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Err(oneshot::RecvError));
+}
+
+#[test_case]
+fn broadcast_reaches_every_receiver() {
+    static RECEIVED: spin::Mutex<Vec<(usize, u32)>> = spin::Mutex::new(Vec::new());
+
+    let (sender, first) = broadcast::channel(4);
+    let second = sender.subscribe();
+    let mut executor = Executor::new();
+    for (index, mut receiver) in vec![first, second].into_iter().enumerate() {
+        executor.spawn(async move {
+            while let Ok(value) = receiver.recv().await {
+                RECEIVED.lock().push((index, value));
+            }
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(sender.send(1), Ok(2));
+    assert_eq!(sender.send(2), Ok(2));
+    drop(sender);
+    executor.run_until_idle();
+
+    let mut received = RECEIVED.lock().clone();
+    received.sort_unstable();
+    assert_eq!(received, [(0, 1), (0, 2), (1, 1), (1, 2)]);
+}
+
+#[test_case]
+fn broadcast_receiver_lags_behind() {
+    let (sender, mut receiver) = broadcast::channel(2);
+    for i in 0..5 {
+        sender.send(i).unwrap();
+    }
+
+    assert_eq!(receiver.try_recv(), Err(broadcast::TryRecvError::Lagged(3)));
+    assert_eq!(receiver.try_recv(), Ok(3));
+    assert_eq!(receiver.try_recv(), Ok(4));
+    assert_eq!(receiver.try_recv(), Err(broadcast::TryRecvError::Empty));
+
+    drop(receiver);
+    assert_eq!(sender.send(5), Err(SendError(5)));
+}
@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::elf::{self, embedded, ElfError};
+use rust_os::memory;
+use rust_os::usermode::Exit;
+use x86_64::registers::control::Cr3;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    rust_os::thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn hello() -> &'static [u8] {
+    embedded::find("hello").expect("hello is not embedded")
+}
+
+// See user/hello.S for how the exit status comes about
+#[test_case]
+fn program_gets_arguments_and_environment() {
+    let program = elf::load(hello(), &["hello", "a", "b"], &["X=1"]).expect("load failed");
+    assert_eq!(program.run(), Exit::Exited(311));
+}
+
+#[test_case]
+fn kernel_page_table_is_restored() {
+    let before = Cr3::read().0;
+    let program = elf::load(hello(), &[], &[]).expect("load failed");
+    assert!(!program.address_space().is_active());
+    assert_eq!(program.run(), Exit::Exited(1));
+    assert_eq!(Cr3::read().0, before);
+}
+
+#[test_case]
+fn stack_is_set_up() {
+    let program = elf::load(hello(), &["hello"], &[]).expect("load failed");
+    let stack_pointer = program.stack_pointer();
+    assert_eq!(stack_pointer.as_u64() % 16, 0);
+
+    let space = program.address_space();
+    assert!(space.translate(stack_pointer).is_some());
+    assert!(space.translate(program.entry()).is_some());
+    // The guard page below the stack
+    let guard = VirtAddr::new(memory::address_space::USER_END - elf::STACK_SIZE - 1);
+    assert!(space.translate(guard).is_none());
+}
+
+// Dropping a program frees its frames, page tables included
+#[test_case]
+fn frames_are_freed() {
+    let first = elf::load(hello(), &[], &[]).expect("load failed");
+    let table = first.address_space().page_table();
+    drop(first);
+    // The level 4 table is freed last and taken first
+    let second = elf::load(hello(), &[], &[]).expect("load failed");
+    assert_eq!(second.address_space().page_table(), table);
+}
+
+// Every thread switches to its own address space
+#[test_case]
+fn programs_run_in_threads() {
+    let handles: Vec<_> = (0..3)
+        .map(|i| {
+            rust_os::thread::spawn("user", move || {
+                let argv = ["x"; 3];
+                let program = elf::load(hello(), &argv[..i], &[]).expect("load failed");
+                program.run()
+            })
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), Exit::Exited(i as u64 * 100 + 1));
+    }
+}
+
+// Overwrites a little-endian field of a copy of hello
+fn patched(offset: usize, value: &[u8]) -> Vec<u8> {
+    let mut image = hello().to_vec();
+    image[offset..offset + value.len()].copy_from_slice(value);
+    image
+}
+
+fn load_error(image: &[u8]) -> Option<ElfError> {
+    elf::load(image, &[], &[]).err()
+}
+
+#[test_case]
+fn bad_headers_are_rejected() {
+    assert_eq!(load_error(&hello()[..40]), Some(ElfError::Truncated));
+    assert_eq!(
+        load_error(&patched(0, b"\x7fELG")),
+        Some(ElfError::BadMagic)
+    );
+    // A shared object
+    assert_eq!(
+        load_error(&patched(16, &[3, 0])),
+        Some(ElfError::NotExecutable)
+    );
+    // AArch64
+    assert_eq!(
+        load_error(&patched(18, &[0xb7, 0])),
+        Some(ElfError::WrongMachine)
+    );
+}
+
+#[test_case]
+fn bad_segments_are_rejected() {
+    // The first program header is at 64, its address at 16 into it
+    let kernel = 0xffff_8000_0000_0000u64.to_le_bytes();
+    assert_eq!(
+        load_error(&patched(80, &kernel)),
+        Some(ElfError::SegmentOutOfRange)
+    );
+    // File size larger than memory size
+    assert_eq!(
+        load_error(&patched(96, &u64::MAX.to_le_bytes())),
+        Some(ElfError::BadProgramHeader)
+    );
+    // Entry point outside of the code
+    let entry = 0x0000_0800_0000_0000u64.to_le_bytes();
+    assert_eq!(load_error(&patched(24, &entry)), Some(ElfError::BadEntry));
+}
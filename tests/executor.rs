@@ -0,0 +1,280 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use rust_os::task::{self, executor::Executor, JoinError, Priority};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_handle_yields_output() {
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { 42 });
+    executor.spawn(async move {
+        RESULT.store(handle.await.unwrap(), Ordering::SeqCst);
+    });
+    executor.run_until_idle();
+
+    assert_eq!(RESULT.load(Ordering::SeqCst), 42);
+}
+
+#[test_case]
+fn detached_task_still_runs() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    executor
+        .spawn(async {
+            RAN.store(true, Ordering::SeqCst);
+        })
+        .detach();
+    executor.run_until_idle();
+
+    assert!(RAN.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn abort_drops_future_and_wakes_joiner() {
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+    static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+    struct SetOnDrop;
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            DROPPED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async {
+        let _guard = SetOnDrop;
+        futures_util::future::pending::<()>().await;
+    });
+    executor.run_until_idle();
+    assert!(!handle.is_finished());
+
+    handle.abort();
+    executor.spawn(async move {
+        CANCELLED.store(handle.await == Err(JoinError::Cancelled), Ordering::SeqCst);
+    });
+    executor.run_until_idle();
+
+    assert!(DROPPED.load(Ordering::SeqCst));
+    assert!(CANCELLED.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn spawner_spawns_from_running_task() {
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(async move {
+        let inner = spawner.clone();
+        let handle = spawner.spawn(async move { inner.spawn(async { 7 }).await.unwrap() * 6 });
+        RESULT.store(handle.await.unwrap(), Ordering::SeqCst);
+    });
+    executor.run_until_idle();
+
+    assert_eq!(RESULT.load(Ordering::SeqCst), 42);
+}
+
+#[test_case]
+fn more_tasks_than_queue_capacity() {
+    static COMPLETED: AtomicU64 = AtomicU64::new(0);
+
+    // More tasks than fit into the ready queue at once
+    let mut executor = Executor::new();
+    for _ in 0..300 {
+        executor.spawn(async {
+            COMPLETED.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(COMPLETED.load(Ordering::SeqCst), 300);
+    assert!(executor.queue_overflows() > 0);
+}
+
+#[test_case]
+fn repeated_wakes_poll_once() {
+    use core::task::Poll;
+
+    static POLLS: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(futures_util::future::poll_fn(|cx| {
+        if POLLS.fetch_add(1, Ordering::SeqCst) == 0 {
+            // Waking many times before the next poll
+            // only queues the task once
+            for _ in 0..1000 {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }));
+    executor.run_until_idle();
+
+    assert_eq!(POLLS.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn high_priority_task_runs_at_next_await() {
+    static ORDER: spin::Mutex<Vec<&str>> = spin::Mutex::new(Vec::new());
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn_with_priority(
+        async move {
+            ORDER.lock().push("low 1");
+            spawner
+                .spawn_with_priority(async { ORDER.lock().push("high") }, Priority::High)
+                .detach();
+            task::yield_now().await;
+            ORDER.lock().push("low 2");
+        },
+        Priority::Low,
+    );
+    executor.spawn(async { ORDER.lock().push("normal") });
+    executor.run_until_idle();
+
+    assert_eq!(*ORDER.lock(), ["normal", "low 1", "high", "low 2"]);
+}
+
+#[test_case]
+fn busy_high_priority_task_does_not_starve_low() {
+    static HIGH_ITERATIONS: AtomicU64 = AtomicU64::new(0);
+    static HIGH_SEEN_BY_LOW: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn_with_priority(
+        async {
+            for _ in 0..100 {
+                HIGH_ITERATIONS.fetch_add(1, Ordering::SeqCst);
+                task::yield_now().await;
+            }
+        },
+        Priority::High,
+    );
+    executor.spawn_with_priority(
+        async {
+            HIGH_SEEN_BY_LOW.store(HIGH_ITERATIONS.load(Ordering::SeqCst), Ordering::SeqCst);
+        },
+        Priority::Low,
+    );
+    executor.run_until_idle();
+
+    assert!(HIGH_SEEN_BY_LOW.load(Ordering::SeqCst) < 100);
+}
+
+#[test_case]
+fn exhausted_budget_yields() {
+    static ITERATIONS: AtomicU64 = AtomicU64::new(0);
+    static SEEN_BY_OTHER: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(async {
+        for _ in 0..200 {
+            ITERATIONS.fetch_add(1, Ordering::SeqCst);
+            task::consume_budget().await;
+        }
+    });
+    executor.spawn(async {
+        SEEN_BY_OTHER.store(ITERATIONS.load(Ordering::SeqCst), Ordering::SeqCst);
+    });
+    executor.run_until_idle();
+
+    assert_eq!(ITERATIONS.load(Ordering::SeqCst), 200);
+    let seen = SEEN_BY_OTHER.load(Ordering::SeqCst);
+    assert!(seen > 0 && seen < 200);
+}
+
+#[test_case]
+fn tasks_are_listed_with_their_stats() {
+    use rust_os::task::stats::{self, TaskState};
+    use rust_os::task::{channel::oneshot, SpawnOptions};
+
+    let find = || {
+        stats::list()
+            .into_iter()
+            .find(|task| task.name == Some("listed"))
+            .expect("task not listed")
+    };
+
+    let (sender, receiver) = oneshot::channel::<()>();
+    let mut executor = Executor::new();
+    executor.spawn_with(
+        async move {
+            receiver.await.unwrap();
+        },
+        SpawnOptions::named("listed"),
+    );
+    executor.run_until_idle();
+
+    let task = find();
+    assert_eq!(task.state, TaskState::Pending);
+    assert_eq!(task.polls, 1);
+    assert!(task.poll_cycles > 0);
+
+    sender.send(()).unwrap();
+    assert_eq!(find().state, TaskState::Ready);
+    executor.run_until_idle();
+
+    let task = find();
+    assert_eq!(task.state, TaskState::Finished);
+    assert_eq!(task.polls, 2);
+}
+
+#[test_case]
+fn long_polls_are_counted() {
+    use rust_os::task::stats::{self, TaskState};
+    use rust_os::task::SpawnOptions;
+
+    let threshold = stats::long_poll_threshold();
+    stats::set_long_poll_threshold(0);
+    let mut executor = Executor::new();
+    executor.spawn_with(async {}, SpawnOptions::named("long poll"));
+    executor.run_until_idle();
+    stats::set_long_poll_threshold(threshold);
+
+    let task = stats::list()
+        .into_iter()
+        .find(|task| task.name == Some("long poll"))
+        .expect("task not listed");
+    assert_eq!(task.state, TaskState::Finished);
+    assert_eq!(task.long_polls, 1);
+}
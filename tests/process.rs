@@ -0,0 +1,155 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::elf::embedded;
+use rust_os::process::file::{Console, File, FileTable, STDERR};
+use rust_os::process::{self, Pid, ProcessError, ProcessState};
+use rust_os::syscall::Errno;
+use rust_os::thread;
+use rust_os::usermode::Exit;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn spawn(parent: Option<Pid>, name: &str, argv: &[&str]) -> Pid {
+    let image = embedded::find(name).expect("program not embedded");
+    process::spawn(parent, name, image, argv, &[]).expect("spawn failed")
+}
+
+fn wait_until_exited(pid: Pid) {
+    while process::state(pid) == Ok(ProcessState::Running) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn exit_status_is_collected() {
+    let pid = spawn(None, "hello", &["hello", "a"]);
+    assert_eq!(process::waitpid(pid), Ok(Exit::Exited(201)));
+    // Reaped
+    assert_eq!(process::state(pid), Err(ProcessError::NoSuchProcess));
+    assert_eq!(process::waitpid(pid), Err(ProcessError::NoSuchProcess));
+}
+
+#[test_case]
+fn exited_processes_are_zombies() {
+    let pid = spawn(None, "hello", &[]);
+    wait_until_exited(pid);
+    assert_eq!(
+        process::state(pid),
+        Ok(ProcessState::Zombie(Exit::Exited(1)))
+    );
+    assert_eq!(process::try_waitpid(pid), Ok(Some(Exit::Exited(1))));
+    assert_eq!(process::try_waitpid(pid), Err(ProcessError::NoSuchProcess));
+}
+
+#[test_case]
+fn killed_process_exits() {
+    let pid = spawn(None, "spin", &[]);
+    assert_eq!(process::try_waitpid(pid), Ok(None));
+    process::kill(pid).unwrap();
+    assert_eq!(process::waitpid(pid), Ok(Exit::Killed));
+}
+
+#[test_case]
+fn wait_collects_any_child() {
+    let parent = spawn(None, "spin", &[]);
+    let first = spawn(Some(parent), "hello", &["hello"]);
+    let second = spawn(Some(parent), "hello", &["hello", "a", "b"]);
+    assert_eq!(process::parent(first), Ok(Some(parent)));
+
+    let (pid, exit) = process::wait(Some(parent)).unwrap();
+    let (other, other_exit) = process::wait(Some(parent)).unwrap();
+    if pid == first {
+        assert_eq!(
+            (exit, other, other_exit),
+            (Exit::Exited(101), second, Exit::Exited(301))
+        );
+    } else {
+        assert_eq!(
+            (pid, exit, other_exit),
+            (second, Exit::Exited(301), Exit::Exited(101))
+        );
+    }
+    assert_eq!(process::wait(Some(parent)), Err(ProcessError::NoChildren));
+
+    process::kill(parent).unwrap();
+    assert_eq!(process::waitpid(parent), Ok(Exit::Killed));
+}
+
+#[test_case]
+fn orphans_are_adopted_by_the_kernel() {
+    let parent = spawn(None, "spin", &[]);
+    let child = spawn(Some(parent), "spin", &[]);
+    process::kill(parent).unwrap();
+    assert_eq!(process::waitpid(parent), Ok(Exit::Killed));
+    assert_eq!(process::parent(child), Ok(None));
+    // Reaped by the kernel when it exits
+    assert_eq!(
+        process::try_waitpid(child),
+        Err(ProcessError::NoSuchProcess)
+    );
+
+    process::kill(child).unwrap();
+    while process::state(child).is_ok() {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn processes_are_listed() {
+    let pid = spawn(None, "spin", &[]);
+    let info = process::list()
+        .into_iter()
+        .find(|process| process.pid == pid)
+        .expect("process not listed");
+    assert_eq!(info.name, "spin");
+    assert_eq!(info.parent, None);
+    // Standard input, output and error
+    assert_eq!(info.open_files, 3);
+
+    process::kill(pid).unwrap();
+    process::waitpid(pid).unwrap();
+    assert!(process::list().iter().all(|process| process.pid != pid));
+}
+
+#[test_case]
+fn file_descriptors_are_reused() {
+    let mut files = FileTable::with_console();
+    assert_eq!(files.len(), 3);
+    let console: Arc<dyn File> = Arc::new(Console);
+    assert_eq!(files.insert(console.clone()), Ok(3));
+    assert!(files.remove(STDERR).is_ok());
+    assert_eq!(files.remove(STDERR).err(), Some(Errno::EBADF));
+    assert_eq!(files.insert(console), Ok(STDERR));
+    assert!(files.get(4).is_none());
+}
@@ -0,0 +1,212 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os::task::multicore;
+use rust_os::{smp, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    smp::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// The tests run with `-smp 4`
+#[test_case]
+fn all_cpus_are_online() {
+    assert_eq!(smp::cpu_count(), 4);
+    assert_eq!(smp::online_cpus(), 4);
+    assert!(smp::is_boot_cpu());
+}
+
+// Waits for at most a second for `condition` to hold
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = time::ticks() + 100;
+    while !condition() && time::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    condition()
+}
+
+#[test_case]
+fn tasks_run_on_aps() {
+    static COMPLETED: AtomicU64 = AtomicU64::new(0);
+    static ON_BOOT_CPU: AtomicU64 = AtomicU64::new(0);
+
+    // The boot CPU does not run an executor in this test
+    for _ in 0..8 {
+        multicore::spawn(async {
+            if smp::is_boot_cpu() {
+                ON_BOOT_CPU.fetch_add(1, Ordering::SeqCst);
+            }
+            COMPLETED.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+    }
+
+    assert!(wait_for(|| COMPLETED.load(Ordering::SeqCst) == 8));
+    assert_eq!(ON_BOOT_CPU.load(Ordering::SeqCst), 0);
+}
+
+#[test_case]
+fn idle_cpu_steals_queued_task() {
+    const NONE: u64 = u64::MAX;
+    static OUTER_CPU: AtomicU64 = AtomicU64::new(NONE);
+    static INNER_CPU: AtomicU64 = AtomicU64::new(NONE);
+
+    multicore::spawn(async {
+        OUTER_CPU.store(smp::current_cpu() as u64, Ordering::SeqCst);
+        // Queued on this CPU, which stays busy until the task ran
+        // elsewhere, so another CPU has to steal it
+        multicore::spawn(async {
+            INNER_CPU.store(smp::current_cpu() as u64, Ordering::SeqCst);
+        })
+        .detach();
+        let deadline = time::ticks() + 100;
+        while INNER_CPU.load(Ordering::SeqCst) == NONE && time::ticks() < deadline {
+            core::hint::spin_loop();
+        }
+    })
+    .detach();
+
+    assert!(wait_for(|| INNER_CPU.load(Ordering::SeqCst) != NONE));
+    assert_ne!(
+        OUTER_CPU.load(Ordering::SeqCst),
+        INNER_CPU.load(Ordering::SeqCst)
+    );
+}
+
+#[test_case]
+fn wakeup_from_other_cpu_reaches_task() {
+    use rust_os::task::channel::oneshot;
+
+    static RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+    let (sender, receiver) = oneshot::channel();
+    multicore::spawn(async move {
+        RECEIVED.store(receiver.await.unwrap(), Ordering::SeqCst);
+    })
+    .detach();
+
+    // Give the task time to start waiting and its CPU to halt
+    let deadline = time::ticks() + 5;
+    while time::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    sender.send(42).unwrap();
+
+    assert!(wait_for(|| RECEIVED.load(Ordering::SeqCst) == 42));
+}
+
+#[test_case]
+fn call_runs_on_every_other_cpu() {
+    static RAN_ON: AtomicU64 = AtomicU64::new(0);
+
+    smp::call_on_others(|| {
+        RAN_ON.fetch_or(1 << smp::current_cpu(), Ordering::SeqCst);
+    });
+
+    let all = (1 << smp::cpu_count()) - 1;
+    assert_eq!(RAN_ON.load(Ordering::SeqCst), all & !1);
+}
+
+#[test_case]
+fn remapping_is_seen_by_other_cpus() {
+    use rust_os::memory;
+    use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+    use x86_64::VirtAddr;
+
+    static SEEN: AtomicU64 = AtomicU64::new(0);
+
+    let write_frame = |frame: PhysFrame, value: u64| {
+        let offset = memory::physical_memory_offset().unwrap();
+        let virt = offset + frame.start_address().as_u64();
+        unsafe { core::ptr::write_volatile(virt.as_mut_ptr::<u64>(), value) };
+    };
+    let first = memory::allocate_frame().unwrap();
+    let second = memory::allocate_frame().unwrap();
+    write_frame(first, 1);
+    write_frame(second, 2);
+
+    let page = Page::containing_address(VirtAddr::new(0x6666_0000_0000));
+    let read_on_cpu_1 = move || {
+        smp::call_on(1, move || {
+            let value = unsafe { core::ptr::read_volatile(page.start_address().as_ptr::<u64>()) };
+            SEEN.store(value, Ordering::SeqCst);
+        });
+        SEEN.load(Ordering::SeqCst)
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // CPU 1 caches the translation to the first frame in its TLB
+    unsafe { memory::map(page, first, flags).unwrap() };
+    assert_eq!(read_on_cpu_1(), 1);
+
+    let shootdowns = memory::tlb::shootdowns();
+    memory::unmap(page).unwrap();
+    unsafe { memory::map(page, second, flags).unwrap() };
+    assert_eq!(read_on_cpu_1(), 2);
+    assert_eq!(memory::tlb::shootdowns(), shootdowns + 1);
+
+    memory::unmap(page).unwrap();
+}
+
+// Runs last, as the executor keeps its CPU for good
+#[test_case]
+fn spawner_wakes_idle_executor_on_other_cpu() {
+    use rust_os::interrupts::{stats, InterruptIndex};
+    use rust_os::task::executor::{Executor, Spawner};
+
+    static SPAWNER: spin::Mutex<Option<Spawner>> = spin::Mutex::new(None);
+    static RAN_ON: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    multicore::spawn(async {
+        let mut executor = Executor::new();
+        *SPAWNER.lock() = Some(executor.spawner());
+        executor.run();
+    })
+    .detach();
+    assert!(wait_for(|| SPAWNER.lock().is_some()));
+
+    // Give the executor time to halt
+    let deadline = time::ticks() + 5;
+    while time::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    let wakeups = stats::count(InterruptIndex::Wakeup.as_u8());
+    let spawner = SPAWNER.lock().clone().unwrap();
+    spawner
+        .spawn(async {
+            RAN_ON.store(smp::current_cpu() as u64, Ordering::SeqCst);
+        })
+        .detach();
+
+    assert!(wait_for(|| RAN_ON.load(Ordering::SeqCst) != u64::MAX));
+    assert_ne!(RAN_ON.load(Ordering::SeqCst), 0);
+    assert!(stats::count(InterruptIndex::Wakeup.as_u8()) > wakeups);
+}
@@ -0,0 +1,213 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::{pin_mut, task::noop_waker};
+use rust_os::task::{
+    self,
+    executor::Executor,
+    sync::{Barrier, Mutex, Notify, RwLock, Semaphore},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn mutex_guard_held_across_await() {
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..10 {
+        executor.spawn(async {
+            let mut counter = COUNTER.lock().await;
+            let value = *counter;
+            // Everybody else gets to run while we hold the lock
+            task::yield_now().await;
+            *counter = value + 1;
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(*COUNTER.try_lock().unwrap(), 10);
+}
+
+#[test_case]
+fn mutex_try_lock_fails_while_locked() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock();
+    assert!(guard.is_some());
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert!(mutex.try_lock().is_some());
+}
+
+#[test_case]
+fn rwlock_allows_concurrent_readers() {
+    static LOCK: RwLock<usize> = RwLock::new(0);
+    static READING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_READING: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(async {
+            let _guard = LOCK.read().await;
+            let reading = READING.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_READING.fetch_max(reading, Ordering::SeqCst);
+            task::yield_now().await;
+            READING.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    executor.spawn(async {
+        let mut value = LOCK.write().await;
+        assert_eq!(READING.load(Ordering::SeqCst), 0);
+        *value += 1;
+    });
+    executor.run_until_idle();
+
+    assert_eq!(MAX_READING.load(Ordering::SeqCst), 3);
+    assert_eq!(*LOCK.try_read().unwrap(), 1);
+}
+
+#[test_case]
+fn rwlock_waiting_writer_blocks_new_readers() {
+    let lock = RwLock::new(());
+    let reader = lock.try_read().unwrap();
+
+    let write = lock.write();
+    pin_mut!(write);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    assert!(write.as_mut().poll(&mut cx).is_pending());
+
+    assert!(lock.try_read().is_none());
+    drop(reader);
+    assert!(write.as_mut().poll(&mut cx).is_ready());
+}
+
+#[test_case]
+fn semaphore_limits_concurrency() {
+    static SEMAPHORE: Semaphore = Semaphore::new(2);
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..5 {
+        executor.spawn(async {
+            let _permit = SEMAPHORE.acquire().await;
+            let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+            task::yield_now().await;
+            RUNNING.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), 2);
+    assert_eq!(SEMAPHORE.available_permits(), 2);
+}
+
+#[test_case]
+fn dropped_acquire_gives_up_its_place() {
+    let semaphore = Semaphore::new(1);
+    let permit = semaphore.try_acquire().unwrap();
+
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    {
+        let acquire = semaphore.acquire();
+        pin_mut!(acquire);
+        assert!(acquire.as_mut().poll(&mut cx).is_pending());
+        drop(permit);
+        // The permit was handed to the waiting future, which is dropped
+        // here without using it
+    }
+
+    assert_eq!(semaphore.available_permits(), 1);
+    assert!(semaphore.try_acquire().is_some());
+}
+
+#[test_case]
+fn notify_one_before_waiting_is_not_lost() {
+    let notify = Notify::new();
+    notify.notify_one();
+
+    let notified = notify.notified();
+    pin_mut!(notified);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(notified.poll(&mut cx), Poll::Ready(()));
+}
+
+#[test_case]
+fn notify_waiters_wakes_everyone() {
+    static NOTIFY: Notify = Notify::new();
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(async {
+            NOTIFY.notified().await;
+            WOKEN.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    executor.run_until_idle();
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 0);
+
+    NOTIFY.notify_waiters();
+    executor.run_until_idle();
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 3);
+}
+
+#[test_case]
+fn barrier_releases_all_parties() {
+    static BARRIER: Barrier = Barrier::new(3);
+    static ARRIVED: AtomicUsize = AtomicUsize::new(0);
+    static PASSED: AtomicUsize = AtomicUsize::new(0);
+    static LEADERS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(async {
+            ARRIVED.fetch_add(1, Ordering::SeqCst);
+            let result = BARRIER.wait().await;
+            // Nobody passes before everybody arrived
+            assert_eq!(ARRIVED.load(Ordering::SeqCst), 3);
+            PASSED.fetch_add(1, Ordering::SeqCst);
+            if result.is_leader() {
+                LEADERS.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(PASSED.load(Ordering::SeqCst), 3);
+    assert_eq!(LEADERS.load(Ordering::SeqCst), 1);
+}
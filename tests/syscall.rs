@@ -0,0 +1,179 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use rust_os::elf::embedded;
+use rust_os::fs::{self, O_APPEND, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+use rust_os::memory::address_space::USER_START;
+use rust_os::memory::AddressSpace;
+use rust_os::process::memory::{Memory, MMAP_START};
+use rust_os::process::{self, ProcessState};
+use rust_os::syscall::{user, Errno};
+use rust_os::thread;
+use rust_os::usermode::Exit;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    rust_os::thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// user/syscalls.S exits with the number of the check that failed
+#[test_case]
+fn system_calls_work_from_user_mode() {
+    let image = embedded::find("syscalls").expect("program not embedded");
+    let pid = process::spawn(None, "syscalls", image, &["syscalls"], &[]).unwrap();
+    // The last check is that unmapped memory is gone
+    assert_eq!(process::waitpid(pid), Ok(Exit::Faulted(14)));
+    assert_eq!(fs::read_file("/tmp/syscalls").unwrap(), b"abc");
+}
+
+// Would take an hour if the sleep or the console read were not woken
+#[test_case]
+fn killing_ends_blocking_system_calls() {
+    let image = embedded::find("block").expect("program not embedded");
+    for argv in &[&["block"][..], &["block", "read"][..]] {
+        let pid = process::spawn(None, "block", image, argv, &[]).unwrap();
+        // Time to get blocked
+        thread::sleep(Duration::from_millis(50));
+        process::kill(pid).unwrap();
+        assert_eq!(process::waitpid(pid), Ok(Exit::Killed));
+    }
+}
+
+// The second reader waits for the first one's lock on the console, where
+// killing it only wakes it early
+#[test_case]
+fn killing_queued_console_reader() {
+    let image = embedded::find("block").expect("program not embedded");
+    let argv = &["block", "read"];
+    let first = process::spawn(None, "block", image, argv, &[]).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let second = process::spawn(None, "block", image, argv, &[]).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    process::kill(second).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(process::state(second), Ok(ProcessState::Running));
+    process::kill(first).unwrap();
+    assert_eq!(process::waitpid(first), Ok(Exit::Killed));
+    assert_eq!(process::waitpid(second), Ok(Exit::Killed));
+}
+
+#[test_case]
+fn user_pointers_are_checked() {
+    // Not in the user range at all
+    assert_eq!(
+        user::copy_from_user(0xffff_8000_0000_0000, 8),
+        Err(Errno::EFAULT)
+    );
+    assert_eq!(user::copy_from_user(u64::MAX - 2, 8), Err(Errno::EFAULT));
+    assert_eq!(user::copy_to_user(0x1000, &[1]), Err(Errno::EFAULT));
+    // The calling thread is not a process, so it has no user memory
+    assert_eq!(user::check(USER_START, 8, false), Err(Errno::EFAULT));
+}
+
+#[test_case]
+fn files_can_be_written_and_read() {
+    let file = fs::open("/test/file", O_CREAT | O_WRONLY).unwrap();
+    assert_eq!(file.write(b"hello"), Ok(5));
+    assert_eq!(file.read(&mut [0; 4]), Err(Errno::EBADF));
+
+    let file = fs::open("/test/file", O_RDWR | O_APPEND).unwrap();
+    assert_eq!(file.write(b" world"), Ok(6));
+    let file = fs::open("/test/file", O_RDONLY).unwrap();
+    let mut buffer = [0; 16];
+    assert_eq!(file.read(&mut buffer), Ok(11));
+    assert_eq!(&buffer[..11], b"hello world");
+    assert_eq!(file.read(&mut buffer), Ok(0));
+
+    fs::open("/test/file", O_WRONLY | O_TRUNC).unwrap();
+    assert_eq!(fs::read_file("/test/file").unwrap(), b"");
+    assert_eq!(fs::remove_file("/test/file"), Ok(()));
+    assert_eq!(fs::open("/test/file", O_RDONLY).err(), Some(Errno::ENOENT));
+}
+
+#[test_case]
+fn programs_are_read_only_files() {
+    assert!(fs::list().iter().any(|path| path == "/bin/hello"));
+    assert_eq!(
+        fs::read_file("/bin/hello").unwrap(),
+        embedded::find("hello").unwrap()
+    );
+    assert_eq!(fs::open("/bin/hello", O_RDWR).err(), Some(Errno::EACCES));
+    assert_eq!(fs::write_file("/bin/hello", b""), Err(Errno::EACCES));
+}
+
+fn new_memory() -> Memory {
+    let heap_start = VirtAddr::new(USER_START + 0x10_0000);
+    Memory::new(AddressSpace::new().unwrap(), heap_start)
+}
+
+fn is_mapped(memory: &Memory, address: u64) -> bool {
+    let page = Page::containing_address(VirtAddr::new(address));
+    memory.address_space().flags(page).is_some()
+}
+
+#[test_case]
+fn heap_grows_and_shrinks() {
+    let mut memory = new_memory();
+    let start = memory.program_break();
+    let end = start + 5000u64;
+    assert_eq!(memory.set_program_break(end), end);
+    assert!(is_mapped(&memory, start.as_u64() + 4096));
+    assert!(!is_mapped(&memory, start.as_u64() + 8192));
+
+    assert_eq!(memory.set_program_break(start + 10u64), start + 10u64);
+    assert!(is_mapped(&memory, start.as_u64()));
+    assert!(!is_mapped(&memory, start.as_u64() + 4096));
+    // Below the start of the heap
+    assert_eq!(memory.set_program_break(start - 4096u64), start + 10u64);
+}
+
+#[test_case]
+fn anonymous_memory_is_mapped() {
+    let mut memory = new_memory();
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let first = memory.map_anonymous(None, 100, flags).unwrap();
+    assert_eq!(first.as_u64(), MMAP_START);
+    let second = memory.map_anonymous(None, 4096, flags).unwrap();
+    assert_eq!(second.as_u64(), MMAP_START + 4096);
+    assert!(memory.address_space().is_accessible(first, 8192, true));
+
+    let fixed = VirtAddr::new(MMAP_START + 0x10_0000);
+    assert_eq!(memory.map_anonymous(Some(fixed), 4096, flags), Ok(fixed));
+    assert_eq!(
+        memory.map_anonymous(Some(fixed + 1u64), 4096, flags),
+        Err(Errno::EINVAL)
+    );
+
+    assert_eq!(memory.unmap(first, 8192), Ok(()));
+    assert!(!is_mapped(&memory, first.as_u64()));
+    assert_eq!(memory.unmap(first + 1u64, 4096), Err(Errno::EINVAL));
+}
@@ -0,0 +1,168 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use rust_os::thread;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn busy_threads_are_preempted() {
+    static COUNTERS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static FINISHED: AtomicU64 = AtomicU64::new(0);
+
+    for counter in COUNTERS.iter() {
+        // Neither thread ever yields on its own
+        thread::spawn("busy", move || {
+            while !STOP.load(Ordering::SeqCst) {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    // We only get here again if the timer preempts them
+    while COUNTERS
+        .iter()
+        .any(|counter| counter.load(Ordering::SeqCst) == 0)
+    {
+        core::hint::spin_loop();
+    }
+    STOP.store(true, Ordering::SeqCst);
+    while FINISHED.load(Ordering::SeqCst) < 2 {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn exited_threads_disappear() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let id = thread::spawn("short", || DONE.store(true, Ordering::SeqCst)).id();
+    assert!(thread::list().iter().any(|thread| thread.id == id));
+    while !DONE.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+    // Give it the chance to exit after setting the flag
+    while thread::list().iter().any(|thread| thread.id == id) {
+        thread::yield_now();
+    }
+    assert_ne!(thread::current(), id);
+}
+
+#[test_case]
+fn join_returns_result() {
+    let handle = thread::spawn("answer", || 6 * 7);
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn sleep_waits_for_timer() {
+    use core::time::Duration;
+    use rust_os::time;
+
+    let duration = Duration::from_millis(50);
+    let start = time::ticks();
+    thread::sleep(duration);
+    assert!(time::ticks() - start >= time::duration_to_ticks(duration));
+}
+
+#[test_case]
+fn mutex_excludes_other_threads() {
+    use alloc::vec::Vec;
+    use thread::sync::Mutex;
+
+    static COUNTER: Mutex<u64> = Mutex::new(0);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn("counter", || {
+                for _ in 0..10 {
+                    let mut counter = COUNTER.lock();
+                    let value = *counter;
+                    // Others get to run while we hold the lock
+                    thread::yield_now();
+                    *counter = value + 1;
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|handle| handle.join());
+
+    assert_eq!(*COUNTER.lock(), 40);
+}
+
+#[test_case]
+fn mutex_survives_early_wakeups() {
+    use core::time::Duration;
+    use thread::sync::Mutex;
+
+    static LOCK: Mutex<u64> = Mutex::new(0);
+
+    let guard = LOCK.lock();
+    let first = thread::spawn("first", || {
+        // Leaves a timer wakeup behind, which arrives while the thread
+        // waits for the lock
+        thread::sleep_unless(Duration::from_millis(20), || true);
+        let mut value = LOCK.lock();
+        // The second thread queues up behind us meanwhile
+        thread::sleep(Duration::from_millis(50));
+        *value += 1;
+    });
+    thread::sleep(Duration::from_millis(40));
+    drop(guard);
+    thread::sleep(Duration::from_millis(10));
+
+    let second = thread::spawn("second", || *LOCK.lock() += 1);
+    first.join();
+    second.join();
+    assert_eq!(*LOCK.lock(), 2);
+}
+
+#[test_case]
+fn condvar_wakes_waiting_thread() {
+    use thread::sync::{Condvar, Mutex};
+
+    static READY: Mutex<Option<u64>> = Mutex::new(None);
+    static CONDVAR: Condvar = Condvar::new();
+
+    let consumer = thread::spawn("consumer", || {
+        let value = CONDVAR.wait_while(READY.lock(), |value| value.is_none());
+        value.unwrap()
+    });
+
+    thread::sleep(core::time::Duration::from_millis(20));
+    *READY.lock() = Some(7);
+    CONDVAR.notify_one();
+
+    assert_eq!(consumer.join(), 7);
+}
@@ -0,0 +1,144 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory;
+use rust_os::usermode::{self, Exit};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// Where the test programs are copied to and their stack
+const CODE: u64 = 0x1000_0000;
+const STACK: u64 = 0x1001_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    rust_os::thread::init();
+
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for &address in &[CODE, STACK] {
+        let page = Page::containing_address(VirtAddr::new(address));
+        let frame = memory::allocate_frame().expect("out of memory");
+        unsafe { memory::map(page, frame, flags).expect("mapping failed") };
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// Runs the given machine code in user mode
+fn run(code: &[u8]) -> Exit {
+    unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), CODE as *mut u8, code.len()) };
+    usermode::enter(VirtAddr::new(CODE), VirtAddr::new(STACK + 4096))
+}
+
+#[test_case]
+fn exit_status_is_returned() {
+    let code = [
+        0xbf, 0x2a, 0x00, 0x00, 0x00, // mov edi, 42
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(run(&code), Exit::Exited(42));
+}
+
+#[test_case]
+fn registers_survive_system_calls() {
+    let code = [
+        0xbb, 0x07, 0x00, 0x00, 0x00, // mov ebx, 7
+        0xbf, 0x05, 0x00, 0x00, 0x00, // mov edi, 5
+        0xb8, 0x18, 0x00, 0x00, 0x00, // mov eax, 24 (sched_yield)
+        0x0f, 0x05, // syscall
+        0x01, 0xdf, // add edi, ebx
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(run(&code), Exit::Exited(12));
+}
+
+#[test_case]
+fn unknown_system_call_fails() {
+    let code = [
+        0xb8, 0xe7, 0x03, 0x00, 0x00, // mov eax, 999
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x48, 0xf7, 0xdf, // neg rdi
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(run(&code), Exit::Exited(38));
+}
+
+#[test_case]
+fn privileged_instruction_kills_user_code() {
+    let code = [0xf4]; // hlt
+    assert_eq!(run(&code), Exit::Faulted(13));
+}
+
+#[test_case]
+fn kernel_memory_is_not_accessible() {
+    let address = (&CODE as *const u64 as u64).to_le_bytes();
+    let code = [
+        0x48, 0xb8, address[0], address[1], address[2], address[3], address[4], address[5],
+        address[6], address[7], // movabs rax, address
+        0x48, 0x8b, 0x00, // mov rax, [rax]
+    ];
+    assert_eq!(run(&code), Exit::Faulted(14));
+}
+
+#[test_case]
+fn trap_flag_kills_user_code() {
+    let code = [
+        0x9c, // pushfq
+        0x48, 0x81, 0x0c, 0x24, 0x00, 0x01, 0x00, 0x00, // or qword [rsp], 0x100 (TF)
+        0x9d, // popfq
+        0x90, // nop
+    ];
+    assert_eq!(run(&code), Exit::Faulted(1));
+}
+
+#[test_case]
+fn non_canonical_stack_kills_user_code() {
+    let code = [
+        0x48, 0xbc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, // movabs rsp, 1 << 63
+        0x50, // push rax
+    ];
+    assert_eq!(run(&code), Exit::Faulted(12));
+}
+
+// Interrupts from user mode have to find the per-CPU data
+#[test_case]
+fn timer_interrupts_preempt_user_code() {
+    let start = rust_os::time::ticks();
+    let code = [
+        0x48, 0xc7, 0xc1, 0x00, 0x00, 0x00, 0x01, // mov rcx, 0x1000000
+        0xe2, 0xfe, // loop .
+        0x31, 0xff, // xor edi, edi
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(run(&code), Exit::Exited(0));
+    assert!(rust_os::time::ticks() > start);
+}
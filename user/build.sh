@@ -0,0 +1,13 @@
+#!/bin/sh
+# Assembles and links the user programs embedded in the kernel, see
+# src/elf/embedded.rs. The resulting ELF files are checked in, so this
+# only has to be run after changing a program.
+set -e
+cd "$(dirname "$0")"
+
+for source in *.S; do
+    name="${source%.S}"
+    as --64 -o "$name.o" "$source"
+    ld -static -nostdlib -z max-page-size=0x1000 -T link.ld -o "$name.elf" "$name.o"
+    rm "$name.o"
+done